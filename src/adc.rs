@@ -2,9 +2,37 @@
 // - enforce that the ADC clock does not exceed 30 MHz
 
 use cortex_m;
+use gpio::gpioa::{PA0, PA1, PA2, PA3, PA4, PA5, PA6, PA7};
+use gpio::gpiob::{PB0, PB1};
+use gpio::gpioc::{PC0, PC1, PC2, PC3, PC4, PC5};
+use gpio::gpiof::{PF10, PF3, PF4, PF5, PF6, PF7, PF8, PF9};
+use gpio::{Analog, Input};
+use hal;
+use nb;
 use rcc::APB2;
 use stm32f7x7::{ADC1, ADC2, ADC3, C_ADC};
 
+pub use self::injected::{Event, InjectedMode, InjectedSequence, MAX_INJECTED_LEN};
+pub use self::scan::{DmaEvent, Half, ScanDma, ScanMode, ScanSequence, MAX_SEQUENCE_LEN};
+
+mod injected;
+mod scan;
+
+/// ADC error
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Error {
+    /// A conversion result was overwritten before it was read
+    Overrun,
+    /// A sequence holds at most 16 regular or 4 injected conversions
+    SequenceFull,
+    /// A sequence needs at least one conversion
+    EmptySequence,
+    /// A scan buffer must hold an even number of whole scans
+    InvalidBuffer,
+    /// DMA transfer error
+    Transfer,
+}
+
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum SampleTime {
     Cycles3,
@@ -17,15 +45,78 @@ pub enum SampleTime {
     Cycles480,
 }
 
+/// Input channels, named after the ADCs sharing the pin
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Channel {
+    /// PA0
+    Adc123In0,
+    /// PA1
+    Adc123In1,
+    /// PA2
+    Adc123In2,
+    /// PA3
     Adc123In3,
+    /// PA4
+    Adc12In4,
+    /// PA5
+    Adc12In5,
+    /// PA6
+    Adc12In6,
+    /// PA7
+    Adc12In7,
+    /// PB0
+    Adc12In8,
+    /// PB1
+    Adc12In9,
+    /// PC0
     Adc123In10,
+    /// PC1
+    Adc123In11,
+    /// PC2
+    Adc123In12,
+    /// PC3
     Adc123In13,
-    Adc12In9,
+    /// PC4
+    Adc12In14,
+    /// PC5
+    Adc12In15,
+    /// PF6
+    Adc3In4,
+    /// PF7
+    Adc3In5,
+    /// PF8
+    Adc3In6,
+    /// PF9
+    Adc3In7,
+    /// PF10
+    Adc3In8,
+    /// PF3
     Adc3In9,
+    /// PF4
+    Adc3In14,
+    /// PF5
     Adc3In15,
-    Adc3In8,
+    /// Internal reference voltage, see `Adc::enable_temperature_vrefint()`
+    Vrefint,
+    /// Temperature sensor, see `Adc::enable_temperature_vrefint()`
+    Temperature,
+    /// VBAT / 4, see `Adc::enable_vbat()`
+    Vbat,
+}
+
+/// Temperature sensor channel (type state), ADC1 only
+pub struct Temperature {
+    _0: (),
+}
+
+/// Internal reference voltage channel (type state), ADC1 only
+pub struct Vrefint {
+    _0: (),
+}
+
+/// VBAT / 4 channel (type state), ADC1 only
+pub struct Vbat {
+    _0: (),
 }
 
 #[derive(Copy, Clone, Debug, PartialEq)]
@@ -36,6 +127,53 @@ pub enum Prescaler {
     Prescaler8,
 }
 
+/// External trigger edge
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TriggerEdge {
+    Rising,
+    Falling,
+    Both,
+}
+
+/// External trigger of the regular group
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RegularTrigger {
+    Tim1Cc1,
+    Tim1Cc2,
+    Tim1Cc3,
+    Tim2Cc2,
+    Tim5Trgo,
+    Tim4Cc4,
+    Tim3Cc4,
+    Tim8Trgo,
+    Tim8Trgo2,
+    Tim1Trgo,
+    Tim1Trgo2,
+    Tim2Trgo,
+    Tim4Trgo,
+    Tim6Trgo,
+    Exti11,
+}
+
+/// External trigger of the injected group
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum InjectedTrigger {
+    Tim1Trgo,
+    Tim1Cc4,
+    Tim2Trgo,
+    Tim2Cc1,
+    Tim3Cc4,
+    Tim4Trgo,
+    Tim8Cc4,
+    Tim1Trgo2,
+    Tim8Trgo,
+    Tim8Trgo2,
+    Tim3Cc3,
+    Tim5Trgo,
+    Tim3Cc1,
+    Tim6Trgo,
+}
+
 /// ADC conversion resolution
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Resolution {
@@ -67,13 +205,87 @@ impl From<SampleTime> for u8 {
 impl From<Channel> for u8 {
     fn from(c: Channel) -> u8 {
         match c {
+            Channel::Adc123In0 => 0,
+            Channel::Adc123In1 => 1,
+            Channel::Adc123In2 => 2,
             Channel::Adc123In3 => 3,
+            Channel::Adc12In4 => 4,
+            Channel::Adc12In5 => 5,
+            Channel::Adc12In6 => 6,
+            Channel::Adc12In7 => 7,
+            Channel::Adc12In8 => 8,
+            Channel::Adc12In9 => 9,
             Channel::Adc123In10 => 10,
+            Channel::Adc123In11 => 11,
+            Channel::Adc123In12 => 12,
             Channel::Adc123In13 => 13,
-            Channel::Adc12In9 => 9,
+            Channel::Adc12In14 => 14,
+            Channel::Adc12In15 => 15,
+            Channel::Adc3In4 => 4,
+            Channel::Adc3In5 => 5,
+            Channel::Adc3In6 => 6,
+            Channel::Adc3In7 => 7,
+            Channel::Adc3In8 => 8,
             Channel::Adc3In9 => 9,
+            Channel::Adc3In14 => 14,
             Channel::Adc3In15 => 15,
-            Channel::Adc3In8 => 8,
+            Channel::Vrefint => 17,
+            // shared, VBAT is converted while enabled
+            Channel::Temperature => 18,
+            Channel::Vbat => 18,
+        }
+    }
+}
+
+impl From<TriggerEdge> for u8 {
+    fn from(e: TriggerEdge) -> u8 {
+        match e {
+            TriggerEdge::Rising => 0b01,
+            TriggerEdge::Falling => 0b10,
+            TriggerEdge::Both => 0b11,
+        }
+    }
+}
+
+impl From<RegularTrigger> for u8 {
+    fn from(t: RegularTrigger) -> u8 {
+        match t {
+            RegularTrigger::Tim1Cc1 => 0b0000,
+            RegularTrigger::Tim1Cc2 => 0b0001,
+            RegularTrigger::Tim1Cc3 => 0b0010,
+            RegularTrigger::Tim2Cc2 => 0b0011,
+            RegularTrigger::Tim5Trgo => 0b0100,
+            RegularTrigger::Tim4Cc4 => 0b0101,
+            RegularTrigger::Tim3Cc4 => 0b0110,
+            RegularTrigger::Tim8Trgo => 0b0111,
+            RegularTrigger::Tim8Trgo2 => 0b1000,
+            RegularTrigger::Tim1Trgo => 0b1001,
+            RegularTrigger::Tim1Trgo2 => 0b1010,
+            RegularTrigger::Tim2Trgo => 0b1011,
+            RegularTrigger::Tim4Trgo => 0b1100,
+            RegularTrigger::Tim6Trgo => 0b1101,
+            RegularTrigger::Exti11 => 0b1111,
+        }
+    }
+}
+
+impl From<InjectedTrigger> for u8 {
+    fn from(t: InjectedTrigger) -> u8 {
+        match t {
+            InjectedTrigger::Tim1Trgo => 0b0000,
+            InjectedTrigger::Tim1Cc4 => 0b0001,
+            InjectedTrigger::Tim2Trgo => 0b0010,
+            InjectedTrigger::Tim2Cc1 => 0b0011,
+            InjectedTrigger::Tim3Cc4 => 0b0100,
+            InjectedTrigger::Tim4Trgo => 0b0101,
+            InjectedTrigger::Tim8Cc4 => 0b0111,
+            InjectedTrigger::Tim1Trgo2 => 0b1000,
+            InjectedTrigger::Tim8Trgo => 0b1001,
+            InjectedTrigger::Tim8Trgo2 => 0b1010,
+            InjectedTrigger::Tim3Cc3 => 0b1011,
+            InjectedTrigger::Tim5Trgo => 0b1100,
+            InjectedTrigger::Tim3Cc1 => 0b1101,
+            InjectedTrigger::Tim6Trgo => 0b1110,
         }
     }
 }
@@ -102,6 +314,12 @@ impl From<Resolution> for u8 {
 
 pub struct Adc<ADC> {
     adc: ADC,
+    // used by OneShot conversions
+    sample_time: SampleTime,
+    // channel of the pending OneShot conversion
+    pending: Option<u8>,
+    // an injected group is configured, it needs scan mode
+    injected: bool,
 }
 
 macro_rules! hal {
@@ -183,36 +401,79 @@ impl Adc<$ADCX> {
         // clear regular group conversion flag and overrun flag
         adc.sr.modify(|_, w| w.ovr().clear_bit().eoc().clear_bit());
 
-        Adc { adc }
+        Adc {
+            adc,
+            sample_time: SampleTime::Cycles480,
+            pending: None,
+            injected: false,
+        }
+    }
+
+    /// Starts regular group conversions on an external trigger, software
+    /// starts are still possible
+    pub fn set_regular_trigger(&mut self, trigger: RegularTrigger, edge: TriggerEdge) {
+        self.adc.cr2.modify(|_, w| unsafe {
+            w.extsel().bits(u8::from(trigger)).exten().bits(u8::from(edge))
+        });
+    }
+
+    /// Disables the regular group external trigger
+    pub fn disable_regular_trigger(&mut self) {
+        self.adc.cr2.modify(|_, w| w.exten().bits(0b00));
+    }
+
+    /// Starts injected group conversions on an external trigger, software
+    /// starts are still possible
+    pub fn set_injected_trigger(&mut self, trigger: InjectedTrigger, edge: TriggerEdge) {
+        self.adc.cr2.modify(|_, w| unsafe {
+            w.jextsel().bits(u8::from(trigger)).jexten().bits(u8::from(edge))
+        });
+    }
+
+    /// Disables the injected group external trigger
+    pub fn disable_injected_trigger(&mut self) {
+        self.adc.cr2.modify(|_, w| w.jexten().bits(0b00));
+    }
+
+    /// Sets the sample time used by `OneShot` conversions, 480 cycles by
+    /// default
+    pub fn set_sample_time(&mut self, sample_time: SampleTime) {
+        self.sample_time = sample_time;
     }
 
     pub fn read(&self, channel: Channel, sample_time: SampleTime) -> u16 {
-        let smpt = u8::from(sample_time);
+        self.convert(u8::from(channel), sample_time)
+    }
 
-        // single conversion, uses the 1st conversion in the sequence
-        self.adc
-            .sqr3
-            .write(|w| unsafe { w.sq1().bits(u8::from(channel)) });
-
-        // sample time in cycles
-        // channel 10:18 uses SMPR1
-        // channel 0:9 uses SMPR2
-        match channel {
-            Channel::Adc123In3 => self.adc.smpr2.write(|w| unsafe { w.smp3().bits(smpt) }),
-            Channel::Adc3In8 => self.adc.smpr2.write(|w| unsafe { w.smp8().bits(smpt) }),
-            Channel::Adc3In9 => self.adc.smpr2.write(|w| w.smp9().bits(smpt)),
-            Channel::Adc12In9 => self.adc.smpr2.write(|w| w.smp9().bits(smpt)),
-            Channel::Adc123In10 => self.adc.smpr1.write(|w| unsafe { w.smp10().bits(smpt) }),
-            Channel::Adc123In13 => self.adc.smpr1.write(|w| unsafe { w.smp13().bits(smpt) }),
-            Channel::Adc3In15 => self.adc.smpr1.write(|w| unsafe { w.smp15().bits(smpt) }),
-        };
+    /// Reads a pin in analog mode or an internal channel, pins not
+    /// connected to this ADC don't implement the channel trait
+    pub fn read_pin<PIN>(&self, _pin: &PIN, sample_time: SampleTime) -> u16
+    where
+        PIN: hal::adc::Channel<$ADCX, ID = u8>,
+    {
+        self.convert(PIN::channel(), sample_time)
+    }
 
-        // start conversion
-        self.adc.cr2.modify(|_, w| w.swstart().set_bit());
+    fn convert(&self, channel: u8, sample_time: SampleTime) -> u16 {
+        self.start(channel, sample_time);
 
         // wait for conversion to complete
         while !self.adc.sr.read().eoc().bit() {}
 
+        self.result()
+    }
+
+    fn start(&self, channel: u8, sample_time: SampleTime) {
+        self.write_sample_time(channel, sample_time);
+
+        // single conversion, uses the 1st conversion in the sequence
+        self.adc.sqr3.write(|w| unsafe { w.sq1().bits(channel) });
+
+        // start conversion
+        self.adc.cr2.modify(|_, w| w.swstart().set_bit());
+    }
+
+    fn result(&self) -> u16 {
         self.adc.sr.modify(|_, w| {
             w
             // clear regular channel start flag
@@ -224,6 +485,69 @@ impl Adc<$ADCX> {
         // return data register contents
         self.adc.dr.read().data().bits()
     }
+
+    // sample time in cycles
+    // channel 10:18 uses SMPR1
+    // channel 0:9 uses SMPR2
+    fn write_sample_time(&self, channel: u8, sample_time: SampleTime) {
+        let smpt = u32::from(u8::from(sample_time));
+
+        if channel < 10 {
+            let offset = 3 * u32::from(channel);
+            self.adc.smpr2.modify(|r, w| unsafe {
+                w.bits((r.bits() & !(0b111 << offset)) | (smpt << offset))
+            });
+        } else {
+            let offset = 3 * u32::from(channel - 10);
+            self.adc.smpr1.modify(|r, w| unsafe {
+                w.bits((r.bits() & !(0b111 << offset)) | (smpt << offset))
+            });
+        }
+    }
+}
+
+impl<WORD, PIN> hal::adc::OneShot<$ADCX, WORD, PIN> for Adc<$ADCX>
+where
+    WORD: From<u16>,
+    PIN: hal::adc::Channel<$ADCX, ID = u8>,
+{
+    type Error = Error;
+
+    /// Starts a conversion on the first call, returns `WouldBlock` until
+    /// it completes. A conversion for another pin has to be completed
+    /// first.
+    fn read(&mut self, _pin: &mut PIN) -> nb::Result<WORD, Error> {
+        let channel = PIN::channel();
+
+        match self.pending {
+            None => {
+                // discard a stale overrun
+                self.adc.sr.modify(|_, w| w.ovr().clear_bit());
+
+                self.start(channel, self.sample_time);
+                self.pending = Some(channel);
+
+                Err(nb::Error::WouldBlock)
+            }
+            Some(pending) if pending != channel => Err(nb::Error::WouldBlock),
+            Some(_) => {
+                let sr = self.adc.sr.read();
+
+                if sr.ovr().bit() {
+                    self.adc.sr.modify(|_, w| w.ovr().clear_bit());
+                    self.pending = None;
+
+                    Err(nb::Error::Other(Error::Overrun))
+                } else if sr.eoc().bit() {
+                    self.pending = None;
+
+                    Ok(WORD::from(self.result()))
+                } else {
+                    Err(nb::Error::WouldBlock)
+                }
+            }
+        }
+    }
 }
 )+
     }
@@ -234,3 +558,79 @@ hal! {
     ADC2: (adc2, adc2en, false),
     ADC3: (adc3, adc3en, false),
 }
+
+impl Adc<ADC1> {
+    /// Enables the temperature sensor and internal reference voltage
+    /// channels, the temperature sensor needs 10 us to start up
+    pub fn enable_temperature_vrefint(&mut self, c_adc: &mut C_ADC) -> (Temperature, Vrefint) {
+        c_adc.ccr.modify(|_, w| w.tsvrefe().set_bit());
+
+        (Temperature { _0: () }, Vrefint { _0: () })
+    }
+
+    pub fn disable_temperature_vrefint(
+        &mut self,
+        c_adc: &mut C_ADC,
+        _channels: (Temperature, Vrefint),
+    ) {
+        c_adc.ccr.modify(|_, w| w.tsvrefe().clear_bit());
+    }
+
+    /// Enables the VBAT channel, it shares channel 18 with the
+    /// temperature sensor and is converted instead while enabled
+    pub fn enable_vbat(&mut self, c_adc: &mut C_ADC) -> Vbat {
+        c_adc.ccr.modify(|_, w| w.vbate().set_bit());
+
+        Vbat { _0: () }
+    }
+
+    pub fn disable_vbat(&mut self, c_adc: &mut C_ADC, _channel: Vbat) {
+        c_adc.ccr.modify(|_, w| w.vbate().clear_bit());
+    }
+}
+
+macro_rules! channels {
+    ($($CHANNEL:ty: ($($ADCX:ident),+), $channel:expr,)+) => {
+        $(
+            $(
+                impl hal::adc::Channel<$ADCX> for $CHANNEL {
+                    type ID = u8;
+
+                    fn channel() -> u8 {
+                        $channel
+                    }
+                }
+            )+
+        )+
+    }
+}
+
+channels! {
+    PA0<Input<Analog>>: (ADC1, ADC2, ADC3), 0,
+    PA1<Input<Analog>>: (ADC1, ADC2, ADC3), 1,
+    PA2<Input<Analog>>: (ADC1, ADC2, ADC3), 2,
+    PA3<Input<Analog>>: (ADC1, ADC2, ADC3), 3,
+    PA4<Input<Analog>>: (ADC1, ADC2), 4,
+    PA5<Input<Analog>>: (ADC1, ADC2), 5,
+    PA6<Input<Analog>>: (ADC1, ADC2), 6,
+    PA7<Input<Analog>>: (ADC1, ADC2), 7,
+    PB0<Input<Analog>>: (ADC1, ADC2), 8,
+    PB1<Input<Analog>>: (ADC1, ADC2), 9,
+    PC0<Input<Analog>>: (ADC1, ADC2, ADC3), 10,
+    PC1<Input<Analog>>: (ADC1, ADC2, ADC3), 11,
+    PC2<Input<Analog>>: (ADC1, ADC2, ADC3), 12,
+    PC3<Input<Analog>>: (ADC1, ADC2, ADC3), 13,
+    PC4<Input<Analog>>: (ADC1, ADC2), 14,
+    PC5<Input<Analog>>: (ADC1, ADC2), 15,
+    PF6<Input<Analog>>: (ADC3), 4,
+    PF7<Input<Analog>>: (ADC3), 5,
+    PF8<Input<Analog>>: (ADC3), 6,
+    PF9<Input<Analog>>: (ADC3), 7,
+    PF10<Input<Analog>>: (ADC3), 8,
+    PF3<Input<Analog>>: (ADC3), 9,
+    PF4<Input<Analog>>: (ADC3), 14,
+    PF5<Input<Analog>>: (ADC3), 15,
+    Vrefint: (ADC1), 17,
+    Temperature: (ADC1), 18,
+    Vbat: (ADC1), 18,
+}
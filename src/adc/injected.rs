@@ -0,0 +1,227 @@
+//! Injected channel group
+//!
+//! Up to four conversions that interrupt the regular group when triggered,
+//! by software, an external trigger (see `Adc::set_injected_trigger()`) or
+//! automatically after each regular group conversion. Results are kept in
+//! their own data registers, less the per rank offset.
+//!
+//! Example:
+//! let mut sequence = InjectedSequence::new(InjectedMode::Triggered);
+//! sequence.push_pin(&current_sense, SampleTime::Cycles15, 0)?;
+//!
+//! adc.configure_injected(&sequence)?;
+//! adc.set_injected_trigger(InjectedTrigger::Tim2Trgo, TriggerEdge::Rising);
+//! adc.listen(Event::InjectedEndOfConversion);
+//!
+//! // ADC interrupt
+//! let results = adc.read_injected()?;
+
+use core::marker::PhantomData;
+
+use super::{Adc, Channel, Error, SampleTime};
+use hal;
+use nb;
+use stm32f7x7::{ADC1, ADC2, ADC3};
+
+/// Maximum number of conversions in the injected group
+pub const MAX_INJECTED_LEN: usize = 4;
+
+/// Starting conversions of the injected group
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum InjectedMode {
+    /// Converted once per software or external trigger
+    Triggered,
+    /// Converted after every regular group conversion, external triggers
+    /// must be disabled
+    Auto,
+}
+
+/// Interrupt events
+pub enum Event {
+    /// The injected group conversions completed
+    InjectedEndOfConversion,
+}
+
+/// Injected group conversion sequence of an ADC
+pub struct InjectedSequence<ADC> {
+    channels: [u8; MAX_INJECTED_LEN],
+    sample_times: [SampleTime; MAX_INJECTED_LEN],
+    offsets: [u16; MAX_INJECTED_LEN],
+    len: usize,
+    mode: InjectedMode,
+    _adc: PhantomData<ADC>,
+}
+
+impl<ADC> InjectedSequence<ADC> {
+    pub fn new(mode: InjectedMode) -> Self {
+        InjectedSequence {
+            channels: [0; MAX_INJECTED_LEN],
+            sample_times: [SampleTime::Cycles480; MAX_INJECTED_LEN],
+            offsets: [0; MAX_INJECTED_LEN],
+            len: 0,
+            mode,
+            _adc: PhantomData,
+        }
+    }
+
+    /// Appends a channel, `offset` (12 bits) is subtracted from its
+    /// result
+    pub fn push(
+        &mut self,
+        channel: Channel,
+        sample_time: SampleTime,
+        offset: u16,
+    ) -> Result<(), Error> {
+        self.push_channel(u8::from(channel), sample_time, offset)
+    }
+
+    /// Appends a pin in analog mode or an internal channel of this ADC
+    pub fn push_pin<PIN>(
+        &mut self,
+        _pin: &PIN,
+        sample_time: SampleTime,
+        offset: u16,
+    ) -> Result<(), Error>
+    where
+        PIN: hal::adc::Channel<ADC, ID = u8>,
+    {
+        self.push_channel(PIN::channel(), sample_time, offset)
+    }
+
+    /// Returns the number of conversions
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn mode(&self) -> InjectedMode {
+        self.mode
+    }
+
+    fn push_channel(
+        &mut self,
+        channel: u8,
+        sample_time: SampleTime,
+        offset: u16,
+    ) -> Result<(), Error> {
+        if self.len == MAX_INJECTED_LEN {
+            return Err(Error::SequenceFull);
+        }
+
+        self.channels[self.len] = channel;
+        self.sample_times[self.len] = sample_time;
+        self.offsets[self.len] = offset & 0xFFF;
+        self.len += 1;
+
+        Ok(())
+    }
+}
+
+macro_rules! injected {
+    ($($ADCX:ident,)+) => {
+        $(
+impl Adc<$ADCX> {
+    /// Configures the injected group, conversions start on the next
+    /// trigger
+    pub fn configure_injected(&mut self, sequence: &InjectedSequence<$ADCX>) -> Result<(), Error> {
+        if sequence.is_empty() {
+            return Err(Error::EmptySequence);
+        }
+
+        // a sequence shorter than 4 starts at JSQ(4 - JL)
+        let first = MAX_INJECTED_LEN - sequence.len;
+        let mut jsqr = ((sequence.len - 1) as u32) << 20;
+        for rank in 0..sequence.len {
+            jsqr |= u32::from(sequence.channels[rank]) << (5 * (first + rank));
+
+            self.write_sample_time(sequence.channels[rank], sequence.sample_times[rank]);
+        }
+
+        self.adc.jsqr.write(|w| unsafe { w.bits(jsqr) });
+
+        // offsets follow the rank
+        self.adc.jofr1.write(|w| w.joffset1().bits(sequence.offsets[0]));
+        self.adc.jofr2.write(|w| w.joffset2().bits(sequence.offsets[1]));
+        self.adc.jofr3.write(|w| w.joffset3().bits(sequence.offsets[2]));
+        self.adc.jofr4.write(|w| w.joffset4().bits(sequence.offsets[3]));
+
+        let auto = sequence.mode == InjectedMode::Auto;
+        if auto {
+            // auto-injection doesn't work with external triggers
+            self.adc.cr2.modify(|_, w| w.jexten().bits(0b00));
+        }
+
+        self.adc.cr1.modify(|_, w| {
+            w
+                // scan the injected channels
+                .scan().set_bit()
+                .jauto().bit(auto)
+                // disable discontinuous mode
+                .jdiscen().clear_bit()
+        });
+
+        // clear injected group conversion flags
+        self.adc.sr.modify(|_, w| w.jeoc().clear_bit().jstrt().clear_bit());
+
+        self.injected = true;
+
+        Ok(())
+    }
+
+    /// Starts the injected group conversions by software
+    pub fn start_injected(&mut self) {
+        self.adc.cr2.modify(|_, w| w.jswstart().set_bit());
+    }
+
+    /// Returns the results of the injected group in rank order, unused
+    /// ranks are 0, `WouldBlock` until the conversions complete.
+    ///
+    /// Results are signed as the offsets are subtracted.
+    pub fn read_injected(&mut self) -> nb::Result<[i16; MAX_INJECTED_LEN], Error> {
+        if !self.adc.sr.read().jeoc().bit() {
+            return Err(nb::Error::WouldBlock);
+        }
+
+        // clear injected group conversion flags
+        self.adc.sr.modify(|_, w| w.jeoc().clear_bit().jstrt().clear_bit());
+
+        let len = (self.adc.jsqr.read().jl().bits() + 1) as usize;
+        let data = [
+            self.adc.jdr1.read().jdata().bits() as i16,
+            self.adc.jdr2.read().jdata().bits() as i16,
+            self.adc.jdr3.read().jdata().bits() as i16,
+            self.adc.jdr4.read().jdata().bits() as i16,
+        ];
+
+        let mut results = [0; MAX_INJECTED_LEN];
+        results[..len].copy_from_slice(&data[..len]);
+
+        Ok(results)
+    }
+
+    /// Starts listening for an interrupt event
+    pub fn listen(&mut self, event: Event) {
+        match event {
+            Event::InjectedEndOfConversion => self.adc.cr1.modify(|_, w| w.jeocie().set_bit()),
+        }
+    }
+
+    /// Stops listening for an interrupt event
+    pub fn unlisten(&mut self, event: Event) {
+        match event {
+            Event::InjectedEndOfConversion => self.adc.cr1.modify(|_, w| w.jeocie().clear_bit()),
+        }
+    }
+}
+)+
+    }
+}
+
+injected! {
+    ADC1,
+    ADC2,
+    ADC3,
+}
@@ -0,0 +1,392 @@
+//! Regular group scan sequences streamed by DMA
+//!
+//! The conversion results of each scan are written by DMA2 into a caller
+//! owned circular buffer, holding a whole number of scans per half so one
+//! half can be read while the other is written.
+//!
+//! ADC1 uses DMA2 stream 0 channel 0, ADC2 stream 2 channel 1 and ADC3
+//! stream 1 channel 2, see `DmaExt::split()`.
+//!
+//! Example:
+//! static mut BUFFER: [u16; 8] = [0; 8];
+//!
+//! let mut sequence = ScanSequence::new(ScanMode::Continuous);
+//! sequence.push_pin(&pedal_a, SampleTime::Cycles144)?;
+//! sequence.push_pin(&pedal_b, SampleTime::Cycles144)?;
+//!
+//! let streams = dp.DMA2.split(&mut rcc.ahb1);
+//! let mut scan = adc.scan_dma(&sequence, unsafe { &mut BUFFER }, streams.s0)?;
+//! scan.listen(DmaEvent::HalfTransfer);
+//! scan.listen(DmaEvent::TransferComplete);
+//!
+//! // DMA2 stream interrupt
+//! scan.on_interrupt(|half, samples| {
+//!     // samples holds two scans of two channels
+//! })?;
+
+use core::marker::PhantomData;
+use core::slice;
+use core::sync::atomic::{self, Ordering};
+
+use super::{Adc, Channel, Error, SampleTime};
+use dma::{S0, S1, S2, STREAM_FLAGS};
+use hal;
+use stm32f7x7::{ADC1, ADC2, ADC3};
+
+/// Maximum number of conversions in a regular sequence
+pub const MAX_SEQUENCE_LEN: usize = 16;
+
+// DMA stream flags
+const TEIF: u32 = 1 << 3;
+const HTIF: u32 = 1 << 4;
+const TCIF: u32 = 1 << 5;
+
+/// Starting conversions of a sequence
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ScanMode {
+    /// The sequence is restarted as soon as it completes
+    Continuous,
+    /// The sequence is converted once per trigger, see `ScanDma::start()`
+    /// and `Adc::set_regular_trigger()`
+    Triggered,
+}
+
+/// Half of the circular buffer
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Half {
+    First,
+    Second,
+}
+
+/// DMA interrupt events
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum DmaEvent {
+    /// The first half of the buffer was written
+    HalfTransfer,
+    /// The second half of the buffer was written
+    TransferComplete,
+}
+
+/// Regular group conversion sequence of an ADC
+pub struct ScanSequence<ADC> {
+    channels: [u8; MAX_SEQUENCE_LEN],
+    sample_times: [SampleTime; MAX_SEQUENCE_LEN],
+    len: usize,
+    mode: ScanMode,
+    _adc: PhantomData<ADC>,
+}
+
+impl<ADC> ScanSequence<ADC> {
+    pub fn new(mode: ScanMode) -> Self {
+        ScanSequence {
+            channels: [0; MAX_SEQUENCE_LEN],
+            sample_times: [SampleTime::Cycles480; MAX_SEQUENCE_LEN],
+            len: 0,
+            mode,
+            _adc: PhantomData,
+        }
+    }
+
+    /// Appends a channel, a channel converted more than once in a sequence
+    /// uses the last sample time given
+    pub fn push(&mut self, channel: Channel, sample_time: SampleTime) -> Result<(), Error> {
+        if self.len == MAX_SEQUENCE_LEN {
+            return Err(Error::SequenceFull);
+        }
+
+        self.channels[self.len] = u8::from(channel);
+        self.sample_times[self.len] = sample_time;
+        self.len += 1;
+
+        Ok(())
+    }
+
+    /// Appends a pin in analog mode or an internal channel of this ADC
+    pub fn push_pin<PIN>(&mut self, _pin: &PIN, sample_time: SampleTime) -> Result<(), Error>
+    where
+        PIN: hal::adc::Channel<ADC, ID = u8>,
+    {
+        if self.len == MAX_SEQUENCE_LEN {
+            return Err(Error::SequenceFull);
+        }
+
+        self.channels[self.len] = PIN::channel();
+        self.sample_times[self.len] = sample_time;
+        self.len += 1;
+
+        Ok(())
+    }
+
+    /// Returns the number of conversions
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn mode(&self) -> ScanMode {
+        self.mode
+    }
+}
+
+/// A scan sequence being converted into a circular buffer by a DMA2
+/// stream
+pub struct ScanDma<ADC, STREAM> {
+    adc: Adc<ADC>,
+    stream: STREAM,
+    buffer: &'static mut [u16],
+}
+
+macro_rules! scan {
+    ($(
+        $ADCX:ident: ($SX:ident, $chsel:expr),
+    )+) => {
+        $(
+impl Adc<$ADCX> {
+    /// Starts converting a scan sequence, results are written by DMA to
+    /// `buffer` which must hold an even number of whole scans
+    pub fn scan_dma(
+        self,
+        sequence: &ScanSequence<$ADCX>,
+        buffer: &'static mut [u16],
+        mut stream: $SX,
+    ) -> Result<ScanDma<$ADCX, $SX>, Error> {
+        if sequence.is_empty() {
+            return Err(Error::EmptySequence);
+        }
+
+        if buffer.is_empty()
+            || (buffer.len() % (2 * sequence.len()) != 0)
+            || (buffer.len() > 0xFFFF)
+        {
+            return Err(Error::InvalidBuffer);
+        }
+
+        // stop conversions while being configured
+        self.adc.cr2.modify(|_, w| w.cont().clear_bit().dma().clear_bit());
+
+        // SQ1 to SQ6 in SQR3, SQ7 to SQ12 in SQR2, SQ13 to SQ16 in SQR1
+        let mut sqr = [0u32; 3];
+        for (rank, channel) in sequence.channels[..sequence.len].iter().enumerate() {
+            sqr[rank / 6] |= u32::from(*channel) << (5 * (rank % 6));
+        }
+        let l = (sequence.len - 1) as u32;
+
+        self.adc.sqr3.write(|w| unsafe { w.bits(sqr[0]) });
+        self.adc.sqr2.write(|w| unsafe { w.bits(sqr[1]) });
+        self.adc.sqr1.write(|w| unsafe { w.bits(sqr[2] | (l << 20)) });
+
+        for rank in 0..sequence.len {
+            self.write_sample_time(sequence.channels[rank], sequence.sample_times[rank]);
+        }
+
+        // disable the stream, it must be disabled to be configured
+        stream.cr().modify(|_, w| w.en().clear_bit());
+        while stream.cr().read().en().bit() {}
+
+        stream.clear_flags(STREAM_FLAGS);
+
+        stream.par().write(|w| w.pa().bits(&self.adc.dr as *const _ as u32));
+        stream.m0ar().write(|w| w.m0a().bits(buffer.as_ptr() as u32));
+        stream.ndtr().write(|w| w.ndt().bits(buffer.len() as u16));
+
+        stream.cr().write(|w| unsafe {
+            w
+                .chsel().bits($chsel)
+                // high priority
+                .pl().bits(0b10)
+                // 16 bit transfers
+                .msize().bits(0b01)
+                .psize().bits(0b01)
+                // increment the memory address only
+                .minc().set_bit()
+                .pinc().clear_bit()
+                .circ().set_bit()
+                // peripheral to memory
+                .dir().bits(0b00)
+        });
+
+        stream.cr().modify(|_, w| w.en().set_bit());
+
+        // clear regular group conversion flag and overrun flag
+        self.adc.sr.modify(|_, w| w.ovr().clear_bit().eoc().clear_bit());
+
+        self.adc.cr1.modify(|_, w| w.scan().set_bit());
+        self.adc.cr2.modify(|_, w| {
+            w
+                // EOC set at the end of the sequence
+                .eocs().clear_bit()
+                // keep issuing DMA requests, the buffer is circular
+                .dds().set_bit()
+                .dma().set_bit()
+                .cont().bit(sequence.mode == ScanMode::Continuous)
+        });
+
+        let mut scan = ScanDma {
+            adc: self,
+            stream,
+            buffer,
+        };
+
+        if sequence.mode == ScanMode::Continuous {
+            scan.start();
+        }
+
+        Ok(scan)
+    }
+}
+
+impl ScanDma<$ADCX, $SX> {
+    /// Starts the conversion of the sequence by software, needed once
+    /// for `ScanMode::Continuous` which is done by `Adc::scan_dma()`
+    pub fn start(&mut self) {
+        self.adc.adc.cr2.modify(|_, w| w.swstart().set_bit());
+    }
+
+    /// Starts listening for a DMA interrupt event
+    pub fn listen(&mut self, event: DmaEvent) {
+        match event {
+            DmaEvent::HalfTransfer => self.stream.cr().modify(|_, w| w.htie().set_bit()),
+            DmaEvent::TransferComplete => self.stream.cr().modify(|_, w| w.tcie().set_bit()),
+        }
+    }
+
+    /// Stops listening for a DMA interrupt event
+    pub fn unlisten(&mut self, event: DmaEvent) {
+        match event {
+            DmaEvent::HalfTransfer => self.stream.cr().modify(|_, w| w.htie().clear_bit()),
+            DmaEvent::TransferComplete => self.stream.cr().modify(|_, w| w.tcie().clear_bit()),
+        }
+    }
+
+    /// Calls `f` with the half of the buffer that was just written, to be
+    /// called from the DMA stream interrupt handler or polled.
+    ///
+    /// `f` must be done with the samples before the DMA wraps around to
+    /// that half again. If both halves completed since the last call only
+    /// the second one is passed, the first is being written again.
+    ///
+    /// On an ADC overrun the DMA requests stop, the scan is restarted from
+    /// the start of the buffer and `Error::Overrun` is returned, the
+    /// samples of the interrupted scan are lost.
+    pub fn on_interrupt<F>(&mut self, f: F) -> Result<Option<Half>, Error>
+    where
+        F: FnOnce(Half, &[u16]),
+    {
+        let flags = self.stream.flags();
+
+        if (flags & TEIF) != 0 {
+            self.stream.clear_flags(TEIF);
+            return Err(Error::Transfer);
+        }
+
+        if self.adc.adc.sr.read().ovr().bit() {
+            self.restart();
+            return Err(Error::Overrun);
+        }
+
+        let half = if (flags & TCIF) != 0 {
+            // a pending half transfer is stale
+            self.stream.clear_flags(TCIF | HTIF);
+            Half::Second
+        } else if (flags & HTIF) != 0 {
+            self.stream.clear_flags(HTIF);
+            Half::First
+        } else {
+            return Ok(None);
+        };
+
+        // the buffer is written by DMA behind the compiler's back
+        atomic::compiler_fence(Ordering::SeqCst);
+
+        let len = self.buffer.len() / 2;
+        let offset = match half {
+            Half::First => 0,
+            Half::Second => len,
+        };
+
+        // NOTE(unsafe) the DMA is writing the other half
+        let samples = unsafe { slice::from_raw_parts(self.buffer.as_ptr().add(offset), len) };
+        f(half, samples);
+
+        Ok(Some(half))
+    }
+
+    // Recovers from an overrun, the stream is re-armed at the start of the
+    // buffer before the DMA requests are enabled again
+    fn restart(&mut self) {
+        let adc = &self.adc.adc;
+        let stream = &mut self.stream;
+        let (address, len) = (self.buffer.as_ptr() as u32, self.buffer.len() as u16);
+
+        adc.cr2.modify(|_, w| w.dma().clear_bit());
+
+        stream.cr().modify(|_, w| w.en().clear_bit());
+        while stream.cr().read().en().bit() {}
+
+        stream.clear_flags(STREAM_FLAGS);
+        stream.m0ar().write(|w| w.m0a().bits(address));
+        stream.ndtr().write(|w| w.ndt().bits(len));
+        stream.cr().modify(|_, w| w.en().set_bit());
+
+        // clear regular group conversion flag and overrun flag
+        adc.sr.modify(|_, w| w.ovr().clear_bit().eoc().clear_bit());
+        adc.cr2.modify(|_, w| w.dma().set_bit());
+
+        // triggered scans restart on the next trigger
+        if adc.cr2.read().cont().bit() {
+            self.start();
+        }
+    }
+
+    /// Stops the conversions and the DMA stream, the ADC is restored to
+    /// single conversions, scan mode is kept for a configured injected
+    /// group
+    pub fn stop(self) -> (Adc<$ADCX>, $SX, &'static mut [u16]) {
+        let ScanDma { adc, mut stream, buffer } = self;
+
+        adc.adc.cr2.modify(|_, w| {
+            w.cont()
+                .clear_bit()
+                .dma()
+                .clear_bit()
+                .dds()
+                .clear_bit()
+                .eocs()
+                .set_bit()
+        });
+        if !adc.injected {
+            adc.adc.cr1.modify(|_, w| w.scan().clear_bit());
+        }
+        adc.adc.sqr1.write(|w| w.l().bits(0b0000));
+
+        stream.cr().modify(|_, w| {
+            w.en()
+                .clear_bit()
+                .htie()
+                .clear_bit()
+                .tcie()
+                .clear_bit()
+        });
+        while stream.cr().read().en().bit() {}
+
+        stream.clear_flags(STREAM_FLAGS);
+
+        // clear regular group conversion flag and overrun flag
+        adc.adc.sr.modify(|_, w| w.ovr().clear_bit().eoc().clear_bit());
+
+        (adc, stream, buffer)
+    }
+}
+)+
+    }
+}
+
+// (DMA2 stream, channel)
+scan! {
+    ADC1: (S0, 0),
+    ADC2: (S2, 1),
+    ADC3: (S1, 2),
+}
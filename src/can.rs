@@ -2,39 +2,79 @@
 // https://github.com/jonlamb-gh/STM32Cube_FW_F7_V1.8.0/blob/master/Drivers/STM32F7xx_HAL_Driver/Src/stm32f7xx_hal_can.c
 //
 // TODO
-// - assert_eq!(self.can.esr.read().bits(), 0), check for error registers, when?
 // - add rx/tx timeouts?, currently blocking
 // - error/result types
-// - provide rx timestamp/counter?
-// - do something with the rx filter match index?
-// - conversion (using clock) data rate to/from bit timing
 // - macro out the defintions for CANX
 #![allow(dead_code)]
 
-use gpio::gpiob::{PB12, PB13};
+use core::cmp;
+use gpio::gpioa::{PA15, PA8};
+use gpio::gpiob::{PB12, PB13, PB3, PB4};
 use gpio::gpiod::{PD0, PD1};
-use gpio::AF9;
-use rcc::APB1;
-use stm32f7x7::{can1, CAN1, CAN2};
-
-// use time::Hertz;
+use gpio::{AF11, AF9};
+use hal;
+use nb;
+use rcc::{Clocks, APB1};
+use spsc::Producer;
+use stm32f7x7::{can1, CAN1, CAN2, CAN3};
+use time::{Bps, Hertz};
 
 pub use embedded_types::can::{
     BaseID, CanFrame, DataFrame, ExtendedDataFrame, ExtendedID, RemoteFrame, ID,
 };
 
-// TODO
-// currently only used in the Tx logic to wait for completion
-// that would normally lock up if the bus wasn't connected for example
-// 10 us at 16 MHz
-pub const MAX_BLOCK_TICKS: u32 = 16 * 10;
+pub use self::filter::{Filter, FilterBanks, NUM_CAN3_FILTER_BANKS, NUM_FILTER_BANKS};
+pub use self::frame::{from_hal_id, to_hal_id, HalFrame};
+pub use self::scheduler::{CanScheduler, ScheduleEntry, SharedFrame};
+pub use self::self_test::{SelfTestReport, SelfTestResult, SELF_TEST_FRAMES};
+pub use self::stats::{frame_bits, CanStats, IdRange};
+pub use self::ttcm::{BitTimeClock, TimeSlot, TimeTriggeredSchedule};
+pub use self::tx_queue::CanTxQueue;
+
+mod filter;
+mod frame;
+mod scheduler;
+mod self_test;
+mod stats;
+mod ttcm;
+mod tx_queue;
+
+/// Number of bit times to wait for the controller to acknowledge a mode
+/// change, entering initialization or sleep mode waits for the current
+/// frame to complete and leaving them takes 11 recessive bits. Also the
+/// time `Can::transmit()` waits for a transmission to complete.
+pub const ACK_TIMEOUT_BITS: u32 = 1_000;
+
+/// Maximum bit rate error, in parts per million, accepted when
+/// solving for a bit timing
+pub const MAX_BIT_RATE_ERROR_PPM: u32 = 5_000;
+
+/// Maximum bit rate supported by the CAN bus
+pub const MAX_BIT_RATE: u32 = 1_000_000;
+
+// APB1 enable/reset bit of CAN1
+const CAN1_EN_BIT: u32 = 25;
+
+// Allowed number of time quanta per bit, 1 + BS1 + BS2
+const MIN_TQ_PER_BIT: u32 = 8;
+const MAX_TQ_PER_BIT: u32 = 25;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum CanError {
     BufferExhausted,
     ConfigurationFailed,
     InvalidFrame,
+    InvalidBitRate,
     Timeout,
+    /// Initialization mode was not acknowledged
+    InitTimeout,
+    /// Leaving initialization mode was not acknowledged, the controller
+    /// never saw 11 recessive bits on RX
+    LeaveInitTimeout,
+    /// Sleep mode was not acknowledged
+    SleepTimeout,
+    /// Leaving sleep mode was not acknowledged
+    WakeupTimeout,
 }
 
 pub struct CanConfig {
@@ -62,7 +102,7 @@ pub struct CanConfig {
     /// Enable or disable the transmit FIFO priority.
     pub txfp: bool,
 
-    // TODO - to/from time::Hertz
+    /// Bit timing, see `CanBitTiming::from_bitrate()`.
     pub bit_timing: CanBitTiming,
 }
 
@@ -88,6 +128,9 @@ impl Default for CanConfig {
     }
 }
 
+/// Bit timing, fields hold the raw BTR register values which are
+/// one less than the actual quantities
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct CanBitTiming {
     /// Specifies the length of a time quantum.
     pub prescaler: u16,
@@ -104,27 +147,291 @@ pub struct CanBitTiming {
     pub bs2: u8,
 }
 
+impl CanBitTiming {
+    /// Finds the bit timing closest to `bitrate` for the APB1 clock.
+    ///
+    /// `sample_point` is in tenths of a percent, e.g. 875 is 87.5%.
+    ///
+    /// Returns the bit timing and its absolute bit rate error.
+    pub fn from_bitrate(
+        bitrate: Bps,
+        clocks: &Clocks,
+        sample_point: u16,
+    ) -> Result<(Self, Bps), CanError> {
+        Self::from_bitrate_with_clock(bitrate, clocks.pclk1(), sample_point)
+    }
+
+    /// Same as `from_bitrate()` but with an explicit peripheral clock
+    pub fn from_bitrate_with_clock(
+        bitrate: Bps,
+        pclk1: Hertz,
+        sample_point: u16,
+    ) -> Result<(Self, Bps), CanError> {
+        let clock = pclk1.0;
+        let bitrate = bitrate.0;
+        let sample_point = u32::from(sample_point);
+
+        if bitrate == 0 || bitrate > MAX_BIT_RATE || sample_point == 0 || sample_point >= 1000 {
+            return Err(CanError::InvalidBitRate);
+        }
+
+        // (timing, bit rate error, sample point error)
+        let mut best: Option<(CanBitTiming, u32, u32)> = None;
+
+        // prefer more time quanta per bit, finer resynchronization
+        for tq in (MIN_TQ_PER_BIT..=MAX_TQ_PER_BIT).rev() {
+            let prescaler = (clock + (bitrate * tq) / 2) / (bitrate * tq);
+
+            if prescaler < 1 || prescaler > 1024 {
+                continue;
+            }
+
+            let actual = clock / (prescaler * tq);
+            let error = if actual > bitrate {
+                actual - bitrate
+            } else {
+                bitrate - actual
+            };
+
+            // sync segment + BS1 places the sample point
+            let bs1 = ((tq * sample_point + 500) / 1000).saturating_sub(1);
+            let bs1 = if bs1 < 1 {
+                1
+            } else if bs1 > 16 {
+                16
+            } else {
+                bs1
+            };
+
+            if tq < bs1 + 2 {
+                continue;
+            }
+
+            let bs2 = tq - 1 - bs1;
+
+            if bs2 < 1 || bs2 > 8 {
+                continue;
+            }
+
+            let actual_sp = ((1 + bs1) * 1000) / tq;
+            let sp_error = if actual_sp > sample_point {
+                actual_sp - sample_point
+            } else {
+                sample_point - actual_sp
+            };
+
+            let better = match best {
+                None => true,
+                Some((_, e, spe)) => (error < e) || ((error == e) && (sp_error < spe)),
+            };
+
+            if better {
+                let sjw = if bs2 < 4 { bs2 } else { 4 };
+
+                best = Some((
+                    CanBitTiming {
+                        prescaler: (prescaler - 1) as u16,
+                        sjw: (sjw - 1) as u8,
+                        bs1: (bs1 - 1) as u8,
+                        bs2: (bs2 - 1) as u8,
+                    },
+                    error,
+                    sp_error,
+                ));
+            }
+        }
+
+        match best {
+            Some((timing, error, _)) => {
+                let ppm = (u64::from(error) * 1_000_000) / u64::from(bitrate);
+
+                if ppm > u64::from(MAX_BIT_RATE_ERROR_PPM) {
+                    Err(CanError::InvalidBitRate)
+                } else {
+                    Ok((timing, Bps(error)))
+                }
+            }
+            None => Err(CanError::InvalidBitRate),
+        }
+    }
+
+    /// Returns the bit rate produced by this timing on the APB1 clock
+    pub fn bitrate(&self, clocks: &Clocks) -> Bps {
+        self.bitrate_with_clock(clocks.pclk1())
+    }
+
+    /// Same as `bitrate()` but with an explicit peripheral clock
+    pub fn bitrate_with_clock(&self, pclk1: Hertz) -> Bps {
+        Bps(pclk1.0 / ((u32::from(self.prescaler) + 1) * self.tq_per_bit()))
+    }
+
+    /// Number of time quanta in a bit, sync segment + BS1 + BS2
+    pub fn tq_per_bit(&self) -> u32 {
+        3 + u32::from(self.bs1) + u32::from(self.bs2)
+    }
+
+    /// Sample point in tenths of a percent
+    pub fn sample_point(&self) -> u16 {
+        (((2 + u32::from(self.bs1)) * 1000) / self.tq_per_bit()) as u16
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum TxMailbox {
     Mailbox0,
     Mailbox1,
     Mailbox2,
 }
 
+/// Status of a transmit mailbox
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TxStatus {
+    /// Mailbox is empty, no request was completed since the status was
+    /// last cleared
+    Idle,
+    /// Transmission is pending
+    Pending,
+    /// Frame was transmitted successfully
+    Success,
+    /// Frame lost arbitration
+    ArbitrationLost,
+    /// Transmission error
+    Error,
+    /// Transmission was aborted
+    Aborted,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum RxFifo {
     Fifo0,
     Fifo1,
 }
 
+/// A received frame along with its receive metadata
+pub struct ReceivedFrame {
+    pub frame: CanFrame,
+    /// Data length code, the requested length for remote frames
+    pub dlc: u8,
+    /// FIFO the frame was received in
+    pub fifo: RxFifo,
+    /// Filter match index (FMI), numbers the filters assigned to the
+    /// FIFO, see the reference manual for how the index is counted
+    pub filter_match_index: u8,
+    /// Value of the 16 bit bit-time counter at the start of frame,
+    /// only valid with time triggered communication mode enabled
+    pub timestamp: u16,
+}
+
+/// Interrupt events
+pub enum Event {
+    /// A message is pending in FIFO 0
+    Fifo0MessagePending,
+    /// A message is pending in FIFO 1
+    Fifo1MessagePending,
+    /// A transmit mailbox became empty
+    TransmitMailboxEmpty,
+    /// TEC or REC reached the warning limit
+    ErrorWarning,
+    /// TEC or REC went above the error passive limit
+    ErrorPassive,
+    /// The controller entered bus-off
+    BusOff,
+    /// A new last error code was set by hardware
+    LastErrorCode,
+    /// Bus activity was detected in sleep mode
+    Wakeup,
+    /// The controller entered sleep mode
+    Sleep,
+}
+
+/// Fault confinement state
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorState {
+    /// Normal operation, TEC and REC below 128
+    Active,
+    /// TEC or REC above 127
+    Passive,
+    /// TEC above 255, the controller no longer takes part in bus activity
+    BusOff,
+}
+
+/// Error detected by the last bus transfer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LastErrorCode {
+    NoError,
+    Stuff,
+    Form,
+    Acknowledgment,
+    BitRecessive,
+    BitDominant,
+    Crc,
+    /// Written by software, see `Can::clear_last_error_code()`
+    SetBySoftware,
+}
+
+impl From<u8> for LastErrorCode {
+    fn from(lec: u8) -> LastErrorCode {
+        match lec & 0b111 {
+            0b000 => LastErrorCode::NoError,
+            0b001 => LastErrorCode::Stuff,
+            0b010 => LastErrorCode::Form,
+            0b011 => LastErrorCode::Acknowledgment,
+            0b100 => LastErrorCode::BitRecessive,
+            0b101 => LastErrorCode::BitDominant,
+            0b110 => LastErrorCode::Crc,
+            _ => LastErrorCode::SetBySoftware,
+        }
+    }
+}
+
+/// Decoded error status register (ESR)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ErrorStatus {
+    pub state: ErrorState,
+    /// TEC or REC reached the warning limit of 96
+    pub warning: bool,
+    /// Transmit error counter
+    pub tec: u8,
+    /// Receive error counter
+    pub rec: u8,
+    pub last_error_code: LastErrorCode,
+}
+
+impl ErrorStatus {
+    /// Decodes the raw ESR register value
+    pub fn from_esr(esr: u32) -> Self {
+        let state = if (esr & (1 << 2)) != 0 {
+            ErrorState::BusOff
+        } else if (esr & (1 << 1)) != 0 {
+            ErrorState::Passive
+        } else {
+            ErrorState::Active
+        };
+
+        ErrorStatus {
+            state,
+            warning: (esr & 1) != 0,
+            tec: (esr >> 16) as u8,
+            rec: (esr >> 24) as u8,
+            last_error_code: LastErrorCode::from(((esr >> 4) & 0b111) as u8),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum FilterMode {
     IdMask,
     IdList,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum FilterScale {
     Fs16Bit,
     Fs32Bit,
 }
 
+/// See `Filter` for typed constructors.
+///
 /// NOTE: for 16 bit ID list mode filters, ID needs to be shifted left by 5.
 ///
 /// Example:
@@ -164,6 +471,42 @@ impl Default for CanFilterConfig {
     }
 }
 
+/// Arbitration priority of a frame, lower values win arbitration.
+///
+/// Follows the order of the arbitration field on the bus:
+/// base ID, RTR/SRR, IDE, extended ID, RTR.
+pub fn arbitration_priority(frame: &CanFrame) -> u32 {
+    let remote = if let CanFrame::RemoteFrame(_) = *frame {
+        1
+    } else {
+        0
+    };
+
+    match frame.id() {
+        ID::BaseID(id) => (u32::from(u16::from(id)) << 21) | (remote << 20),
+        ID::ExtendedID(id) => {
+            let id = u32::from(id);
+
+            ((id >> 18) << 21) | (1 << 20) | (1 << 19) | ((id & 0x3_FFFF) << 1) | remote
+        }
+    }
+}
+
+/// Transmit mailbox access, implemented by every `Can` instance
+pub trait Transmitter {
+    /// See `Can::try_transmit()`
+    fn try_transmit(&self, frame: &HalFrame) -> nb::Result<TxMailbox, CanError>;
+
+    /// See `Can::tx_status()`
+    fn tx_status(&self, mailbox: &TxMailbox) -> TxStatus;
+
+    /// See `Can::clear_tx_status()`
+    fn clear_tx_status(&self, mailbox: &TxMailbox);
+
+    /// See `Can::abort()`
+    fn abort(&self, mailbox: &TxMailbox);
+}
+
 // FIXME these should be "closed" traits
 /// TX pin - DO NOT IMPLEMENT THIS TRAIT
 pub unsafe trait TxPin<CAN> {}
@@ -173,56 +516,114 @@ pub unsafe trait RxPin<CAN> {}
 
 unsafe impl TxPin<CAN1> for PD1<AF9> {}
 unsafe impl TxPin<CAN2> for PB13<AF9> {}
+unsafe impl TxPin<CAN3> for PA15<AF11> {}
+unsafe impl TxPin<CAN3> for PB4<AF11> {}
 
 unsafe impl RxPin<CAN1> for PD0<AF9> {}
 unsafe impl RxPin<CAN2> for PB12<AF9> {}
+unsafe impl RxPin<CAN3> for PA8<AF11> {}
+unsafe impl RxPin<CAN3> for PB3<AF11> {}
 
 // CAN abstraction
 pub struct Can<CAN, PINS> {
     can: CAN,
     pins: PINS,
+    ack_timeout: u32,
+    // mailbox aborted by the embedded-hal transmit to make room
+    replacing: Option<TxMailbox>,
+}
+
+// Number of status register polls lasting at least `ACK_TIMEOUT_BITS`,
+// a poll takes at least one system clock cycle
+fn ack_timeout(clocks: &Clocks, bit_timing: &CanBitTiming) -> u32 {
+    let bitrate = cmp::max(bit_timing.bitrate(clocks).0, 1);
+
+    (clocks.sysclk().0 / bitrate).saturating_mul(ACK_TIMEOUT_BITS)
+}
+
+// Polls `done` at most `timeout` times
+fn wait_for<F>(timeout: u32, error: CanError, mut done: F) -> Result<(), CanError>
+where
+    F: FnMut() -> bool,
+{
+    for _ in 0..timeout {
+        if done() {
+            return Ok(());
+        }
+    }
+
+    if done() {
+        Ok(())
+    } else {
+        Err(error)
+    }
 }
 
 macro_rules! hal {
     ($(
-        $CANX:ident: ($canX:ident, $canXen:ident, $canXrst:ident, $init_wait:ident),
+        $CANX:ident: (
+            $canX:ident,
+            $canXen:expr,
+            $slave:ident,
+            $FILTERS:ident,
+            $num_filters:expr,
+            $shared_filters:ident
+        ),
     )+) => {
         $(
 impl<TX, RX> Can<$CANX, (TX, RX)> {
+    /// Enables and configures the controller.
+    ///
+    /// If initialization mode can't be entered or left in time, the error
+    /// is returned along with the peripheral and the pins.
     pub fn $canX(
         can: $CANX,
         pins: (TX, RX),
         apb: &mut APB1,
+        clocks: &Clocks,
         settings: &CanConfig,
-    ) -> Result<Self, CanError>
+    ) -> Result<Self, (CanError, $CANX, (TX, RX))>
     where
         TX: TxPin<$CANX>,
         RX: RxPin<$CANX>,
     {
+        // NOTE the PAC doesn't define the CAN3 enable/reset bits, all
+        // instances use the bit position instead, same for both registers
+        let en_bit = 1 << $canXen;
+
         // enable
-        apb.enr().modify(|_, w| w.$canXen().set_bit());
+        apb.enr().modify(|r, w| unsafe { w.bits(r.bits() | en_bit) });
+
+        // the slave (CAN2) only works with the master (CAN1) clocked,
+        // the master is not reset since it may already be in use
+        if $slave {
+            apb.enr().modify(|r, w| unsafe { w.bits(r.bits() | (1 << CAN1_EN_BIT)) });
+        }
 
         // reset
-        apb.rstr().modify(|_, w| w.$canXrst().set_bit());
-        apb.rstr().modify(|_, w| w.$canXrst().clear_bit());
+        apb.rstr().modify(|r, w| unsafe { w.bits(r.bits() | en_bit) });
+        apb.rstr().modify(|r, w| unsafe { w.bits(r.bits() & !en_bit) });
 
         // master CAN reset
         can.mcr.modify(|_, w| w.reset().set_bit());
 
-        // exit from sleep mode
-        can.mcr.modify(|_, w| w.sleep().clear_bit());
-        // TODO - ref impl doesn't wait
-        //while can.msr.read().slak().bit() == true {}
+        let ack_timeout = ack_timeout(clocks, &settings.bit_timing);
 
-        // request initialization mode
-        can.mcr.modify(|_, w| w.inrq().set_bit());
+        // exit from sleep mode and request initialization mode
+        can.mcr.modify(|_, w| w.sleep().clear_bit().inrq().set_bit());
 
-        // TODO - timeout?
         // wait for ack
-        while can.msr.read().inak().bit() == false {}
+        let entered = wait_for(ack_timeout, CanError::InitTimeout, || {
+            let msr = can.msr.read();
+            msr.inak().bit() && !msr.slak().bit()
+        });
 
-        // clear wakeup interrupt
-        can.msr.modify(|_, w| w.wkui().clear_bit());
+        if let Err(e) = entered {
+            return Err((e, can, pins));
+        }
+
+        // clear wakeup interrupt, cleared by writing 1
+        can.msr.write(|w| w.wkui().set_bit());
 
         // apply settings/configurations
         can.mcr.modify(|_, w| w.ttcm().bit(settings.ttcm));
@@ -250,31 +651,43 @@ impl<TX, RX> Can<$CANX, (TX, RX)> {
         // request to leave inialization mode
         can.mcr.modify(|_, w| w.inrq().clear_bit());
 
-        // TODO - timeout?
         // wait for ack
-        // TODO - conditional added because it seems only master (CAN1)
-        // responds with INAK, needs more testing
-        if $init_wait {
-            while can.msr.read().inak().bit() == true {}
+        let left = wait_for(ack_timeout, CanError::LeaveInitTimeout, || {
+            !can.msr.read().inak().bit()
+        });
+
+        if let Err(e) = left {
+            return Err((e, can, pins));
         }
 
-        Ok(Can { can, pins })
+        Ok(Can {
+            can,
+            pins,
+            ack_timeout,
+            replacing: None,
+        })
     }
 
     pub fn configure_filter(&self, config: &CanFilterConfig) -> Result<(), CanError> {
         // CAN1/2 share the same filters, so CAN2 is actually
-        // accessing CAN1 IP block
-        let can = unsafe { &*CAN1::ptr() };
+        // accessing CAN1 IP block, CAN3 has its own
+        let can = unsafe { &*$FILTERS::ptr() };
+
+        if config.filter_number >= $num_filters {
+            return Err(CanError::ConfigurationFailed);
+        }
 
         let filter_num_bitpos = 1 << config.filter_number;
 
         // enter filter initialization mode
         can.fmr.modify(|_, w| w.finit().set_bit());
 
-        // select start slave bank
-        can
-            .fmr
-            .modify(|_, w| unsafe { w.can2sb().bits(config.bank_number) });
+        // select start slave bank, only used by the shared filters
+        if $shared_filters {
+            can
+                .fmr
+                .modify(|_, w| unsafe { w.can2sb().bits(config.bank_number) });
+        }
 
         // filter deactivation
         can
@@ -615,88 +1028,88 @@ impl<TX, RX> Can<$CANX, (TX, RX)> {
                 // 32 bit mask or second 32 bit id
                 match config.filter_number {
                     0 => can.f0r2.write(|w| unsafe {
-                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_high))
+                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_low))
                     }),
                     1 => can.f1r2.write(|w| unsafe {
-                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_high))
+                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_low))
                     }),
                     2 => can.f2r2.write(|w| unsafe {
-                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_high))
+                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_low))
                     }),
                     3 => can.f3r2.write(|w| unsafe {
-                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_high))
+                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_low))
                     }),
                     4 => can.f4r2.write(|w| unsafe {
-                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_high))
+                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_low))
                     }),
                     5 => can.f5r2.write(|w| unsafe {
-                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_high))
+                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_low))
                     }),
                     6 => can.f6r2.write(|w| unsafe {
-                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_high))
+                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_low))
                     }),
                     7 => can.f7r2.write(|w| unsafe {
-                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_high))
+                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_low))
                     }),
                     8 => can.f8r2.write(|w| unsafe {
-                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_high))
+                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_low))
                     }),
                     9 => can.f9r2.write(|w| unsafe {
-                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_high))
+                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_low))
                     }),
                     10 => can.f10r2.write(|w| unsafe {
-                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_high))
+                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_low))
                     }),
                     11 => can.f11r2.write(|w| unsafe {
-                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_high))
+                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_low))
                     }),
                     12 => can.f12r2.write(|w| unsafe {
-                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_high))
+                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_low))
                     }),
                     13 => can.f13r2.write(|w| unsafe {
-                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_high))
+                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_low))
                     }),
                     14 => can.f14r2.write(|w| unsafe {
-                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_high))
+                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_low))
                     }),
                     15 => can.f15r2.write(|w| unsafe {
-                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_high))
+                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_low))
                     }),
                     16 => can.f16r2.write(|w| unsafe {
-                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_high))
+                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_low))
                     }),
                     17 => can.f17r2.write(|w| unsafe {
-                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_high))
+                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_low))
                     }),
                     18 => can.f18r2.write(|w| unsafe {
-                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_high))
+                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_low))
                     }),
                     19 => can.f19r2.write(|w| unsafe {
-                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_high))
+                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_low))
                     }),
                     20 => can.f20r2.write(|w| unsafe {
-                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_high))
+                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_low))
                     }),
                     21 => can.f21r2.write(|w| unsafe {
-                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_high))
+                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_low))
                     }),
                     22 => can.f22r2.write(|w| unsafe {
-                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_high))
+                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_low))
                     }),
                     23 => can.f23r2.write(|w| unsafe {
-                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_high))
+                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_low))
                     }),
                     24 => can.f24r2.write(|w| unsafe {
-                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_high))
+                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_low))
                     }),
                     25 => can.f25r2.write(|w| unsafe {
-                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_high))
+                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_low))
                     }),
                     26 => can.f26r2.write(|w| unsafe {
-                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_high))
+                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_low))
                     }),
                     27 => can.f27r2.write(|w| unsafe {
-                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_high))
+                        w.bits((config.filter_mask_id_high << 16) | (config.filter_mask_id_low))
                     }),
                     _ => return Err(CanError::ConfigurationFailed),
                 }
@@ -706,20 +1119,506 @@ impl<TX, RX> Can<$CANX, (TX, RX)> {
         Ok(())
     }
 
+    /// Starts listening for an interrupt event
+    pub fn listen(&mut self, event: Event) {
+        match event {
+            Event::Fifo0MessagePending => self.can.ier.modify(|_, w| w.fmpie0().set_bit()),
+            Event::Fifo1MessagePending => self.can.ier.modify(|_, w| w.fmpie1().set_bit()),
+            Event::TransmitMailboxEmpty => self.can.ier.modify(|_, w| w.tmeie().set_bit()),
+            Event::ErrorWarning => self
+                .can
+                .ier
+                .modify(|_, w| w.ewgie().set_bit().errie().set_bit()),
+            Event::ErrorPassive => self
+                .can
+                .ier
+                .modify(|_, w| w.epvie().set_bit().errie().set_bit()),
+            Event::BusOff => self.can.ier.modify(|_, w| w.bofie().set_bit().errie().set_bit()),
+            Event::LastErrorCode => self
+                .can
+                .ier
+                .modify(|_, w| w.lecie().set_bit().errie().set_bit()),
+            Event::Wakeup => self.can.ier.modify(|_, w| w.wkuie().set_bit()),
+            Event::Sleep => self.can.ier.modify(|_, w| w.slkie().set_bit()),
+        }
+    }
+
+    /// Stops listening for an interrupt event
+    pub fn unlisten(&mut self, event: Event) {
+        match event {
+            Event::Fifo0MessagePending => self.can.ier.modify(|_, w| w.fmpie0().clear_bit()),
+            Event::Fifo1MessagePending => self.can.ier.modify(|_, w| w.fmpie1().clear_bit()),
+            Event::TransmitMailboxEmpty => self.can.ier.modify(|_, w| w.tmeie().clear_bit()),
+            Event::ErrorWarning => self.can.ier.modify(|_, w| w.ewgie().clear_bit()),
+            Event::ErrorPassive => self.can.ier.modify(|_, w| w.epvie().clear_bit()),
+            Event::BusOff => self.can.ier.modify(|_, w| w.bofie().clear_bit()),
+            Event::LastErrorCode => self.can.ier.modify(|_, w| w.lecie().clear_bit()),
+            Event::Wakeup => self.can.ier.modify(|_, w| w.wkuie().clear_bit()),
+            Event::Sleep => self.can.ier.modify(|_, w| w.slkie().clear_bit()),
+        }
+
+        // the error interrupt is only needed by the error events
+        let ier = self.can.ier.read();
+        if !(ier.ewgie().bit() || ier.epvie().bit() || ier.bofie().bit() || ier.lecie().bit()) {
+            self.can.ier.modify(|_, w| w.errie().clear_bit());
+        }
+    }
+
+    /// Returns the fault confinement state, error counters and last
+    /// error code
+    pub fn error_state(&self) -> ErrorStatus {
+        ErrorStatus::from_esr(self.can.esr.read().bits())
+    }
+
+    /// Sets the last error code to `LastErrorCode::SetBySoftware` so that
+    /// the next error detected by hardware can be told apart
+    pub fn clear_last_error_code(&self) {
+        self.can.esr.write(|w| unsafe { w.lec().bits(0b111) });
+    }
+
+    /// Clears the error interrupt flag, to be called from the status
+    /// change/error interrupt handler
+    pub fn clear_error_interrupt(&self) {
+        // ERRI is cleared by writing 1, other bits are no-ops with 0
+        self.can.msr.write(|w| w.erri().set_bit());
+    }
+
+    /// Requests sleep mode, pending transmissions are completed first.
+    ///
+    /// With `CanConfig.awum` set, bus activity wakes the controller up,
+    /// otherwise `wakeup()` must be called.
+    pub fn sleep(&mut self) -> Result<(), CanError> {
+        self.can.mcr.modify(|_, w| w.inrq().clear_bit().sleep().set_bit());
+
+        // wait for ack
+        let can = &self.can;
+        wait_for(self.ack_timeout, CanError::SleepTimeout, || {
+            let msr = can.msr.read();
+            msr.slak().bit() && !msr.inak().bit()
+        })
+    }
+
+    /// Leaves sleep mode, returns once the controller has synchronized
+    /// to the bus
+    pub fn wakeup(&mut self) -> Result<(), CanError> {
+        self.can.mcr.modify(|_, w| w.sleep().clear_bit());
+
+        // wait for ack
+        let can = &self.can;
+        wait_for(self.ack_timeout, CanError::WakeupTimeout, || {
+            !can.msr.read().slak().bit()
+        })
+    }
+
+    /// Returns true if the controller acknowledged sleep mode
+    pub fn is_sleeping(&self) -> bool {
+        self.can.msr.read().slak().bit()
+    }
+
+    /// Clears the wakeup and sleep acknowledge interrupt flags, to be
+    /// called from the status change/error interrupt handler
+    pub fn clear_wakeup_interrupt(&self) {
+        // cleared by writing 1, other bits are no-ops with 0
+        self.can.msr.write(|w| w.wkui().set_bit().slaki().set_bit());
+    }
+
+    /// Runs a self test in loopback and silent mode, the controller is
+    /// disconnected from the bus during the test.
+    ///
+    /// A pattern of standard, extended and remote frames is transmitted and
+    /// each must be received back unchanged, so the filters must accept
+    /// them.
+    ///
+    /// Frames pending in the receive FIFOs are discarded so they can't be
+    /// mistaken for the pattern, receive them before calling this to keep
+    /// them.
+    ///
+    /// The previous mode is restored afterwards, also when entering the
+    /// test mode fails.
+    pub fn self_test(&mut self) -> Result<SelfTestReport, CanError> {
+        let btr = self.can.btr.read();
+        let (silent, loopback) = (btr.silm().bit(), btr.lbkm().bit());
+
+        let report = self
+            .set_test_mode(true, true)
+            .map(|_| self.run_self_test());
+        let restored = self.set_test_mode(silent, loopback);
+
+        let report = report?;
+        restored?;
+
+        Ok(report)
+    }
+
+    fn run_self_test(&self) -> SelfTestReport {
+        // discard pending frames
+        while self.can.rf0r.read().fmp0().bits() != 0 {
+            self.can.rf0r.write(|w| w.rfom0().set_bit());
+        }
+        while self.can.rf1r.read().fmp1().bits() != 0 {
+            self.can.rf1r.write(|w| w.rfom1().set_bit());
+        }
+
+        let mut results = [SelfTestResult::NotReceived; SELF_TEST_FRAMES];
+        for (index, result) in results.iter_mut().enumerate() {
+            *result = self.self_test_frame(&self_test::pattern_frame(index));
+        }
+
+        SelfTestReport {
+            results,
+            error_status: self.error_state(),
+        }
+    }
+
+    // Silent and loopback modes can only be changed in initialization mode
+    fn set_test_mode(&self, silent: bool, loopback: bool) -> Result<(), CanError> {
+        let can = &self.can;
+
+        can.mcr.modify(|_, w| w.inrq().set_bit());
+        wait_for(self.ack_timeout, CanError::InitTimeout, || {
+            can.msr.read().inak().bit()
+        })?;
+
+        can.btr.modify(|_, w| w.silm().bit(silent).lbkm().bit(loopback));
+
+        can.mcr.modify(|_, w| w.inrq().clear_bit());
+        wait_for(self.ack_timeout, CanError::LeaveInitTimeout, || {
+            !can.msr.read().inak().bit()
+        })
+    }
+
+    fn self_test_frame(&self, frame: &CanFrame) -> SelfTestResult {
+        // the pattern's remote frames request no data
+        let mailbox = match self.request(frame, 0, false) {
+            Ok(mailbox) => mailbox,
+            Err(_) => return SelfTestResult::TransmitFailed,
+        };
+
+        // wait for completion
+        let mut status = TxStatus::Pending;
+        for _ in 0..self.ack_timeout {
+            status = self.tx_status(&mailbox);
+            if status != TxStatus::Pending {
+                break;
+            }
+        }
+
+        if status == TxStatus::Pending {
+            self.abort(&mailbox);
+        }
+        self.clear_tx_status(&mailbox);
+
+        if status != TxStatus::Success {
+            return SelfTestResult::TransmitFailed;
+        }
+
+        for _ in 0..self.ack_timeout {
+            let received = match self.receive(&RxFifo::Fifo0) {
+                Err(CanError::BufferExhausted) => self.receive(&RxFifo::Fifo1),
+                r => r,
+            };
+
+            match received {
+                Ok(ref r) if self_test::frames_match(frame, r) => {
+                    return SelfTestResult::Passed
+                }
+                Err(CanError::BufferExhausted) => (),
+                _ => return SelfTestResult::Corrupted,
+            }
+        }
+
+        SelfTestResult::NotReceived
+    }
+
+    /// Moves the pending frames of a hardware FIFO into a queue, intended
+    /// to be called from the FIFO message pending interrupt handler.
+    ///
+    /// Hardware FIFO overruns and frames dropped because the queue is full
+    /// are counted as queue overruns.
+    ///
+    /// Returns the number of frames enqueued.
+    pub fn receive_into(&self, fifo: &RxFifo, producer: &mut Producer<CanFrame>) -> usize {
+        if self.clear_fifo_overrun(fifo) {
+            producer.record_overrun();
+        }
+
+        let mut count = 0;
+
+        while let Ok(frame) = self.receive(fifo) {
+            if producer.enqueue(frame).is_ok() {
+                count += 1;
+            }
+        }
+
+        count
+    }
+
+    /// Same as `receive_frame()`, also records the frame and the FIFO
+    /// overruns in `stats`
+    pub fn receive_frame_with_stats(
+        &self,
+        fifo: &RxFifo,
+        stats: &mut CanStats,
+    ) -> Result<ReceivedFrame, CanError> {
+        if self.clear_fifo_overrun(fifo) {
+            stats.record_overruns(1);
+        }
+
+        let received = self.receive_frame(fifo)?;
+        stats.record_rx(&received.frame);
+
+        Ok(received)
+    }
+
+    /// Records the outcome of a completed transmission in `stats` and
+    /// clears the mailbox status, intended to be called from the transmit
+    /// mailbox empty interrupt handler. `CanTxQueue` users should call
+    /// `CanStats::record_tx_status()` instead, the queue needs the status.
+    ///
+    /// Returns the status, see `tx_status()`.
+    pub fn collect_tx_status(&self, mailbox: &TxMailbox, stats: &mut CanStats) -> TxStatus {
+        let status = self.tx_status(mailbox);
+
+        match status {
+            TxStatus::Idle | TxStatus::Pending => (),
+            _ => {
+                stats.record_tx_status(self.mailbox_frame(mailbox).as_frame(), status);
+                self.clear_tx_status(mailbox);
+            }
+        }
+
+        status
+    }
+
+    // Clears the FIFO overrun flag, returns true if it was set
+    fn clear_fifo_overrun(&self, fifo: &RxFifo) -> bool {
+        match fifo {
+            RxFifo::Fifo0 => {
+                let overrun = self.can.rf0r.read().fovr0().bit();
+
+                if overrun {
+                    // FOVR is cleared by writing 1, other bits are no-ops with 0
+                    self.can.rf0r.write(|w| w.fovr0().set_bit());
+                }
+
+                overrun
+            }
+            RxFifo::Fifo1 => {
+                let overrun = self.can.rf1r.read().fovr1().bit();
+
+                if overrun {
+                    // FOVR is cleared by writing 1, other bits are no-ops with 0
+                    self.can.rf1r.write(|w| w.fovr1().set_bit());
+                }
+
+                overrun
+            }
+        }
+    }
+
+    /// Transmits a frame and waits for its completion, up to
+    /// `ACK_TIMEOUT_BITS` bit times, aborting it on a timeout
     pub fn transmit(&self, frame: &CanFrame) -> Result<(), CanError> {
-        // select an empty tx mailbox
-        if self.can.tsr.read().tme0().bit() {
-            self.transmit_mb0(frame)
-        } else if self.can.tsr.read().tme1().bit() {
-            self.transmit_mb1(frame)
-        } else if self.can.tsr.read().tme2().bit() {
-            self.transmit_mb2(frame)
+        let mailbox = match self.request(frame, 0, false) {
+            Ok(mailbox) => mailbox,
+            // all mailboxes are in use
+            Err(nb::Error::WouldBlock) => return Err(CanError::BufferExhausted),
+            Err(nb::Error::Other(e)) => return Err(e),
+        };
+
+        // wait for completion
+        let completed = wait_for(self.ack_timeout, CanError::Timeout, || {
+            self.tx_status(&mailbox) != TxStatus::Pending
+        });
+
+        if let Err(e) = completed {
+            self.abort(&mailbox);
+            return Err(e);
+        }
+
+        match self.tx_status(&mailbox) {
+            TxStatus::Success => Ok(()),
+            _ => Err(CanError::Timeout),
+        }
+    }
+
+    /// Queues a frame in an empty transmit mailbox without waiting for
+    /// its completion, see `tx_status()`.
+    ///
+    /// Remote frames are sent with the DLC of the `HalFrame`, see
+    /// `Frame::new_remote()`, frames converted from a `CanFrame` have 0.
+    pub fn try_transmit(&self, frame: &HalFrame) -> nb::Result<TxMailbox, CanError> {
+        self.request(frame.as_frame(), frame.remote_dlc(), false)
+    }
+
+    /// Requests the transmission of an 8 byte data frame with the time
+    /// triggered communication mode time stamp, hardware replaces data
+    /// bytes 6 and 7 with the bit-time counter value at start of frame.
+    ///
+    /// Fails with `ConfigurationFailed` if `CanConfig.ttcm` wasn't set.
+    pub fn transmit_with_timestamp(&self, frame: &CanFrame) -> nb::Result<TxMailbox, CanError> {
+        if !self.can.mcr.read().ttcm().bit() {
+            return Err(nb::Error::Other(CanError::ConfigurationFailed));
+        }
+
+        match *frame {
+            CanFrame::DataFrame(ref f) if f.data().len() == 8 => self.request(frame, 0, true),
+            _ => Err(nb::Error::Other(CanError::InvalidFrame)),
+        }
+    }
+
+    /// Returns the bit-time counter value captured at the start of frame
+    /// of the last transmission from a mailbox, time triggered
+    /// communication mode only
+    pub fn tx_timestamp(&self, mailbox: &TxMailbox) -> u16 {
+        match mailbox {
+            TxMailbox::Mailbox0 => self.can.tdt0r.read().time().bits(),
+            TxMailbox::Mailbox1 => self.can.tdt1r.read().time().bits(),
+            TxMailbox::Mailbox2 => self.can.tdt2r.read().time().bits(),
+        }
+    }
+
+    fn request(
+        &self,
+        frame: &CanFrame,
+        remote_dlc: u8,
+        global_time: bool,
+    ) -> nb::Result<TxMailbox, CanError> {
+        let tsr = self.can.tsr.read();
+
+        let mailbox = if tsr.tme0().bit() {
+            TxMailbox::Mailbox0
+        } else if tsr.tme1().bit() {
+            TxMailbox::Mailbox1
+        } else if tsr.tme2().bit() {
+            TxMailbox::Mailbox2
         } else {
             // all mailboxes are in use
-            Err(CanError::BufferExhausted)
+            return Err(nb::Error::WouldBlock);
+        };
+
+        let result = match mailbox {
+            TxMailbox::Mailbox0 => self.request_mb0(frame, remote_dlc, global_time),
+            TxMailbox::Mailbox1 => self.request_mb1(frame, remote_dlc, global_time),
+            TxMailbox::Mailbox2 => self.request_mb2(frame, remote_dlc, global_time),
+        };
+
+        result.map(|_| mailbox).map_err(nb::Error::Other)
+    }
+
+    /// Returns the status of a transmit mailbox
+    pub fn tx_status(&self, mailbox: &TxMailbox) -> TxStatus {
+        let tsr = self.can.tsr.read();
+
+        let (tme, rqcp, txok, alst, terr) = match mailbox {
+            TxMailbox::Mailbox0 => (
+                tsr.tme0().bit(),
+                tsr.rqcp0().bit(),
+                tsr.txok0().bit(),
+                tsr.alst0().bit(),
+                tsr.terr0().bit(),
+            ),
+            TxMailbox::Mailbox1 => (
+                tsr.tme1().bit(),
+                tsr.rqcp1().bit(),
+                tsr.txok1().bit(),
+                tsr.alst1().bit(),
+                tsr.terr1().bit(),
+            ),
+            TxMailbox::Mailbox2 => (
+                tsr.tme2().bit(),
+                tsr.rqcp2().bit(),
+                tsr.txok2().bit(),
+                tsr.alst2().bit(),
+                tsr.terr2().bit(),
+            ),
+        };
+
+        if !tme {
+            TxStatus::Pending
+        } else if !rqcp {
+            TxStatus::Idle
+        } else if txok {
+            TxStatus::Success
+        } else if alst {
+            TxStatus::ArbitrationLost
+        } else if terr {
+            TxStatus::Error
+        } else {
+            TxStatus::Aborted
+        }
+    }
+
+    /// Clears the completion status of a transmit mailbox, also
+    /// acknowledges the transmit mailbox empty interrupt
+    pub fn clear_tx_status(&self, mailbox: &TxMailbox) {
+        // RQCP is cleared by writing 1, clearing TXOK/ALST/TERR with it,
+        // other bits are no-ops with 0
+        match mailbox {
+            TxMailbox::Mailbox0 => self.can.tsr.write(|w| w.rqcp0().set_bit()),
+            TxMailbox::Mailbox1 => self.can.tsr.write(|w| w.rqcp1().set_bit()),
+            TxMailbox::Mailbox2 => self.can.tsr.write(|w| w.rqcp2().set_bit()),
         }
     }
 
+    /// Requests to abort a pending transmission, a frame already being
+    /// transmitted still completes
+    pub fn abort(&self, mailbox: &TxMailbox) {
+        // ABRQ is set by software, other bits are no-ops with 0
+        match mailbox {
+            TxMailbox::Mailbox0 => self.can.tsr.write(|w| w.abrq0().set_bit()),
+            TxMailbox::Mailbox1 => self.can.tsr.write(|w| w.abrq1().set_bit()),
+            TxMailbox::Mailbox2 => self.can.tsr.write(|w| w.abrq2().set_bit()),
+        }
+    }
+
+    // Reads back the frame held by a transmit mailbox
+    fn mailbox_frame(&self, mailbox: &TxMailbox) -> HalFrame {
+        let (tir, tdtr, tdlr, tdhr) = match mailbox {
+            TxMailbox::Mailbox0 => (
+                self.can.ti0r.read().bits(),
+                self.can.tdt0r.read().bits(),
+                self.can.tdl0r.read().bits(),
+                self.can.tdh0r.read().bits(),
+            ),
+            TxMailbox::Mailbox1 => (
+                self.can.ti1r.read().bits(),
+                self.can.tdt1r.read().bits(),
+                self.can.tdl1r.read().bits(),
+                self.can.tdh1r.read().bits(),
+            ),
+            TxMailbox::Mailbox2 => (
+                self.can.ti2r.read().bits(),
+                self.can.tdt2r.read().bits(),
+                self.can.tdl2r.read().bits(),
+                self.can.tdh2r.read().bits(),
+            ),
+        };
+
+        // IDE
+        let id = if (tir & (1 << 2)) != 0 {
+            ID::ExtendedID(ExtendedID::new(tir >> 3))
+        } else {
+            ID::BaseID(BaseID::new((tir >> 21) as u16))
+        };
+        let dlc = cmp::min(tdtr & 0xF, 8) as u8;
+
+        // RTR
+        if (tir & (1 << 1)) != 0 {
+            return HalFrame::with_dlc(CanFrame::from(RemoteFrame::new(id)), dlc);
+        }
+
+        let mut data_frame = DataFrame::new(id);
+        data_frame.set_data_length(dlc as usize);
+
+        for (i, byte) in data_frame.data_as_mut().iter_mut().enumerate() {
+            let word = if i < 4 { tdlr } else { tdhr };
+            *byte = (word >> (8 * (i % 4))) as u8;
+        }
+
+        HalFrame::from(CanFrame::from(data_frame))
+    }
+
     pub fn receive(&self, fifo: &RxFifo) -> Result<CanFrame, CanError> {
         match fifo {
             RxFifo::Fifo0 => self.receive_fifo0(),
@@ -727,7 +1626,23 @@ impl<TX, RX> Can<$CANX, (TX, RX)> {
         }
     }
 
+    /// Receives a frame along with the filter match index and timestamp
+    pub fn receive_frame(&self, fifo: &RxFifo) -> Result<ReceivedFrame, CanError> {
+        match fifo {
+            RxFifo::Fifo0 => self.receive_frame_fifo0(),
+            RxFifo::Fifo1 => self.receive_frame_fifo1(),
+        }
+    }
+
     pub fn receive_fifo0(&self) -> Result<CanFrame, CanError> {
+        self.receive_frame_fifo0().map(|r| r.frame)
+    }
+
+    pub fn receive_fifo1(&self) -> Result<CanFrame, CanError> {
+        self.receive_frame_fifo1().map(|r| r.frame)
+    }
+
+    fn receive_frame_fifo0(&self) -> Result<ReceivedFrame, CanError> {
         // gather relevant registers
         let (rfr, rir, rdtr, rdlr, rdhr) = (
             &self.can.rf0r,
@@ -753,7 +1668,10 @@ impl<TX, RX> Can<$CANX, (TX, RX)> {
         };
 
         let remote_frame = rir.read().rtr().bit();
-        let dlc = rdtr.read().dlc().bits() as usize;
+        // DLC values above 8 still carry 8 data bytes
+        let dlc = cmp::min(rdtr.read().dlc().bits() as usize, 8);
+        let filter_match_index = rdtr.read().fmi().bits();
+        let timestamp = rdtr.read().time().bits();
 
         let frame = if remote_frame {
             CanFrame::from(RemoteFrame::new(id))
@@ -789,10 +1707,16 @@ impl<TX, RX> Can<$CANX, (TX, RX)> {
             .full0().clear_bit()
         });
 
-        Ok(frame)
+        Ok(ReceivedFrame {
+            frame,
+            dlc: dlc as u8,
+            fifo: RxFifo::Fifo0,
+            filter_match_index,
+            timestamp,
+        })
     }
 
-    pub fn receive_fifo1(&self) -> Result<CanFrame, CanError> {
+    fn receive_frame_fifo1(&self) -> Result<ReceivedFrame, CanError> {
         // gather relevant registers
         let (rfr, rir, rdtr, rdlr, rdhr) = (
             &self.can.rf1r,
@@ -812,15 +1736,16 @@ impl<TX, RX> Can<$CANX, (TX, RX)> {
 
         // get ID
         let id = if ext_id {
-            ID::ExtendedID(ExtendedID::new(
-                rir.read().exid().bits() | (rir.read().stid().bits() << 18) as u32,
-            ))
+            ID::ExtendedID(ExtendedID::new(rir.read().bits() >> 3 as u32))
         } else {
             ID::BaseID(BaseID::new(rir.read().stid().bits()))
         };
 
         let remote_frame = rir.read().rtr().bit();
-        let dlc = rdtr.read().dlc().bits() as usize;
+        // DLC values above 8 still carry 8 data bytes
+        let dlc = cmp::min(rdtr.read().dlc().bits() as usize, 8);
+        let filter_match_index = rdtr.read().fmi().bits();
+        let timestamp = rdtr.read().time().bits();
 
         let frame = if remote_frame {
             CanFrame::from(RemoteFrame::new(id))
@@ -856,36 +1781,21 @@ impl<TX, RX> Can<$CANX, (TX, RX)> {
             .full1().clear_bit()
         });
 
-        Ok(frame)
+        Ok(ReceivedFrame {
+            frame,
+            dlc: dlc as u8,
+            fifo: RxFifo::Fifo1,
+            filter_match_index,
+            timestamp,
+        })
     }
 
-    fn get_tx_status(&self, mb: &TxMailbox) -> bool {
-        match mb {
-            TxMailbox::Mailbox0 => {
-                let rqcp = self.can.tsr.read().rqcp0().bit();
-                let txok = self.can.tsr.read().txok0().bit();
-                let tme = self.can.tsr.read().tme0().bit();
-
-                rqcp && txok && tme
-            }
-            TxMailbox::Mailbox1 => {
-                let rqcp = self.can.tsr.read().rqcp1().bit();
-                let txok = self.can.tsr.read().txok1().bit();
-                let tme = self.can.tsr.read().tme1().bit();
-
-                rqcp && txok && tme
-            }
-            TxMailbox::Mailbox2 => {
-                let rqcp = self.can.tsr.read().rqcp2().bit();
-                let txok = self.can.tsr.read().txok2().bit();
-                let tme = self.can.tsr.read().tme2().bit();
-
-                rqcp && txok && tme
-            }
-        }
-    }
-
-    fn transmit_mb0(&self, frame: &CanFrame) -> Result<(), CanError> {
+    fn request_mb0(
+        &self,
+        frame: &CanFrame,
+        remote_dlc: u8,
+        global_time: bool,
+    ) -> Result<(), CanError> {
         // gather relevant registers
         let (tir, tdtr, tdlr, tdhr) = (
             &self.can.ti0r,
@@ -895,9 +1805,17 @@ impl<TX, RX> Can<$CANX, (TX, RX)> {
         );
 
         // setup ID, start from TIxR reset
-        if let ID::ExtendedID(_) = frame.id() {
-            // extented
-            tir.write(|w| unsafe { w.ide().set_bit().exid().bits(frame.id().into()) });
+        if let ID::ExtendedID(id) = frame.id() {
+            // extented, upper 11 bits go in STID
+            let id = u32::from(id);
+            tir.write(|w| unsafe {
+                w.ide()
+                    .set_bit()
+                    .stid()
+                    .bits((id >> 18) as u16)
+                    .exid()
+                    .bits(id & 0x3_FFFF)
+            });
         } else {
             // std
             tir.write(|w| unsafe {
@@ -932,32 +1850,25 @@ impl<TX, RX> Can<$CANX, (TX, RX)> {
                 };
             }
         } else {
-            tdtr.write(|w| unsafe { w.dlc().bits(0) });
+            // the DLC of a remote frame is the requested length
+            tdtr.write(|w| unsafe { w.dlc().bits(remote_dlc) });
         }
 
-        // don't transmit global time
-        tdtr.modify(|_, w| w.tgt().clear_bit());
+        // transmit global time in data bytes 6 and 7, TTCM only
+        tdtr.modify(|_, w| w.tgt().bit(global_time));
 
         // request transmission
         tir.modify(|_, w| w.txrq().set_bit());
 
-        // TODO - timeout and cancel?
-        // wait for completion
-        let mut ticks: u32 = 0;
-        while self.get_tx_status(&TxMailbox::Mailbox0) == false {
-            ticks += 1;
-            if ticks >= MAX_BLOCK_TICKS {
-                // cancel transmit
-                self.can.tsr.modify(|_, w| w.abrq0().set_bit());
-
-                return Err(CanError::Timeout);
-            }
-        }
-
         Ok(())
     }
 
-    fn transmit_mb1(&self, frame: &CanFrame) -> Result<(), CanError> {
+    fn request_mb1(
+        &self,
+        frame: &CanFrame,
+        remote_dlc: u8,
+        global_time: bool,
+    ) -> Result<(), CanError> {
         // gather relevant registers
         let (tir, tdtr, tdlr, tdhr) = (
             &self.can.ti1r,
@@ -967,9 +1878,17 @@ impl<TX, RX> Can<$CANX, (TX, RX)> {
         );
 
         // setup ID, start from TIxR reset
-        if let ID::ExtendedID(_) = frame.id() {
-            // extented
-            tir.write(|w| unsafe { w.ide().set_bit().exid().bits(frame.id().into()) });
+        if let ID::ExtendedID(id) = frame.id() {
+            // extented, upper 11 bits go in STID
+            let id = u32::from(id);
+            tir.write(|w| unsafe {
+                w.ide()
+                    .set_bit()
+                    .stid()
+                    .bits((id >> 18) as u16)
+                    .exid()
+                    .bits(id & 0x3_FFFF)
+            });
         } else {
             // std
             tir.write(|w| unsafe {
@@ -1004,32 +1923,25 @@ impl<TX, RX> Can<$CANX, (TX, RX)> {
                 };
             }
         } else {
-            tdtr.write(|w| unsafe { w.dlc().bits(0) });
+            // the DLC of a remote frame is the requested length
+            tdtr.write(|w| unsafe { w.dlc().bits(remote_dlc) });
         }
 
-        // don't transmit global time
-        tdtr.modify(|_, w| w.tgt().clear_bit());
+        // transmit global time in data bytes 6 and 7, TTCM only
+        tdtr.modify(|_, w| w.tgt().bit(global_time));
 
         // request transmission
         tir.modify(|_, w| w.txrq().set_bit());
 
-        // TODO - timeout and cancel?
-        // wait for completion
-        let mut ticks: u32 = 0;
-        while self.get_tx_status(&TxMailbox::Mailbox1) == false {
-            ticks += 1;
-            if ticks >= MAX_BLOCK_TICKS {
-                // cancel transmit
-                self.can.tsr.modify(|_, w| w.abrq1().set_bit());
-
-                return Err(CanError::Timeout);
-            }
-        }
-
         Ok(())
     }
 
-    fn transmit_mb2(&self, frame: &CanFrame) -> Result<(), CanError> {
+    fn request_mb2(
+        &self,
+        frame: &CanFrame,
+        remote_dlc: u8,
+        global_time: bool,
+    ) -> Result<(), CanError> {
         // gather relevant registers
         let (tir, tdtr, tdlr, tdhr) = (
             &self.can.ti2r,
@@ -1039,9 +1951,17 @@ impl<TX, RX> Can<$CANX, (TX, RX)> {
         );
 
         // setup ID, start from TIxR reset
-        if let ID::ExtendedID(_) = frame.id() {
-            // extented
-            tir.write(|w| unsafe { w.ide().set_bit().exid().bits(frame.id().into()) });
+        if let ID::ExtendedID(id) = frame.id() {
+            // extented, upper 11 bits go in STID
+            let id = u32::from(id);
+            tir.write(|w| unsafe {
+                w.ide()
+                    .set_bit()
+                    .stid()
+                    .bits((id >> 18) as u16)
+                    .exid()
+                    .bits(id & 0x3_FFFF)
+            });
         } else {
             // std
             tir.write(|w| unsafe {
@@ -1076,36 +1996,186 @@ impl<TX, RX> Can<$CANX, (TX, RX)> {
                 };
             }
         } else {
-            tdtr.write(|w| unsafe { w.dlc().bits(0) });
+            // the DLC of a remote frame is the requested length
+            tdtr.write(|w| unsafe { w.dlc().bits(remote_dlc) });
         }
 
-        // don't transmit global time
-        tdtr.modify(|_, w| w.tgt().clear_bit());
+        // transmit global time in data bytes 6 and 7, TTCM only
+        tdtr.modify(|_, w| w.tgt().bit(global_time));
 
         // request transmission
         tir.modify(|_, w| w.txrq().set_bit());
 
-        // TODO - timeout and cancel?
-        // wait for completion
-        let mut ticks: u32 = 0;
-        while self.get_tx_status(&TxMailbox::Mailbox2) == false {
-            ticks += 1;
-            if ticks >= MAX_BLOCK_TICKS {
-                // cancel transmit
-                self.can.tsr.modify(|_, w| w.abrq2().set_bit());
-
-                return Err(CanError::Timeout);
+        Ok(())
+    }
+}
+
+impl<TX, RX> Transmitter for Can<$CANX, (TX, RX)> {
+    fn try_transmit(&self, frame: &HalFrame) -> nb::Result<TxMailbox, CanError> {
+        Can::<$CANX, (TX, RX)>::try_transmit(self, frame)
+    }
+
+    fn tx_status(&self, mailbox: &TxMailbox) -> TxStatus {
+        Can::<$CANX, (TX, RX)>::tx_status(self, mailbox)
+    }
+
+    fn clear_tx_status(&self, mailbox: &TxMailbox) {
+        Can::<$CANX, (TX, RX)>::clear_tx_status(self, mailbox)
+    }
+
+    fn abort(&self, mailbox: &TxMailbox) {
+        Can::<$CANX, (TX, RX)>::abort(self, mailbox)
+    }
+}
+
+/// Receiving polls FIFO 0 before FIFO 1. When every mailbox is pending,
+/// transmitting aborts the lowest priority pending frame if it has a lower
+/// priority than the new one and returns it. `WouldBlock` is returned until
+/// the abort completed, the same frame must be passed again.
+impl<TX, RX> hal::can::nb::Can for Can<$CANX, (TX, RX)> {
+    type Frame = HalFrame;
+    type Error = CanError;
+
+    fn transmit(&mut self, frame: &HalFrame) -> nb::Result<Option<HalFrame>, CanError> {
+        let mailbox = match self.replacing {
+            Some(mailbox) => mailbox,
+            None => {
+                match self.request(frame.as_frame(), frame.remote_dlc(), false) {
+                    Err(nb::Error::WouldBlock) => (),
+                    result => return result.map(|_| None),
+                }
+
+                let mut lowest: Option<(TxMailbox, u32)> = None;
+
+                for mailbox in &[TxMailbox::Mailbox0, TxMailbox::Mailbox1, TxMailbox::Mailbox2] {
+                    let priority = arbitration_priority(self.mailbox_frame(mailbox).as_frame());
+
+                    match lowest {
+                        Some((_, p)) if p >= priority => (),
+                        _ => lowest = Some((*mailbox, priority)),
+                    }
+                }
+
+                let mailbox = match lowest {
+                    Some((mailbox, p)) if p > arbitration_priority(frame.as_frame()) => mailbox,
+                    _ => return Err(nb::Error::WouldBlock),
+                };
+
+                self.abort(&mailbox);
+                self.replacing = Some(mailbox);
+
+                mailbox
             }
+        };
+
+        // a frame already being transmitted still completes
+        let status = self.tx_status(&mailbox);
+        if status == TxStatus::Pending {
+            return Err(nb::Error::WouldBlock);
         }
 
-        Ok(())
+        // the mailbox registers still hold the frame once it is empty
+        let replaced = match status {
+            TxStatus::Aborted => Some(self.mailbox_frame(&mailbox)),
+            _ => None,
+        };
+
+        self.replacing = None;
+        self.clear_tx_status(&mailbox);
+
+        match self.request(frame.as_frame(), frame.remote_dlc(), false) {
+            Ok(_) => Ok(replaced),
+            Err(e) => {
+                // put the aborted frame back rather than losing it
+                if let Some(ref pending) = replaced {
+                    self.request(pending.as_frame(), pending.remote_dlc(), false).ok();
+                }
+
+                Err(e)
+            }
+        }
+    }
+
+    fn receive(&mut self) -> nb::Result<HalFrame, CanError> {
+        let received = match self.receive_frame_fifo0() {
+            Err(CanError::BufferExhausted) => self.receive_frame_fifo1(),
+            result => result,
+        };
+
+        match received {
+            Ok(r) => Ok(HalFrame::with_dlc(r.frame, r.dlc)),
+            Err(CanError::BufferExhausted) => Err(nb::Error::WouldBlock),
+            Err(e) => Err(nb::Error::Other(e)),
+        }
     }
 }
 )+
     }
 }
 
+// (constructor, APB1 enable/reset bit, slave of CAN1, filter block,
+//  number of filter banks, filter banks are shared with CAN2)
 hal! {
-    CAN1: (can1, can1en, can1rst, true),
-    CAN2: (can2, can2en, can2rst, false),
+    CAN1: (can1, CAN1_EN_BIT, false, CAN1, NUM_FILTER_BANKS, true),
+    CAN2: (can2, 26, true, CAN1, NUM_FILTER_BANKS, true),
+    CAN3: (can3, 13, false, CAN3, NUM_CAN3_FILTER_BANKS, false),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PCLK1: Hertz = Hertz(54_000_000);
+
+    #[test]
+    fn bit_timing_from_bitrate() {
+        for &bitrate in &[125_000, 250_000, 500_000, 1_000_000] {
+            let (timing, error) =
+                CanBitTiming::from_bitrate_with_clock(Bps(bitrate), PCLK1, 875).unwrap();
+
+            assert_eq!(error.0, 0);
+            assert_eq!(timing.bitrate_with_clock(PCLK1).0, bitrate);
+            assert!(timing.tq_per_bit() >= MIN_TQ_PER_BIT);
+            assert!(timing.tq_per_bit() <= MAX_TQ_PER_BIT);
+            assert!(timing.bs2 < 8);
+            assert!(timing.sjw <= timing.bs2);
+            assert!((timing.sample_point() >= 850) && (timing.sample_point() <= 900));
+        }
+    }
+
+    #[test]
+    fn bit_timing_registers() {
+        let (timing, _) = CanBitTiming::from_bitrate_with_clock(Bps(500_000), PCLK1, 875).unwrap();
+
+        // 6 * 18 time quanta, 1 + 15 + 2
+        assert_eq!(
+            timing,
+            CanBitTiming {
+                prescaler: 5,
+                sjw: 1,
+                bs1: 14,
+                bs2: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn bit_timing_unreachable_bitrate() {
+        assert_eq!(
+            CanBitTiming::from_bitrate_with_clock(Bps(1_000_000), Hertz(7_000_000), 875).err(),
+            Some(CanError::InvalidBitRate)
+        );
+        assert_eq!(
+            CanBitTiming::from_bitrate_with_clock(Bps(2_000_000), PCLK1, 875).err(),
+            Some(CanError::InvalidBitRate)
+        );
+        assert_eq!(
+            CanBitTiming::from_bitrate_with_clock(Bps(0), PCLK1, 875).err(),
+            Some(CanError::InvalidBitRate)
+        );
+        assert_eq!(
+            CanBitTiming::from_bitrate_with_clock(Bps(500_000), PCLK1, 1000).err(),
+            Some(CanError::InvalidBitRate)
+        );
+    }
 }
@@ -0,0 +1,338 @@
+//! Typed acceptance filters
+//!
+//! Builds the filter bank register values (FiR1/FiR2) from identifiers
+//! instead of hand shifting them into a `CanFilterConfig`.
+//!
+//! Example:
+//! let mut banks = FilterBanks::new(14)?;
+//! let config = banks.allocate_can1(&Filter::standard_mask(BaseID::new(0x80), 0x7F0))?;
+//! can.configure_filter(&config)?;
+//!
+//! CAN3 has its own filter banks, the bank number is used directly:
+//! can3.configure_filter(&Filter::accept_all().to_config(0, 0))?;
+
+use super::{BaseID, CanError, CanFilterConfig, ExtendedID, FilterMode, FilterScale, RxFifo};
+
+/// Number of filter banks shared by CAN1 and CAN2
+pub const NUM_FILTER_BANKS: u8 = 28;
+
+/// Number of filter banks owned by CAN3
+pub const NUM_CAN3_FILTER_BANKS: u8 = 14;
+
+// 32 bit scale layout
+const STID_SHIFT_32: u32 = 21;
+const EXID_SHIFT_32: u32 = 3;
+const IDE_32: u32 = 1 << 2;
+const RTR_32: u32 = 1 << 1;
+
+// 16 bit scale layout
+const STID_SHIFT_16: u32 = 5;
+const RTR_16: u32 = 1 << 4;
+
+/// Acceptance filter bank contents
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Filter {
+    mode: FilterMode,
+    scale: FilterScale,
+    fifo: RxFifo,
+    fr1: u32,
+    fr2: u32,
+}
+
+impl Filter {
+    /// Matches every frame
+    pub fn accept_all() -> Self {
+        Filter::new(FilterMode::IdMask, FilterScale::Fs32Bit, 0, 0)
+    }
+
+    /// Matches standard frames whose ID bits selected by `mask` equal
+    /// those of `id`, data and remote frames alike
+    pub fn standard_mask(id: BaseID, mask: u16) -> Self {
+        let id = u32::from(u16::from(id));
+        let mask = u32::from(mask & 0x7FF);
+
+        Filter::new(
+            FilterMode::IdMask,
+            FilterScale::Fs32Bit,
+            id << STID_SHIFT_32,
+            (mask << STID_SHIFT_32) | IDE_32,
+        )
+    }
+
+    /// Matches extended frames whose ID bits selected by `mask` equal
+    /// those of `id`, data and remote frames alike
+    pub fn extended_mask(id: ExtendedID, mask: u32) -> Self {
+        let id = u32::from(id);
+        let mask = mask & 0x1FFF_FFFF;
+
+        Filter::new(
+            FilterMode::IdMask,
+            FilterScale::Fs32Bit,
+            (id << EXID_SHIFT_32) | IDE_32,
+            (mask << EXID_SHIFT_32) | IDE_32,
+        )
+    }
+
+    /// Matches standard data frames with one of the four IDs
+    pub fn standard_list(ids: &[BaseID; 4]) -> Self {
+        let entry = |id: BaseID| u32::from(u16::from(id)) << STID_SHIFT_16;
+
+        Filter::new(
+            FilterMode::IdList,
+            FilterScale::Fs16Bit,
+            (entry(ids[1]) << 16) | entry(ids[0]),
+            (entry(ids[3]) << 16) | entry(ids[2]),
+        )
+    }
+
+    /// Matches extended data frames with one of the two IDs
+    pub fn extended_list(ids: &[ExtendedID; 2]) -> Self {
+        let entry = |id: ExtendedID| (u32::from(id) << EXID_SHIFT_32) | IDE_32;
+
+        Filter::new(
+            FilterMode::IdList,
+            FilterScale::Fs32Bit,
+            entry(ids[0]),
+            entry(ids[1]),
+        )
+    }
+
+    /// Only matches remote frames, list entries are changed from data
+    /// frames to remote frames
+    pub fn remote_only(mut self) -> Self {
+        match self.scale {
+            FilterScale::Fs32Bit => {
+                // sets the RTR mask bit in mask mode
+                self.fr1 |= RTR_32;
+                self.fr2 |= RTR_32;
+            }
+            FilterScale::Fs16Bit => {
+                let rtr = (RTR_16 << 16) | RTR_16;
+                self.fr1 |= rtr;
+                self.fr2 |= rtr;
+            }
+        }
+
+        self
+    }
+
+    /// Only matches data frames
+    pub fn data_only(mut self) -> Self {
+        if let (FilterMode::IdMask, FilterScale::Fs32Bit) = (self.mode, self.scale) {
+            self.fr1 &= !RTR_32;
+            self.fr2 |= RTR_32;
+        }
+
+        self
+    }
+
+    /// Assigns the matching frames to `fifo`, defaults to FIFO 0
+    pub fn fifo(mut self, fifo: RxFifo) -> Self {
+        self.fifo = fifo;
+        self
+    }
+
+    /// Returns the FiR1 and FiR2 register values
+    pub fn registers(&self) -> (u32, u32) {
+        (self.fr1, self.fr2)
+    }
+
+    /// Builds the filter configuration for the given filter bank,
+    /// `can2_start_bank` is the first bank owned by CAN2
+    pub fn to_config(&self, filter_number: u8, can2_start_bank: u8) -> CanFilterConfig {
+        // see CanFilterConfig for how the halves are written
+        let (id_high, id_low, mask_high, mask_low) = match self.scale {
+            FilterScale::Fs32Bit => (
+                self.fr1 >> 16,
+                self.fr1 & 0xFFFF,
+                self.fr2 >> 16,
+                self.fr2 & 0xFFFF,
+            ),
+            FilterScale::Fs16Bit => (
+                self.fr2 & 0xFFFF,
+                self.fr1 & 0xFFFF,
+                self.fr2 >> 16,
+                self.fr1 >> 16,
+            ),
+        };
+
+        CanFilterConfig {
+            filter_number,
+            bank_number: can2_start_bank,
+            fifo_assignment: self.fifo,
+            mode: self.mode,
+            scale: self.scale,
+            filter_id_high: id_high,
+            filter_id_low: id_low,
+            filter_mask_id_high: mask_high,
+            filter_mask_id_low: mask_low,
+            enabled: true,
+        }
+    }
+
+    fn new(mode: FilterMode, scale: FilterScale, fr1: u32, fr2: u32) -> Self {
+        Filter {
+            mode,
+            scale,
+            fifo: RxFifo::Fifo0,
+            fr1,
+            fr2,
+        }
+    }
+}
+
+/// Hands out filter bank numbers, banks below the CAN2 start bank belong
+/// to CAN1, the rest to CAN2
+pub struct FilterBanks {
+    can2_start: u8,
+    next_can1: u8,
+    next_can2: u8,
+}
+
+impl FilterBanks {
+    /// Splits the banks between CAN1 and CAN2 at `can2_start`
+    pub fn new(can2_start: u8) -> Result<Self, CanError> {
+        if can2_start > NUM_FILTER_BANKS {
+            return Err(CanError::ConfigurationFailed);
+        }
+
+        Ok(FilterBanks {
+            can2_start,
+            next_can1: 0,
+            next_can2: can2_start,
+        })
+    }
+
+    /// Returns the first bank owned by CAN2
+    pub fn can2_start(&self) -> u8 {
+        self.can2_start
+    }
+
+    /// Returns the number of banks CAN1 still has available
+    pub fn can1_available(&self) -> u8 {
+        self.can2_start - self.next_can1
+    }
+
+    /// Returns the number of banks CAN2 still has available
+    pub fn can2_available(&self) -> u8 {
+        NUM_FILTER_BANKS - self.next_can2
+    }
+
+    /// Assigns the next CAN1 bank to `filter`
+    pub fn allocate_can1(&mut self, filter: &Filter) -> Result<CanFilterConfig, CanError> {
+        if self.next_can1 >= self.can2_start {
+            return Err(CanError::ConfigurationFailed);
+        }
+
+        let config = filter.to_config(self.next_can1, self.can2_start);
+        self.next_can1 += 1;
+
+        Ok(config)
+    }
+
+    /// Assigns the next CAN2 bank to `filter`
+    pub fn allocate_can2(&mut self, filter: &Filter) -> Result<CanFilterConfig, CanError> {
+        if self.next_can2 >= NUM_FILTER_BANKS {
+            return Err(CanError::ConfigurationFailed);
+        }
+
+        let config = filter.to_config(self.next_can2, self.can2_start);
+        self.next_can2 += 1;
+
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // FiR1 and FiR2 as written by `Can::configure_filter()`
+    fn written(config: &CanFilterConfig) -> (u32, u32) {
+        match config.scale {
+            FilterScale::Fs32Bit => (
+                (config.filter_id_high << 16) | config.filter_id_low,
+                (config.filter_mask_id_high << 16) | config.filter_mask_id_low,
+            ),
+            FilterScale::Fs16Bit => (
+                (config.filter_mask_id_low << 16) | config.filter_id_low,
+                (config.filter_mask_id_high << 16) | config.filter_id_high,
+            ),
+        }
+    }
+
+    #[test]
+    fn standard_mask() {
+        let filter = Filter::standard_mask(BaseID::new(0x80), 0x7F0);
+
+        assert_eq!(filter.registers(), (0x1000_0000, 0xFE00_0004));
+        assert_eq!(written(&filter.to_config(0, 14)), filter.registers());
+    }
+
+    #[test]
+    fn extended_mask() {
+        let filter = Filter::extended_mask(ExtendedID::new(0x18FE_F100), 0x1FFF_FFFF);
+
+        assert_eq!(filter.registers(), (0xC7F7_8804, 0xFFFF_FFFC));
+        assert_eq!(written(&filter.to_config(0, 14)), filter.registers());
+    }
+
+    #[test]
+    fn standard_list() {
+        let ids = [
+            BaseID::new(0x100),
+            BaseID::new(0x101),
+            BaseID::new(0x102),
+            BaseID::new(0x103),
+        ];
+        let filter = Filter::standard_list(&ids);
+
+        assert_eq!(filter.registers(), (0x2020_2000, 0x2060_2040));
+
+        // the upper half of FiR1 goes through the mask low half
+        let config = filter.to_config(3, 14);
+        assert_eq!(config.filter_mask_id_low, 0x2020);
+        assert_eq!(written(&config), filter.registers());
+
+        let filter = filter.remote_only();
+        assert_eq!(filter.registers(), (0x2030_2010, 0x2070_2050));
+        assert_eq!(written(&filter.to_config(3, 14)), filter.registers());
+    }
+
+    #[test]
+    fn extended_list() {
+        let ids = [ExtendedID::new(0x1), ExtendedID::new(0x1FFF_FFFF)];
+        let filter = Filter::extended_list(&ids);
+
+        assert_eq!(filter.registers(), (0x0000_000C, 0xFFFF_FFFC));
+        assert_eq!(written(&filter.to_config(0, 14)), filter.registers());
+    }
+
+    #[test]
+    fn remote_and_data_only() {
+        let filter = Filter::standard_mask(BaseID::new(0x80), 0x7F0);
+
+        assert_eq!(filter.remote_only().registers(), (0x1000_0002, 0xFE00_0006));
+        assert_eq!(filter.data_only().registers(), (0x1000_0000, 0xFE00_0006));
+        assert_eq!(Filter::accept_all().registers(), (0, 0));
+    }
+
+    #[test]
+    fn filter_banks() {
+        assert!(FilterBanks::new(NUM_FILTER_BANKS + 1).is_err());
+
+        let mut banks = FilterBanks::new(2).unwrap();
+        let filter = Filter::accept_all().fifo(RxFifo::Fifo1);
+
+        assert_eq!(banks.allocate_can1(&filter).unwrap().filter_number, 0);
+        assert_eq!(banks.allocate_can1(&filter).unwrap().filter_number, 1);
+        assert!(banks.allocate_can1(&filter).is_err());
+        assert_eq!(banks.can1_available(), 0);
+
+        let config = banks.allocate_can2(&filter).unwrap();
+        assert_eq!(config.filter_number, 2);
+        assert_eq!(config.bank_number, 2);
+        assert_eq!(config.fifo_assignment, RxFifo::Fifo1);
+        assert_eq!(banks.can2_available(), NUM_FILTER_BANKS - 3);
+    }
+}
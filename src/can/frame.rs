@@ -0,0 +1,129 @@
+//! embedded-hal CAN frame bridge
+//!
+//! `CanFrame` comes from `embedded_types`, `HalFrame` wraps it so that it
+//! can implement `embedded_hal::can::Frame`.
+//!
+//! Example:
+//! let frame = HalFrame::new(StandardId::new(0x123).unwrap(), &[1, 2, 3]).unwrap();
+//! block!(can.transmit(&frame))?;
+//!
+//! let frame = block!(can.receive())?.into_inner();
+
+use hal::can::{self, ErrorKind, ExtendedId, Id, StandardId};
+
+use super::{BaseID, CanError, CanFrame, DataFrame, ExtendedID, RemoteFrame, ID};
+
+/// `CanFrame` implementing `embedded_hal::can::Frame`
+pub struct HalFrame {
+    frame: CanFrame,
+    // requested length of remote frames, CanFrame doesn't carry it
+    dlc: u8,
+}
+
+impl HalFrame {
+    /// Returns the wrapped frame
+    pub fn as_frame(&self) -> &CanFrame {
+        &self.frame
+    }
+
+    /// Consumes the wrapper, returning the frame
+    pub fn into_inner(self) -> CanFrame {
+        self.frame
+    }
+
+    // `dlc` is only kept for remote frames
+    pub(crate) fn with_dlc(frame: CanFrame, dlc: u8) -> Self {
+        HalFrame { frame, dlc }
+    }
+
+    // DLC to transmit a remote frame with
+    pub(crate) fn remote_dlc(&self) -> u8 {
+        self.dlc
+    }
+}
+
+impl From<CanFrame> for HalFrame {
+    fn from(frame: CanFrame) -> Self {
+        HalFrame { frame, dlc: 0 }
+    }
+}
+
+impl can::Frame for HalFrame {
+    fn new(id: impl Into<Id>, data: &[u8]) -> Option<Self> {
+        if data.len() > 8 {
+            return None;
+        }
+
+        let mut frame = DataFrame::new(from_hal_id(id.into()));
+        frame.set_data_length(data.len());
+        frame.data_as_mut().copy_from_slice(data);
+
+        Some(HalFrame::from(CanFrame::from(frame)))
+    }
+
+    fn new_remote(id: impl Into<Id>, dlc: usize) -> Option<Self> {
+        if dlc > 8 {
+            return None;
+        }
+
+        Some(HalFrame {
+            frame: CanFrame::from(RemoteFrame::new(from_hal_id(id.into()))),
+            dlc: dlc as u8,
+        })
+    }
+
+    fn is_extended(&self) -> bool {
+        match self.frame.id() {
+            ID::ExtendedID(_) => true,
+            ID::BaseID(_) => false,
+        }
+    }
+
+    fn is_remote_frame(&self) -> bool {
+        match self.frame {
+            CanFrame::RemoteFrame(_) => true,
+            CanFrame::DataFrame(_) => false,
+        }
+    }
+
+    fn id(&self) -> Id {
+        to_hal_id(self.frame.id())
+    }
+
+    fn dlc(&self) -> usize {
+        match self.frame {
+            CanFrame::DataFrame(ref f) => f.data().len(),
+            CanFrame::RemoteFrame(_) => self.dlc as usize,
+        }
+    }
+
+    fn data(&self) -> &[u8] {
+        match self.frame {
+            CanFrame::DataFrame(ref f) => f.data(),
+            CanFrame::RemoteFrame(_) => &[],
+        }
+    }
+}
+
+impl can::Error for CanError {
+    fn kind(&self) -> ErrorKind {
+        ErrorKind::Other
+    }
+}
+
+/// Converts an `embedded_types` ID into an `embedded_hal` ID
+pub fn to_hal_id(id: ID) -> Id {
+    // NOTE(unsafe) BaseID and ExtendedID enforce the same ranges
+    match id {
+        ID::BaseID(id) => Id::Standard(unsafe { StandardId::new_unchecked(u16::from(id)) }),
+        ID::ExtendedID(id) => Id::Extended(unsafe { ExtendedId::new_unchecked(u32::from(id)) }),
+    }
+}
+
+/// Converts an `embedded_hal` ID into an `embedded_types` ID
+pub fn from_hal_id(id: Id) -> ID {
+    match id {
+        Id::Standard(id) => ID::BaseID(BaseID::new(id.as_raw())),
+        Id::Extended(id) => ID::ExtendedID(ExtendedID::new(id.as_raw())),
+    }
+}
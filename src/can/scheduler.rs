@@ -0,0 +1,231 @@
+//! Periodic frame scheduler
+//!
+//! Each entry transmits a shared frame every period, starting at its
+//! offset. The frame can be updated from any context, updates are done in
+//! a critical section so a frame is never transmitted half written.
+//!
+//! A deadline is missed when the frame could not be transmitted before its
+//! next period started, the late transmission is then skipped.
+//!
+//! Example:
+//! static BRAKE_REPORT: SharedFrame = SharedFrame::new();
+//! static STEERING_REPORT: SharedFrame = SharedFrame::new();
+//!
+//! let mut entries = [
+//!     ScheduleEntry::new(&BRAKE_REPORT, 10, 0),
+//!     ScheduleEntry::new(&STEERING_REPORT, 20, 0),
+//! ];
+//! let mut scheduler = CanScheduler::new(&mut entries);
+//! scheduler.stagger();
+//!
+//! // any context
+//! BRAKE_REPORT.set(Some(frame));
+//!
+//! // 1 ms timer interrupt
+//! scheduler.tick(&can, 1);
+
+use core::cell::Cell;
+use core::cmp;
+
+use cortex_m::interrupt::{self, Mutex};
+use nb;
+
+use super::{CanFrame, DataFrame, HalFrame, Transmitter};
+use time::{Instant, MonoTimer};
+
+/// Frame shared between the scheduler and the contexts updating it
+pub struct SharedFrame {
+    frame: Mutex<Cell<Option<DataFrame>>>,
+}
+
+impl SharedFrame {
+    /// Creates an empty frame, nothing is transmitted until it is set
+    pub const fn new() -> Self {
+        SharedFrame {
+            frame: Mutex::new(Cell::new(None)),
+        }
+    }
+
+    /// Replaces the frame, `None` suspends its transmission
+    pub fn set(&self, frame: Option<DataFrame>) {
+        interrupt::free(|cs| self.frame.borrow(cs).set(frame));
+    }
+
+    pub fn get(&self) -> Option<DataFrame> {
+        interrupt::free(|cs| self.frame.borrow(cs).get())
+    }
+}
+
+/// A scheduled frame, periods and offsets are in milliseconds
+pub struct ScheduleEntry<'a> {
+    frame: &'a SharedFrame,
+    period: u32,
+    offset: u32,
+    next: u32,
+    missed: u32,
+}
+
+impl<'a> ScheduleEntry<'a> {
+    /// Transmits `frame` every `period_ms`, `offset_ms` after the
+    /// scheduler start, a zero period is treated as 1 ms
+    pub fn new(frame: &'a SharedFrame, period_ms: u32, offset_ms: u32) -> Self {
+        ScheduleEntry {
+            frame,
+            period: cmp::max(period_ms, 1),
+            offset: offset_ms,
+            next: offset_ms,
+            missed: 0,
+        }
+    }
+
+    pub fn period(&self) -> u32 {
+        self.period
+    }
+
+    pub fn offset(&self) -> u32 {
+        self.offset
+    }
+
+    /// Returns the number of missed deadlines
+    pub fn missed(&self) -> u32 {
+        self.missed
+    }
+}
+
+/// Transmits a table of periodic frames
+pub struct CanScheduler<'a, 'b: 'a> {
+    entries: &'a mut [ScheduleEntry<'b>],
+    // milliseconds since the start
+    now: u32,
+    // MonoTimer time base, cycles not yet accounted for in `now`
+    last: Option<Instant>,
+    remainder: u32,
+}
+
+impl<'a, 'b> CanScheduler<'a, 'b> {
+    pub fn new(entries: &'a mut [ScheduleEntry<'b>]) -> Self {
+        CanScheduler {
+            entries,
+            now: 0,
+            last: None,
+            remainder: 0,
+        }
+    }
+
+    /// Spreads the offsets evenly over the shortest period so that frames
+    /// with common periods are not transmitted back to back
+    pub fn stagger(&mut self) {
+        let count = self.entries.len() as u32;
+        let shortest = match self.entries.iter().map(|e| e.period).min() {
+            Some(period) => period,
+            None => return,
+        };
+
+        for (index, entry) in self.entries.iter_mut().enumerate() {
+            entry.offset = ((index as u32) * shortest / count) % entry.period;
+            entry.next = self.now.wrapping_add(entry.offset);
+        }
+    }
+
+    /// Advances the time by `elapsed_ms` and transmits the frames due,
+    /// meant to be called from a periodic `Timer` interrupt.
+    ///
+    /// Returns the number of frames transmitted.
+    pub fn tick<T>(&mut self, can: &T, elapsed_ms: u32) -> usize
+    where
+        T: Transmitter,
+    {
+        self.now = self.now.wrapping_add(elapsed_ms);
+        self.process(can)
+    }
+
+    /// Advances the time using a `MonoTimer` and transmits the frames due,
+    /// must be called at least once per timer wrap around.
+    ///
+    /// Returns the number of frames transmitted.
+    pub fn poll<T>(&mut self, can: &T, timer: &MonoTimer) -> usize
+    where
+        T: Transmitter,
+    {
+        let now = timer.now();
+
+        if let Some(last) = self.last {
+            let cycles_per_ms = u64::from(timer.frequency().0 / 1000);
+            // in 64 bits, a full timer period plus the remainder overflows
+            let cycles =
+                u64::from(now.ticks().wrapping_sub(last.ticks())) + u64::from(self.remainder);
+
+            self.now = self.now.wrapping_add((cycles / cycles_per_ms) as u32);
+            self.remainder = (cycles % cycles_per_ms) as u32;
+        }
+
+        self.last = Some(now);
+        self.process(can)
+    }
+
+    /// Returns the time since the start, in milliseconds
+    pub fn now(&self) -> u32 {
+        self.now
+    }
+
+    pub fn entries(&self) -> &[ScheduleEntry<'b>] {
+        self.entries
+    }
+
+    /// Returns the total number of missed deadlines
+    pub fn missed(&self) -> u32 {
+        self.entries
+            .iter()
+            .fold(0, |missed, e| missed.wrapping_add(e.missed))
+    }
+
+    fn process<T>(&mut self, can: &T) -> usize
+    where
+        T: Transmitter,
+    {
+        let now = self.now;
+        let mut sent = 0;
+
+        for entry in self.entries.iter_mut() {
+            // wrap around aware `now >= next`
+            let late = now.wrapping_sub(entry.next);
+            if (late as i32) < 0 {
+                continue;
+            }
+
+            let frame = match entry.frame.get() {
+                Some(frame) => frame,
+                None => {
+                    // suspended, move to the next deadline without a miss
+                    entry.next = entry
+                        .next
+                        .wrapping_add(((late / entry.period) + 1) * entry.period);
+                    continue;
+                }
+            };
+
+            // skip the periods already over
+            if late >= entry.period {
+                let skipped = late / entry.period;
+
+                entry.missed = entry.missed.wrapping_add(skipped);
+                entry.next = entry.next.wrapping_add(skipped * entry.period);
+            }
+
+            match can.try_transmit(&HalFrame::from(CanFrame::from(frame))) {
+                Ok(_) => {
+                    entry.next = entry.next.wrapping_add(entry.period);
+                    sent += 1;
+                }
+                // mailboxes full, retried on the next call
+                Err(nb::Error::WouldBlock) => (),
+                Err(nb::Error::Other(_)) => {
+                    entry.missed = entry.missed.wrapping_add(1);
+                    entry.next = entry.next.wrapping_add(entry.period);
+                }
+            }
+        }
+
+        sent
+    }
+}
@@ -0,0 +1,89 @@
+//! Loopback self test report and frame pattern
+//!
+//! Example:
+//! let report = can.self_test()?;
+//! if !report.passed() {
+//!     // keep the actuators disabled
+//! }
+
+use super::{BaseID, CanFrame, DataFrame, ErrorStatus, ExtendedID, RemoteFrame, ID};
+
+/// Number of frames transmitted by `Can::self_test()`
+pub const SELF_TEST_FRAMES: usize = 8;
+
+/// Outcome of a self test frame
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SelfTestResult {
+    Passed,
+    /// No mailbox was available or the transmission did not complete
+    TransmitFailed,
+    /// The frame was not received back, check the filters
+    NotReceived,
+    /// A different or invalid frame was received
+    Corrupted,
+}
+
+/// Result of `Can::self_test()`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SelfTestReport {
+    /// Per frame results, in the order of the pattern
+    pub results: [SelfTestResult; SELF_TEST_FRAMES],
+    /// Error status at the end of the test
+    pub error_status: ErrorStatus,
+}
+
+impl SelfTestReport {
+    /// Returns true if every frame was received back unchanged
+    pub fn passed(&self) -> bool {
+        self.results.iter().all(|r| *r == SelfTestResult::Passed)
+    }
+
+    /// Returns the number of frames that failed
+    pub fn failures(&self) -> usize {
+        self.results
+            .iter()
+            .filter(|r| **r != SelfTestResult::Passed)
+            .count()
+    }
+}
+
+/// Returns the frame at `index` of the self test pattern, standard and
+/// extended IDs with all bits set, cleared and alternating, remote frames
+/// and every data length class
+pub fn pattern_frame(index: usize) -> CanFrame {
+    let data_frame = |id: ID, len: usize, byte: u8| {
+        let mut frame = DataFrame::new(id);
+        frame.set_data_length(len);
+
+        for (i, b) in frame.data_as_mut().iter_mut().enumerate() {
+            // alternate the pattern between bytes
+            *b = if (i % 2) == 0 { byte } else { !byte };
+        }
+
+        CanFrame::from(frame)
+    };
+
+    match index % SELF_TEST_FRAMES {
+        0 => data_frame(ID::BaseID(BaseID::new(0x7FF)), 8, 0xFF),
+        1 => data_frame(ID::BaseID(BaseID::new(0x000)), 8, 0x00),
+        2 => data_frame(ID::BaseID(BaseID::new(0x555)), 4, 0x55),
+        3 => data_frame(ID::BaseID(BaseID::new(0x2AA)), 0, 0),
+        4 => data_frame(ID::ExtendedID(ExtendedID::new(0x1FFF_FFFF)), 8, 0xAA),
+        5 => data_frame(ID::ExtendedID(ExtendedID::new(0x0AAA_AAAA)), 1, 0x0F),
+        6 => CanFrame::from(RemoteFrame::new(ID::BaseID(BaseID::new(0x123)))),
+        _ => CanFrame::from(RemoteFrame::new(ID::ExtendedID(ExtendedID::new(
+            0x1234_5678,
+        )))),
+    }
+}
+
+/// Returns true if both frames have the same type, ID and data
+pub fn frames_match(a: &CanFrame, b: &CanFrame) -> bool {
+    match (a, b) {
+        (&CanFrame::DataFrame(ref a), &CanFrame::DataFrame(ref b)) => {
+            (a.id() == b.id()) && (a.data() == b.data())
+        }
+        (&CanFrame::RemoteFrame(ref a), &CanFrame::RemoteFrame(ref b)) => a.id() == b.id(),
+        _ => false,
+    }
+}
@@ -0,0 +1,343 @@
+//! Bus load and traffic statistics
+//!
+//! `CanStats` is fed the frames seen by the receive and transmit paths, the
+//! transmit statuses and the error status, either through
+//! `Can::receive_frame_with_stats()` and `Can::collect_tx_status()` or
+//! with the `record_*()` methods. Frame lengths include the worst case
+//! number of stuff bits, so the bus load is an upper bound.
+//!
+//! Example:
+//! let mut ranges = [IdRange::standard(0x70, 0x9F), IdRange::standard(0x7E8, 0x7EF)];
+//! let mut stats = CanStats::new(&mut ranges, &config.bit_timing, &clocks, timer, 1000);
+//!
+//! // FIFO message pending interrupt
+//! while let Ok(received) = can.receive_frame_with_stats(&RxFifo::Fifo0, &mut stats) {
+//!     ...
+//! }
+//!
+//! // transmit mailbox empty interrupt
+//! can.collect_tx_status(&TxMailbox::Mailbox0, &mut stats);
+//! stats.record_error_status(&can.error_state());
+//!
+//! if let Some(load) = stats.update() {
+//!     // percent
+//! }
+
+use core::cmp;
+
+use super::{CanBitTiming, CanFrame, ErrorStatus, LastErrorCode, TxStatus, ID};
+use rcc::Clocks;
+use time::{Instant, MonoTimer};
+
+// SOF, ID, RTR, IDE, r0, DLC, CRC, CRC delimiter, ACK, EOF and IFS
+const STANDARD_FRAME_BITS: u32 = 47;
+// adds SRR, IDE and the 18 bit ID extension, r1 instead of IDE
+const EXTENDED_FRAME_BITS: u32 = 67;
+// bits after the CRC that are not subject to stuffing
+const UNSTUFFED_BITS: u32 = 13;
+
+/// Frame counter for a range of IDs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IdRange {
+    first: u32,
+    last: u32,
+    extended: bool,
+    frames: u32,
+}
+
+impl IdRange {
+    /// Standard IDs `first` to `last`, inclusive
+    pub fn standard(first: u16, last: u16) -> Self {
+        IdRange {
+            first: u32::from(first),
+            last: u32::from(last),
+            extended: false,
+            frames: 0,
+        }
+    }
+
+    /// Extended IDs `first` to `last`, inclusive
+    pub fn extended(first: u32, last: u32) -> Self {
+        IdRange {
+            first,
+            last,
+            extended: true,
+            frames: 0,
+        }
+    }
+
+    /// Returns the number of frames received or transmitted in the range
+    pub fn frames(&self) -> u32 {
+        self.frames
+    }
+
+    fn contains(&self, id: ID) -> bool {
+        let (extended, id) = match id {
+            ID::BaseID(id) => (false, u32::from(u16::from(id))),
+            ID::ExtendedID(id) => (true, u32::from(id)),
+        };
+
+        (extended == self.extended) && (id >= self.first) && (id <= self.last)
+    }
+}
+
+/// Traffic counters and bus load
+pub struct CanStats<'a> {
+    ranges: &'a mut [IdRange],
+    bitrate: u32,
+    timer: MonoTimer,
+    window: u32,
+    window_start: Instant,
+    window_bits: u32,
+    bus_load: f32,
+    rx_frames: u32,
+    tx_frames: u32,
+    errors: u32,
+    overruns: u32,
+    arbitration_lost: u32,
+    last_error_code: LastErrorCode,
+}
+
+impl<'a> CanStats<'a> {
+    /// Creates the collector, the bus load is computed over windows of
+    /// `window_ms`, limited to the wrap around period of the timer
+    pub fn new(
+        ranges: &'a mut [IdRange],
+        bit_timing: &CanBitTiming,
+        clocks: &Clocks,
+        timer: MonoTimer,
+        window_ms: u32,
+    ) -> Self {
+        CanStats {
+            ranges,
+            bitrate: bit_timing.bitrate(clocks).0,
+            timer,
+            window: window_ticks(timer.frequency().0, window_ms),
+            window_start: timer.now(),
+            window_bits: 0,
+            bus_load: 0.0,
+            rx_frames: 0,
+            tx_frames: 0,
+            errors: 0,
+            overruns: 0,
+            arbitration_lost: 0,
+            last_error_code: LastErrorCode::NoError,
+        }
+    }
+
+    /// Counts a received frame
+    pub fn record_rx(&mut self, frame: &CanFrame) {
+        self.rx_frames = self.rx_frames.wrapping_add(1);
+        self.record_frame(frame);
+    }
+
+    /// Counts a transmitted frame
+    pub fn record_tx(&mut self, frame: &CanFrame) {
+        self.tx_frames = self.tx_frames.wrapping_add(1);
+        self.record_frame(frame);
+    }
+
+    /// Counts the outcome of a transmission, `frame` is counted as
+    /// transmitted on success
+    pub fn record_tx_status(&mut self, frame: &CanFrame, status: TxStatus) {
+        match status {
+            TxStatus::Success => self.record_tx(frame),
+            TxStatus::ArbitrationLost => {
+                self.arbitration_lost = self.arbitration_lost.wrapping_add(1)
+            }
+            TxStatus::Error => self.errors = self.errors.wrapping_add(1),
+            _ => (),
+        }
+    }
+
+    /// Counts a bus error when the last error code changes, see
+    /// `Can::clear_last_error_code()` to count repeated codes
+    pub fn record_error_status(&mut self, status: &ErrorStatus) {
+        if (status.last_error_code != self.last_error_code)
+            && (status.last_error_code != LastErrorCode::NoError)
+            && (status.last_error_code != LastErrorCode::SetBySoftware)
+        {
+            self.errors = self.errors.wrapping_add(1);
+        }
+
+        self.last_error_code = status.last_error_code;
+    }
+
+    /// Counts frames lost to FIFO overruns
+    pub fn record_overruns(&mut self, count: u32) {
+        self.overruns = self.overruns.wrapping_add(count);
+    }
+
+    /// Closes the measurement window once it has elapsed.
+    ///
+    /// Returns the bus load in percent of the window.
+    pub fn update(&mut self) -> Option<f32> {
+        let elapsed = self.window_start.elapsed();
+
+        if elapsed < self.window {
+            return None;
+        }
+
+        self.bus_load = bus_load(
+            self.window_bits,
+            self.bitrate,
+            elapsed,
+            self.timer.frequency().0,
+        );
+        self.window_bits = 0;
+        self.window_start = self.timer.now();
+
+        Some(self.bus_load)
+    }
+
+    /// Returns the bus load of the last complete window, in percent
+    pub fn bus_load(&self) -> f32 {
+        self.bus_load
+    }
+
+    pub fn rx_frames(&self) -> u32 {
+        self.rx_frames
+    }
+
+    pub fn tx_frames(&self) -> u32 {
+        self.tx_frames
+    }
+
+    /// Returns the number of bus and transmission errors
+    pub fn errors(&self) -> u32 {
+        self.errors
+    }
+
+    pub fn overruns(&self) -> u32 {
+        self.overruns
+    }
+
+    pub fn arbitration_lost(&self) -> u32 {
+        self.arbitration_lost
+    }
+
+    /// Returns the per ID range counters
+    pub fn ranges(&self) -> &[IdRange] {
+        self.ranges
+    }
+
+    /// Clears the counters and starts a new window
+    pub fn reset(&mut self) {
+        for range in self.ranges.iter_mut() {
+            range.frames = 0;
+        }
+
+        self.rx_frames = 0;
+        self.tx_frames = 0;
+        self.errors = 0;
+        self.overruns = 0;
+        self.arbitration_lost = 0;
+        self.window_bits = 0;
+        self.window_start = self.timer.now();
+    }
+
+    fn record_frame(&mut self, frame: &CanFrame) {
+        let id = frame.id();
+
+        for range in self.ranges.iter_mut() {
+            if range.contains(id) {
+                range.frames = range.frames.wrapping_add(1);
+            }
+        }
+
+        self.window_bits = self.window_bits.saturating_add(frame_bits(frame));
+    }
+}
+
+/// Returns the length of a frame on the bus in bits, including the worst
+/// case number of stuff bits
+pub fn frame_bits(frame: &CanFrame) -> u32 {
+    let data_bits = match *frame {
+        CanFrame::DataFrame(ref f) => 8 * f.data().len() as u32,
+        CanFrame::RemoteFrame(_) => 0,
+    };
+
+    let bits = match frame.id() {
+        ID::BaseID(_) => STANDARD_FRAME_BITS,
+        ID::ExtendedID(_) => EXTENDED_FRAME_BITS,
+    } + data_bits;
+
+    // one stuff bit after every 4 bits past the first 5 of the stuffed part
+    let stuffed = bits - UNSTUFFED_BITS;
+
+    bits + ((stuffed - 1) / 4)
+}
+
+// Window length in timer ticks, saturated to the timer range
+fn window_ticks(frequency: u32, window_ms: u32) -> u32 {
+    let window = (u64::from(frequency) * u64::from(window_ms)) / 1000;
+
+    cmp::min(window, u64::from(u32::max_value())) as u32
+}
+
+// Percentage of `elapsed` ticks the bus was busy transmitting `bits`
+fn bus_load(bits: u32, bitrate: u32, elapsed: u32, frequency: u32) -> f32 {
+    // bits / (bitrate * seconds)
+    let bus_time = (bitrate as f32) * (elapsed as f32) / (frequency as f32);
+
+    (bits as f32) * 100.0 / bus_time
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{BaseID, DataFrame, ExtendedID, RemoteFrame};
+    use super::*;
+
+    fn data_frame(id: ID, len: usize) -> CanFrame {
+        let mut frame = DataFrame::new(id);
+        frame.set_data_length(len);
+
+        CanFrame::from(frame)
+    }
+
+    #[test]
+    fn standard_frame_bits() {
+        let id = ID::BaseID(BaseID::new(0x123));
+
+        assert_eq!(frame_bits(&data_frame(id, 0)), 47 + 8);
+        assert_eq!(frame_bits(&data_frame(id, 8)), 111 + 24);
+        assert_eq!(frame_bits(&CanFrame::from(RemoteFrame::new(id))), 47 + 8);
+    }
+
+    #[test]
+    fn extended_frame_bits() {
+        let id = ID::ExtendedID(ExtendedID::new(0x1234_5678));
+
+        assert_eq!(frame_bits(&data_frame(id, 0)), 67 + 13);
+        assert_eq!(frame_bits(&data_frame(id, 8)), 131 + 29);
+        assert_eq!(frame_bits(&CanFrame::from(RemoteFrame::new(id))), 67 + 13);
+    }
+
+    #[test]
+    fn window_length() {
+        assert_eq!(window_ticks(216_000_000, 1000), 216_000_000);
+        assert_eq!(window_ticks(216_000_000, 19_000), 4_104_000_000);
+        assert_eq!(window_ticks(1000, 0), 0);
+    }
+
+    #[test]
+    fn saturated_window() {
+        // past the 19.9 s timer wrap around at 216 MHz
+        assert_eq!(window_ticks(216_000_000, 20_000), u32::max_value());
+        assert_eq!(
+            window_ticks(216_000_000, u32::max_value()),
+            u32::max_value()
+        );
+    }
+
+    #[test]
+    fn load() {
+        // 250 bits in 1 ms at 500 kbit/s
+        assert_eq!(bus_load(250, 500_000, 216_000, 216_000_000), 50.0);
+        assert_eq!(bus_load(0, 500_000, 216_000, 216_000_000), 0.0);
+
+        // a saturated window
+        let load = bus_load(4_000_000, 1_000_000, u32::max_value(), 216_000_000);
+        assert!((load - 20.1).abs() < 0.1);
+    }
+}
@@ -0,0 +1,196 @@
+//! Time triggered communication mode scheduling
+//!
+//! With `CanConfig.ttcm` set the controller runs a 16 bit counter
+//! incremented every bit time and captures it at the start of every
+//! received and transmitted frame. The counter can't be read directly,
+//! `BitTimeClock` extrapolates it from the last captured value.
+//!
+//! Periods and offsets are in bit times and must be below half the
+//! counter range, 32768 bit times.
+//!
+//! Example:
+//! let mut slots = [TimeSlot::new(5000, 0), TimeSlot::new(5000, 2500)];
+//! let mut schedule = TimeTriggeredSchedule::new(&mut slots);
+//! let mut clock = BitTimeClock::new(&config.bit_timing, &clocks, timer);
+//!
+//! // FIFO message pending interrupt, reference frame from the time master
+//! let received = can.receive_frame(&RxFifo::Fifo0)?;
+//! clock.sync(received.timestamp, frame_bits(&received.frame));
+//! schedule.set_reference(received.timestamp);
+//!
+//! // main loop
+//! while let Some(slot) = schedule.poll(clock.now()) {
+//!     can.transmit_with_timestamp(&frames[slot])?;
+//! }
+
+use super::CanBitTiming;
+use rcc::Clocks;
+use time::{Instant, MonoTimer};
+
+/// Extrapolated value of the bit-time counter
+pub struct BitTimeClock {
+    timer: MonoTimer,
+    bitrate: u32,
+    timestamp: u16,
+    instant: Instant,
+}
+
+impl BitTimeClock {
+    pub fn new(bit_timing: &CanBitTiming, clocks: &Clocks, timer: MonoTimer) -> Self {
+        BitTimeClock {
+            timer,
+            bitrate: bit_timing.bitrate(clocks).0,
+            timestamp: 0,
+            instant: timer.now(),
+        }
+    }
+
+    /// Synchronizes to a captured counter value, `bits_since` is the
+    /// number of bit times elapsed since it was captured, e.g. the frame
+    /// length when called right after the frame was received
+    pub fn sync(&mut self, timestamp: u16, bits_since: u32) {
+        self.timestamp = timestamp.wrapping_add(bits_since as u16);
+        self.instant = self.timer.now();
+    }
+
+    /// Returns the current counter value
+    pub fn now(&self) -> u16 {
+        let elapsed = u64::from(self.instant.elapsed());
+        let bits = (elapsed * u64::from(self.bitrate)) / u64::from(self.timer.frequency().0);
+
+        self.timestamp.wrapping_add(bits as u16)
+    }
+}
+
+/// A periodic transmission slot
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimeSlot {
+    period: u16,
+    offset: u16,
+    next: u16,
+}
+
+impl TimeSlot {
+    /// Slot due every `period` bit times, `offset` bit times after the
+    /// reference
+    ///
+    /// # Panics
+    ///
+    /// If `period` is zero.
+    pub fn new(period: u16, offset: u16) -> Self {
+        assert!(period != 0);
+
+        TimeSlot {
+            period,
+            offset,
+            next: offset,
+        }
+    }
+
+    /// Returns the counter value the slot is next due at
+    pub fn next(&self) -> u16 {
+        self.next
+    }
+}
+
+/// Periodic slots on the bit-time counter
+pub struct TimeTriggeredSchedule<'a> {
+    slots: &'a mut [TimeSlot],
+    synchronized: bool,
+}
+
+impl<'a> TimeTriggeredSchedule<'a> {
+    pub fn new(slots: &'a mut [TimeSlot]) -> Self {
+        TimeTriggeredSchedule {
+            slots,
+            synchronized: false,
+        }
+    }
+
+    /// Aligns the slots to a reference counter value, e.g. the time stamp
+    /// of a reference frame, slots aren't due before the first reference
+    pub fn set_reference(&mut self, reference: u16) {
+        for slot in self.slots.iter_mut() {
+            slot.next = reference.wrapping_add(slot.offset);
+        }
+
+        self.synchronized = true;
+    }
+
+    /// Returns the index of a slot due at `now` and moves it to its next
+    /// period, call until `None` is returned.
+    ///
+    /// Periods missed entirely are skipped.
+    pub fn poll(&mut self, now: u16) -> Option<usize> {
+        if !self.synchronized {
+            return None;
+        }
+
+        for (index, slot) in self.slots.iter_mut().enumerate() {
+            if is_due(slot.next, now) {
+                // move past `now`, skipping missed periods
+                while is_due(slot.next, now) {
+                    slot.next = slot.next.wrapping_add(slot.period);
+                }
+
+                return Some(index);
+            }
+        }
+
+        None
+    }
+
+    pub fn slots(&self) -> &[TimeSlot] {
+        self.slots
+    }
+}
+
+// Wrap around aware `now >= time`
+fn is_due(time: u16, now: u16) -> bool {
+    (now.wrapping_sub(time) as i16) >= 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn due_once_per_period() {
+        let mut slots = [TimeSlot::new(100, 10)];
+        let mut schedule = TimeTriggeredSchedule::new(&mut slots);
+
+        assert_eq!(schedule.poll(10), None);
+
+        schedule.set_reference(0);
+        assert_eq!(schedule.poll(9), None);
+        assert_eq!(schedule.poll(10), Some(0));
+        assert_eq!(schedule.poll(10), None);
+        assert_eq!(schedule.slots()[0].next(), 110);
+    }
+
+    #[test]
+    fn missed_periods_are_skipped() {
+        let mut slots = [TimeSlot::new(100, 0)];
+        let mut schedule = TimeTriggeredSchedule::new(&mut slots);
+
+        schedule.set_reference(65500);
+        assert_eq!(schedule.poll(200), Some(0));
+        assert_eq!(schedule.poll(200), None);
+        assert_eq!(schedule.slots()[0].next(), 264);
+
+        // due exactly at the next period
+        let mut slots = [TimeSlot::new(100, 0)];
+        let mut schedule = TimeTriggeredSchedule::new(&mut slots);
+
+        schedule.set_reference(0);
+        assert_eq!(schedule.poll(100), Some(0));
+        assert_eq!(schedule.poll(100), None);
+        assert_eq!(schedule.slots()[0].next(), 200);
+    }
+
+    #[test]
+    #[should_panic]
+    fn zero_period() {
+        TimeSlot::new(0, 0);
+    }
+}
@@ -0,0 +1,446 @@
+//! Software transmit queue
+//!
+//! Frames are kept sorted by arbitration priority and handed to the
+//! hardware mailboxes as they become empty, `process()` is meant to be
+//! called after enqueueing and from the transmit mailbox empty interrupt.
+//!
+//! Frames are kept as `HalFrame` so that remote frames keep their DLC.
+//!
+//! The queue is not split into halves like `spsc`, enqueueing from thread
+//! mode while the interrupt processes it needs both to go through a
+//! critical section, e.g. a `cortex_m::interrupt::Mutex<RefCell<_>>`.
+//!
+//! Example:
+//! static mut STORAGE: [Option<HalFrame>; 8] = [None, None, ...];
+//! static QUEUE: Mutex<RefCell<Option<CanTxQueue<'static>>>> = Mutex::new(RefCell::new(None));
+//!
+//! let queue = CanTxQueue::new(unsafe { &mut STORAGE });
+//! interrupt::free(|cs| *QUEUE.borrow(cs).borrow_mut() = Some(queue));
+//! can.listen(Event::TransmitMailboxEmpty);
+//!
+//! // thread mode
+//! interrupt::free(|cs| {
+//!     if let Some(ref mut queue) = *QUEUE.borrow(cs).borrow_mut() {
+//!         queue.enqueue(HalFrame::from(frame)).ok();
+//!         queue.process(&can);
+//!     }
+//! });
+//!
+//! // transmit mailbox empty interrupt, same critical section
+//! interrupt::free(|cs| {
+//!     if let Some(ref mut queue) = *QUEUE.borrow(cs).borrow_mut() {
+//!         queue.process(&can);
+//!     }
+//! });
+
+use nb;
+
+use super::{arbitration_priority, HalFrame, Transmitter, TxMailbox, TxStatus};
+
+const MAILBOXES: [TxMailbox; 3] = [
+    TxMailbox::Mailbox0,
+    TxMailbox::Mailbox1,
+    TxMailbox::Mailbox2,
+];
+
+/// Priority ordered transmit queue, shared between contexts through a
+/// critical section, see the module documentation
+pub struct CanTxQueue<'a> {
+    // sorted by priority, highest priority first
+    storage: &'a mut [Option<HalFrame>],
+    len: usize,
+    // frames owned by the hardware mailboxes
+    in_flight: [Option<HalFrame>; 3],
+    aborting: [bool; 3],
+    failed: u32,
+}
+
+impl<'a> CanTxQueue<'a> {
+    /// Creates an empty queue, the capacity is the length of `storage`
+    pub fn new(storage: &'a mut [Option<HalFrame>]) -> Self {
+        for slot in storage.iter_mut() {
+            *slot = None;
+        }
+
+        CanTxQueue {
+            storage,
+            len: 0,
+            in_flight: [None, None, None],
+            aborting: [false; 3],
+            failed: 0,
+        }
+    }
+
+    /// Returns the maximum number of queued frames
+    pub fn capacity(&self) -> usize {
+        self.storage.len()
+    }
+
+    /// Returns the number of frames waiting for a mailbox
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns true if no frames are waiting for a mailbox
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns true if no frames are queued or owned by a mailbox
+    pub fn is_idle(&self) -> bool {
+        self.is_empty() && self.in_flight.iter().all(|f| f.is_none())
+    }
+
+    /// Returns the number of frames whose transmission failed, only
+    /// possible with non-automatic retransmission, or that were rejected
+    /// by the interface
+    pub fn failed(&self) -> u32 {
+        self.failed
+    }
+
+    /// Adds a frame to the queue, frames of equal priority keep their
+    /// order.
+    ///
+    /// Returns the frame back if the queue is full.
+    pub fn enqueue(&mut self, frame: HalFrame) -> Result<(), HalFrame> {
+        if self.len == self.storage.len() {
+            return Err(frame);
+        }
+
+        let priority = arbitration_priority(frame.as_frame());
+        let mut index = self.len;
+
+        while (index > 0) && (self.priority_at(index - 1) > priority) {
+            self.storage[index] = self.storage[index - 1].take();
+            index -= 1;
+        }
+
+        self.storage[index] = Some(frame);
+        self.len += 1;
+
+        Ok(())
+    }
+
+    /// Collects completed mailboxes, refills the empty ones and aborts
+    /// a lower priority transmission when the highest priority frame
+    /// would otherwise wait behind it.
+    ///
+    /// Aborted frames are put back in the queue. Mailboxes are not refilled
+    /// while an abort is outstanding, a new request clears the status of
+    /// the aborted mailbox before it could be collected.
+    pub fn process<T>(&mut self, can: &T)
+    where
+        T: Transmitter,
+    {
+        for (index, mailbox) in MAILBOXES.iter().enumerate() {
+            if self.in_flight[index].is_none() {
+                continue;
+            }
+
+            match (can.tx_status(mailbox), self.aborting[index]) {
+                (TxStatus::Pending, _) => continue,
+                // an aborted frame may still have been transmitted
+                (TxStatus::Success, _) | (TxStatus::Idle, false) => {
+                    self.in_flight[index] = None;
+                }
+                (_, true) | (TxStatus::Aborted, false) => self.requeue(index),
+                (TxStatus::ArbitrationLost, false) | (TxStatus::Error, false) => {
+                    self.in_flight[index] = None;
+                    self.failed += 1;
+                }
+            }
+
+            self.aborting[index] = false;
+            can.clear_tx_status(mailbox);
+        }
+
+        if self.aborting.iter().any(|a| *a) {
+            // picked up by the next call, once the abort completed
+            return;
+        }
+
+        while self.len != 0 {
+            let frame = match self.storage[0].take() {
+                Some(f) => f,
+                None => break,
+            };
+
+            match can.try_transmit(&frame) {
+                Ok(mailbox) => {
+                    self.remove_front();
+                    self.in_flight[mailbox_index(&mailbox)] = Some(frame);
+                }
+                Err(nb::Error::WouldBlock) => {
+                    // no empty mailbox
+                    self.storage[0] = Some(frame);
+                    break;
+                }
+                Err(nb::Error::Other(_)) => {
+                    // rejected, drop it so the frames behind aren't blocked
+                    self.remove_front();
+                    self.failed += 1;
+                }
+            }
+        }
+
+        if self.len != 0 {
+            self.avoid_priority_inversion(can);
+        }
+    }
+
+    // Aborts the lowest priority mailbox when every mailbox holds a frame
+    // of lower priority than the front of the queue
+    fn avoid_priority_inversion<T>(&mut self, can: &T)
+    where
+        T: Transmitter,
+    {
+        let front = self.priority_at(0);
+        let mut lowest: Option<(usize, u32)> = None;
+
+        for (index, frame) in self.in_flight.iter().enumerate() {
+            let priority = match *frame {
+                Some(ref f) => arbitration_priority(f.as_frame()),
+                // an empty mailbox means the front couldn't be queued
+                None => return,
+            };
+
+            if priority <= front {
+                return;
+            }
+
+            match lowest {
+                Some((_, p)) if p >= priority => (),
+                _ => lowest = Some((index, priority)),
+            }
+        }
+
+        if let Some((index, _)) = lowest {
+            // completion is picked up by the next call to process()
+            self.aborting[index] = true;
+            can.abort(&MAILBOXES[index]);
+        }
+    }
+
+    fn requeue(&mut self, index: usize) {
+        if let Some(frame) = self.in_flight[index].take() {
+            if self.enqueue(frame).is_err() {
+                self.failed += 1;
+            }
+        }
+    }
+
+    fn priority_at(&self, index: usize) -> u32 {
+        match self.storage[index] {
+            Some(ref f) => arbitration_priority(f.as_frame()),
+            None => u32::max_value(),
+        }
+    }
+
+    fn remove_front(&mut self) {
+        for index in 1..self.len {
+            self.storage[index - 1] = self.storage[index].take();
+        }
+
+        self.len -= 1;
+        self.storage[self.len] = None;
+    }
+}
+
+fn mailbox_index(mailbox: &TxMailbox) -> usize {
+    match mailbox {
+        TxMailbox::Mailbox0 => 0,
+        TxMailbox::Mailbox1 => 1,
+        TxMailbox::Mailbox2 => 2,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::Cell;
+
+    use super::super::{BaseID, CanError, CanFrame, DataFrame, ID};
+    use super::*;
+    use hal::can::{Frame, StandardId};
+
+    // Mailboxes whose status is set by the test
+    struct MockCan {
+        status: [Cell<TxStatus>; 3],
+        ids: [Cell<u32>; 3],
+        dlcs: [Cell<u8>; 3],
+        aborted: [Cell<bool>; 3],
+        requests: Cell<usize>,
+        reject: Cell<Option<u32>>,
+    }
+
+    impl MockCan {
+        fn new() -> Self {
+            MockCan {
+                status: [
+                    Cell::new(TxStatus::Idle),
+                    Cell::new(TxStatus::Idle),
+                    Cell::new(TxStatus::Idle),
+                ],
+                ids: [Cell::new(0), Cell::new(0), Cell::new(0)],
+                dlcs: [Cell::new(0), Cell::new(0), Cell::new(0)],
+                aborted: [Cell::new(false), Cell::new(false), Cell::new(false)],
+                requests: Cell::new(0),
+                reject: Cell::new(None),
+            }
+        }
+
+        fn complete(&self, index: usize, status: TxStatus) {
+            self.status[index].set(status);
+        }
+    }
+
+    impl Transmitter for MockCan {
+        fn try_transmit(&self, frame: &HalFrame) -> nb::Result<TxMailbox, CanError> {
+            let id = match frame.as_frame().id() {
+                ID::BaseID(id) => u32::from(u16::from(id)),
+                ID::ExtendedID(id) => u32::from(id),
+            };
+
+            if self.reject.get() == Some(id) {
+                return Err(nb::Error::Other(CanError::InvalidFrame));
+            }
+
+            let index = match self
+                .status
+                .iter()
+                .position(|s| s.get() != TxStatus::Pending)
+            {
+                Some(index) => index,
+                None => return Err(nb::Error::WouldBlock),
+            };
+
+            // a new request clears the mailbox status
+            self.status[index].set(TxStatus::Pending);
+            self.ids[index].set(id);
+            self.dlcs[index].set(frame.remote_dlc());
+            self.requests.set(self.requests.get() + 1);
+
+            Ok(MAILBOXES[index])
+        }
+
+        fn tx_status(&self, mailbox: &TxMailbox) -> TxStatus {
+            self.status[mailbox_index(mailbox)].get()
+        }
+
+        fn clear_tx_status(&self, mailbox: &TxMailbox) {
+            self.status[mailbox_index(mailbox)].set(TxStatus::Idle);
+        }
+
+        fn abort(&self, mailbox: &TxMailbox) {
+            self.aborted[mailbox_index(mailbox)].set(true);
+        }
+    }
+
+    fn frame(id: u16) -> HalFrame {
+        HalFrame::from(CanFrame::from(DataFrame::new(ID::BaseID(BaseID::new(id)))))
+    }
+
+    // Fills the mailboxes with low priority frames and queues a higher
+    // priority one, mailbox 2 is being aborted on return
+    fn start_abort(queue: &mut CanTxQueue, can: &MockCan) {
+        for id in 0x300..0x303 {
+            assert!(queue.enqueue(frame(id)).is_ok());
+        }
+
+        queue.process(can);
+        assert!(queue.is_empty());
+
+        assert!(queue.enqueue(frame(0x100)).is_ok());
+        queue.process(can);
+
+        assert_eq!(queue.len(), 1);
+        assert!(!can.aborted[0].get() && !can.aborted[1].get());
+        assert!(can.aborted[2].get());
+    }
+
+    #[test]
+    fn aborted_frame_is_requeued() {
+        let mut storage = [None, None, None, None];
+        let mut queue = CanTxQueue::new(&mut storage);
+        let can = MockCan::new();
+
+        start_abort(&mut queue, &can);
+
+        can.complete(2, TxStatus::Aborted);
+        queue.process(&can);
+
+        // the high priority frame took the mailbox, the aborted one waits
+        assert_eq!(queue.len(), 1);
+        assert_eq!(can.ids[2].get(), 0x100);
+        assert_eq!(can.requests.get(), 4);
+
+        can.complete(0, TxStatus::Success);
+        queue.process(&can);
+
+        assert!(queue.is_empty());
+        assert_eq!(can.ids[0].get(), 0x302);
+        assert_eq!(can.requests.get(), 5);
+        assert_eq!(queue.failed(), 0);
+    }
+
+    #[test]
+    fn abort_too_late_is_not_sent_twice() {
+        let mut storage = [None, None, None, None];
+        let mut queue = CanTxQueue::new(&mut storage);
+        let can = MockCan::new();
+
+        start_abort(&mut queue, &can);
+
+        // mailbox 0 frees up first, nothing is requested until the abort
+        // completed
+        can.complete(0, TxStatus::Success);
+        queue.process(&can);
+
+        assert_eq!(queue.len(), 1);
+        assert_eq!(can.requests.get(), 3);
+
+        // the frame went out before the abort took effect
+        can.complete(2, TxStatus::Success);
+        queue.process(&can);
+
+        assert!(queue.is_empty());
+        assert_eq!(can.ids[0].get(), 0x100);
+        assert_eq!(can.requests.get(), 4);
+
+        can.complete(0, TxStatus::Success);
+        can.complete(1, TxStatus::Success);
+        queue.process(&can);
+
+        assert!(queue.is_idle());
+        assert_eq!(can.requests.get(), 4);
+    }
+
+    #[test]
+    fn rejected_frame_is_dropped() {
+        let mut storage = [None, None, None, None];
+        let mut queue = CanTxQueue::new(&mut storage);
+        let can = MockCan::new();
+
+        can.reject.set(Some(0x100));
+        assert!(queue.enqueue(frame(0x100)).is_ok());
+        assert!(queue.enqueue(frame(0x200)).is_ok());
+        queue.process(&can);
+
+        assert!(queue.is_empty());
+        assert_eq!(queue.failed(), 1);
+        assert_eq!(can.ids[0].get(), 0x200);
+    }
+
+    #[test]
+    fn remote_frame_keeps_dlc() {
+        let mut storage = [None, None, None, None];
+        let mut queue = CanTxQueue::new(&mut storage);
+        let can = MockCan::new();
+        let remote = HalFrame::new_remote(StandardId::new(0x100).unwrap(), 6).unwrap();
+
+        assert!(queue.enqueue(remote).is_ok());
+        queue.process(&can);
+
+        assert_eq!(can.ids[0].get(), 0x100);
+        assert_eq!(can.dlcs[0].get(), 6);
+    }
+}
@@ -0,0 +1,596 @@
+//! candump log format
+//!
+//! Text lines as written by `candump -L` and read by the can-utils
+//! `canplayer`/`log2asc` tools, and a fixed size binary record.
+//!
+//! CAN1, CAN2 and CAN3 are logged as `can0`, `can1` and `can2`.
+//!
+//! Text format:
+//! (1436509052.249713) can0 123#DEADBEEF
+//! (1436509052.250182) can1 1F334455#R
+//!
+//! Timestamps are converted from a 64 bit tick count, the 32 bit
+//! `Instant::ticks()` wrap around every 19.9 s at 216 MHz so their
+//! differences are accumulated.
+//!
+//! Example:
+//! let now = timer.now();
+//! ticks += u64::from(now.ticks().wrapping_sub(last.ticks()));
+//! last = now;
+//!
+//! let entry = LogEntry {
+//!     timestamp: Timestamp::from_ticks(ticks, timer.frequency()),
+//!     interface: Interface::Can1,
+//!     frame,
+//! };
+//! entry.write(&mut serial_tx)?;
+
+use core::fmt;
+
+use can::{BaseID, CanFrame, DataFrame, ExtendedID, RemoteFrame, ID};
+use time::Hertz;
+
+/// Length of a binary record
+pub const RECORD_LEN: usize = 22;
+
+// SocketCAN flags in the binary record ID
+const EFF_FLAG: u32 = 0x8000_0000;
+const RTR_FLAG: u32 = 0x4000_0000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    Timestamp,
+    Interface,
+    Id,
+    Data,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interface {
+    Can1,
+    Can2,
+    Can3,
+}
+
+impl Interface {
+    /// Returns the SocketCAN interface name
+    pub fn name(&self) -> &'static str {
+        match self {
+            Interface::Can1 => "can0",
+            Interface::Can2 => "can1",
+            Interface::Can3 => "can2",
+        }
+    }
+
+    /// Looks up an interface by its SocketCAN name
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "can0" => Some(Interface::Can1),
+            "can1" => Some(Interface::Can2),
+            "can2" => Some(Interface::Can3),
+            _ => None,
+        }
+    }
+
+    fn index(&self) -> u8 {
+        match self {
+            Interface::Can1 => 0,
+            Interface::Can2 => 1,
+            Interface::Can3 => 2,
+        }
+    }
+
+    fn from_index(index: u8) -> Option<Self> {
+        match index {
+            0 => Some(Interface::Can1),
+            1 => Some(Interface::Can2),
+            2 => Some(Interface::Can3),
+            _ => None,
+        }
+    }
+}
+
+/// Seconds and microseconds
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Timestamp {
+    pub seconds: u32,
+    pub micros: u32,
+}
+
+impl Timestamp {
+    /// Converts a tick count of a counter running at `frequency`, a zero
+    /// frequency gives a zero timestamp
+    pub fn from_ticks(ticks: u64, frequency: Hertz) -> Self {
+        let frequency = u64::from(frequency.0);
+
+        if frequency == 0 {
+            return Timestamp {
+                seconds: 0,
+                micros: 0,
+            };
+        }
+
+        Timestamp {
+            seconds: (ticks / frequency) as u32,
+            micros: ((ticks % frequency) * 1_000_000 / frequency) as u32,
+        }
+    }
+}
+
+/// A logged frame
+pub struct LogEntry {
+    pub timestamp: Timestamp,
+    pub interface: Interface,
+    pub frame: CanFrame,
+}
+
+impl LogEntry {
+    /// Writes the entry as a text line, including the line feed
+    pub fn write<W>(&self, w: &mut W) -> fmt::Result
+    where
+        W: fmt::Write,
+    {
+        write!(
+            w,
+            "({}.{:06}) {} ",
+            self.timestamp.seconds,
+            self.timestamp.micros,
+            self.interface.name()
+        )?;
+
+        match self.frame.id() {
+            ID::BaseID(id) => write!(w, "{:03X}#", u16::from(id))?,
+            ID::ExtendedID(id) => write!(w, "{:08X}#", u32::from(id))?,
+        }
+
+        match self.frame {
+            CanFrame::DataFrame(ref f) => {
+                for byte in f.data() {
+                    write!(w, "{:02X}", byte)?;
+                }
+            }
+            CanFrame::RemoteFrame(_) => w.write_char('R')?,
+        }
+
+        w.write_char('\n')
+    }
+
+    /// Parses a text line, surrounding whitespace is ignored.
+    ///
+    /// The DLC of remote frames isn't kept.
+    pub fn parse(line: &str) -> Result<Self, ParseError> {
+        let mut fields = line.split_whitespace();
+
+        let timestamp = fields
+            .next()
+            .and_then(parse_timestamp)
+            .ok_or(ParseError::Timestamp)?;
+
+        let interface = fields
+            .next()
+            .and_then(Interface::from_name)
+            .ok_or(ParseError::Interface)?;
+
+        let frame = fields.next().ok_or(ParseError::Id)?;
+        let separator = frame.find('#').ok_or(ParseError::Id)?;
+        let (id, data) = (&frame[..separator], &frame[separator + 1..]);
+
+        let id = match id.len() {
+            3 => {
+                let id = parse_hex(id).ok_or(ParseError::Id)?;
+
+                if id > 0x7FF {
+                    return Err(ParseError::Id);
+                }
+
+                ID::BaseID(BaseID::new(id as u16))
+            }
+            8 => {
+                let id = parse_hex(id).ok_or(ParseError::Id)?;
+
+                if id > 0x1FFF_FFFF {
+                    return Err(ParseError::Id);
+                }
+
+                ID::ExtendedID(ExtendedID::new(id))
+            }
+            _ => return Err(ParseError::Id),
+        };
+
+        let frame = if data.starts_with('R') {
+            CanFrame::from(RemoteFrame::new(id))
+        } else {
+            if ((data.len() % 2) != 0) || (data.len() > 16) {
+                return Err(ParseError::Data);
+            }
+
+            let mut frame = DataFrame::new(id);
+            frame.set_data_length(data.len() / 2);
+
+            for (index, byte) in frame.data_as_mut().iter_mut().enumerate() {
+                let digits = data.get(2 * index..2 * index + 2).ok_or(ParseError::Data)?;
+                *byte = parse_hex(digits).ok_or(ParseError::Data)? as u8;
+            }
+
+            CanFrame::from(frame)
+        };
+
+        Ok(LogEntry {
+            timestamp,
+            interface,
+            frame,
+        })
+    }
+
+    /// Encodes the entry as a binary record, all fields little endian:
+    /// seconds (4), microseconds (4), ID with the SocketCAN EFF/RTR flags
+    /// (4), interface index (1), DLC (1), data (8, zero padded)
+    pub fn encode(&self, record: &mut [u8; RECORD_LEN]) {
+        let (mut id, extended) = match self.frame.id() {
+            ID::BaseID(id) => (u32::from(u16::from(id)), false),
+            ID::ExtendedID(id) => (u32::from(id), true),
+        };
+
+        if extended {
+            id |= EFF_FLAG;
+        }
+
+        let data = match self.frame {
+            CanFrame::DataFrame(ref f) => f.data(),
+            CanFrame::RemoteFrame(_) => {
+                id |= RTR_FLAG;
+                &[]
+            }
+        };
+
+        write_u32(&mut record[0..4], self.timestamp.seconds);
+        write_u32(&mut record[4..8], self.timestamp.micros);
+        write_u32(&mut record[8..12], id);
+        record[12] = self.interface.index();
+        record[13] = data.len() as u8;
+
+        for (index, byte) in record[14..].iter_mut().enumerate() {
+            *byte = data.get(index).cloned().unwrap_or(0);
+        }
+    }
+
+    /// Decodes a binary record, see `encode()`
+    pub fn decode(record: &[u8; RECORD_LEN]) -> Result<Self, ParseError> {
+        let timestamp = Timestamp {
+            seconds: read_u32(&record[0..4]),
+            micros: read_u32(&record[4..8]),
+        };
+
+        let interface = Interface::from_index(record[12]).ok_or(ParseError::Interface)?;
+
+        let raw = read_u32(&record[8..12]);
+        let id = if (raw & EFF_FLAG) != 0 {
+            ID::ExtendedID(ExtendedID::new(raw & 0x1FFF_FFFF))
+        } else if (raw & 0x1FFF_FFFF) <= 0x7FF {
+            ID::BaseID(BaseID::new(raw as u16 & 0x7FF))
+        } else {
+            return Err(ParseError::Id);
+        };
+
+        let frame = if (raw & RTR_FLAG) != 0 {
+            CanFrame::from(RemoteFrame::new(id))
+        } else {
+            let len = record[13] as usize;
+
+            if len > 8 {
+                return Err(ParseError::Data);
+            }
+
+            let mut frame = DataFrame::new(id);
+            frame.set_data_length(len);
+            frame.data_as_mut().copy_from_slice(&record[14..14 + len]);
+
+            CanFrame::from(frame)
+        };
+
+        Ok(LogEntry {
+            timestamp,
+            interface,
+            frame,
+        })
+    }
+}
+
+// Parses "(seconds.micros)"
+fn parse_timestamp(field: &str) -> Option<Timestamp> {
+    if !field.starts_with('(') || !field.ends_with(')') || (field.len() < 3) {
+        return None;
+    }
+
+    let field = &field[1..field.len() - 1];
+    let (seconds, fraction) = match field.find('.') {
+        Some(dot) => (&field[..dot], &field[dot + 1..]),
+        None => (field, ""),
+    };
+
+    // `parse()` would accept a sign
+    if seconds.is_empty()
+        || !seconds.bytes().all(|b| b.is_ascii_digit())
+        || !fraction.bytes().all(|b| b.is_ascii_digit())
+    {
+        return None;
+    }
+
+    // scaled to microseconds
+    let mut micros = 0;
+
+    for index in 0..6 {
+        let digit = fraction.as_bytes().get(index).map_or(0, |b| b - b'0');
+        micros = (micros * 10) + u32::from(digit);
+    }
+
+    Some(Timestamp {
+        seconds: seconds.parse().ok()?,
+        micros,
+    })
+}
+
+// Parses up to 8 hex digits, `from_str_radix()` would accept a sign
+fn parse_hex(digits: &str) -> Option<u32> {
+    if digits.is_empty() || (digits.len() > 8) || !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    u32::from_str_radix(digits, 16).ok()
+}
+
+fn write_u32(bytes: &mut [u8], value: u32) {
+    for (index, byte) in bytes.iter_mut().enumerate() {
+        *byte = (value >> (8 * index)) as u8;
+    }
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    bytes.iter().enumerate().fold(0, |value, (index, byte)| {
+        value | (u32::from(*byte) << (8 * index))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::str;
+
+    // Line buffer for `LogEntry::write()`
+    struct Line {
+        bytes: [u8; 64],
+        len: usize,
+    }
+
+    impl Line {
+        fn as_str(&self) -> &str {
+            str::from_utf8(&self.bytes[..self.len]).unwrap()
+        }
+    }
+
+    impl fmt::Write for Line {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            let end = self.len + s.len();
+
+            self.bytes
+                .get_mut(self.len..end)
+                .ok_or(fmt::Error)?
+                .copy_from_slice(s.as_bytes());
+            self.len = end;
+
+            Ok(())
+        }
+    }
+
+    fn write(entry: &LogEntry) -> Line {
+        let mut line = Line {
+            bytes: [0; 64],
+            len: 0,
+        };
+
+        entry.write(&mut line).unwrap();
+        line
+    }
+
+    fn data_frame(id: ID, data: &[u8]) -> DataFrame {
+        let mut frame = DataFrame::new(id);
+
+        frame.set_data_length(data.len());
+        frame.data_as_mut().copy_from_slice(data);
+
+        frame
+    }
+
+    fn entry<F>(frame: F) -> LogEntry
+    where
+        F: Into<CanFrame>,
+    {
+        LogEntry {
+            timestamp: Timestamp {
+                seconds: 1436509052,
+                micros: 249713,
+            },
+            interface: Interface::Can2,
+            frame: frame.into(),
+        }
+    }
+
+    fn assert_same(a: &LogEntry, b: &LogEntry) {
+        assert_eq!(a.timestamp, b.timestamp);
+        assert_eq!(a.interface, b.interface);
+
+        match (&a.frame, &b.frame) {
+            (&CanFrame::DataFrame(ref a), &CanFrame::DataFrame(ref b)) => assert_eq!(a, b),
+            (&CanFrame::RemoteFrame(ref a), &CanFrame::RemoteFrame(ref b)) => {
+                assert_eq!(a.id(), b.id())
+            }
+            _ => panic!("frame types differ"),
+        }
+    }
+
+    // Data frames of every length, standard and extended
+    fn frames() -> [DataFrame; 18] {
+        let data = [0xDE, 0xAD, 0xBE, 0xEF, 0x01, 0x23, 0x45, 0x67];
+        let standard = ID::BaseID(BaseID::new(0x123));
+        let extended = ID::ExtendedID(ExtendedID::new(0x1F33_4455));
+
+        [
+            data_frame(standard, &data[..0]),
+            data_frame(standard, &data[..1]),
+            data_frame(standard, &data[..2]),
+            data_frame(standard, &data[..3]),
+            data_frame(standard, &data[..4]),
+            data_frame(standard, &data[..5]),
+            data_frame(standard, &data[..6]),
+            data_frame(standard, &data[..7]),
+            data_frame(standard, &data[..8]),
+            data_frame(extended, &data[..0]),
+            data_frame(extended, &data[..1]),
+            data_frame(extended, &data[..2]),
+            data_frame(extended, &data[..3]),
+            data_frame(extended, &data[..4]),
+            data_frame(extended, &data[..5]),
+            data_frame(extended, &data[..6]),
+            data_frame(extended, &data[..7]),
+            data_frame(extended, &data[..8]),
+        ]
+    }
+
+    #[test]
+    fn text_format() {
+        let standard = entry(data_frame(
+            ID::BaseID(BaseID::new(0x123)),
+            &[0xDE, 0xAD, 0xBE, 0xEF],
+        ));
+        assert_eq!(
+            write(&standard).as_str(),
+            "(1436509052.249713) can1 123#DEADBEEF\n"
+        );
+
+        let remote = entry(CanFrame::from(RemoteFrame::new(ID::ExtendedID(
+            ExtendedID::new(0x1F33_4455),
+        ))));
+        assert_eq!(
+            write(&remote).as_str(),
+            "(1436509052.249713) can1 1F334455#R\n"
+        );
+
+        let empty = entry(data_frame(ID::BaseID(BaseID::new(0x7)), &[]));
+        assert_eq!(write(&empty).as_str(), "(1436509052.249713) can1 007#\n");
+    }
+
+    #[test]
+    fn text_round_trip() {
+        for frame in frames().iter() {
+            let entry = entry(CanFrame::from(*frame));
+
+            assert_same(&LogEntry::parse(write(&entry).as_str()).unwrap(), &entry);
+        }
+
+        for id in [
+            ID::BaseID(BaseID::new(0x7FF)),
+            ID::ExtendedID(ExtendedID::new(0x1FFF_FFFF)),
+        ]
+        .iter()
+        {
+            let entry = entry(CanFrame::from(RemoteFrame::new(*id)));
+
+            assert_same(&LogEntry::parse(write(&entry).as_str()).unwrap(), &entry);
+        }
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        let mut record = [0; RECORD_LEN];
+
+        for frame in frames().iter() {
+            let entry = entry(CanFrame::from(*frame));
+
+            entry.encode(&mut record);
+            assert_same(&LogEntry::decode(&record).unwrap(), &entry);
+        }
+
+        let entry = entry(CanFrame::from(RemoteFrame::new(ID::ExtendedID(
+            ExtendedID::new(0x1F33_4455),
+        ))));
+        entry.encode(&mut record);
+        assert_eq!(read_u32(&record[8..12]), 0x1F33_4455 | EFF_FLAG | RTR_FLAG);
+        assert_same(&LogEntry::decode(&record).unwrap(), &entry);
+    }
+
+    #[test]
+    fn malformed_lines() {
+        let errors = [
+            ("", ParseError::Timestamp),
+            ("1436509052.249713 can0 123#00", ParseError::Timestamp),
+            ("(+1436509052.249713) can0 123#00", ParseError::Timestamp),
+            ("(.249713) can0 123#00", ParseError::Timestamp),
+            ("(1436509052.2x9713) can0 123#00", ParseError::Timestamp),
+            ("(1436509052.249713) can3 123#00", ParseError::Interface),
+            ("(1436509052.249713) can0", ParseError::Id),
+            ("(1436509052.249713) can0 12300", ParseError::Id),
+            ("(1436509052.249713) can0 +12#00", ParseError::Id),
+            ("(1436509052.249713) can0 +1F33445#00", ParseError::Id),
+            ("(1436509052.249713) can0 800#00", ParseError::Id),
+            ("(1436509052.249713) can0 20000000#00", ParseError::Id),
+            ("(1436509052.249713) can0 1234#00", ParseError::Id),
+            ("(1436509052.249713) can0 123#0", ParseError::Data),
+            ("(1436509052.249713) can0 123#+F", ParseError::Data),
+            ("(1436509052.249713) can0 123#0G", ParseError::Data),
+            (
+                "(1436509052.249713) can0 123#001122334455667788",
+                ParseError::Data,
+            ),
+        ];
+
+        for &(line, error) in errors.iter() {
+            assert_eq!(LogEntry::parse(line).err(), Some(error), "{}", line);
+        }
+    }
+
+    #[test]
+    fn malformed_records() {
+        let mut record = [0; RECORD_LEN];
+
+        entry(data_frame(ID::BaseID(BaseID::new(0x123)), &[])).encode(&mut record);
+
+        let mut bad = record;
+        bad[12] = 3;
+        assert_eq!(LogEntry::decode(&bad).err(), Some(ParseError::Interface));
+
+        let mut bad = record;
+        write_u32(&mut bad[8..12], 0x800);
+        assert_eq!(LogEntry::decode(&bad).err(), Some(ParseError::Id));
+
+        let mut bad = record;
+        bad[13] = 9;
+        assert_eq!(LogEntry::decode(&bad).err(), Some(ParseError::Data));
+    }
+
+    #[test]
+    fn timestamp_from_ticks() {
+        let frequency = Hertz(216_000_000);
+
+        assert_eq!(
+            Timestamp::from_ticks(216_000_000 + 108_000, frequency),
+            Timestamp {
+                seconds: 1,
+                micros: 500,
+            }
+        );
+
+        // past the 32 bit counter range
+        assert_eq!(
+            Timestamp::from_ticks(u64::from(u32::max_value()) * 2, frequency).seconds,
+            39
+        );
+
+        assert_eq!(
+            Timestamp::from_ticks(1000, Hertz(0)),
+            Timestamp {
+                seconds: 0,
+                micros: 0,
+            }
+        );
+    }
+}
@@ -0,0 +1,139 @@
+//! Direct Memory Access
+//!
+//! DMA2 is split into its eight streams so that each one can be owned by a
+//! different driver, e.g. a `ScanDma` per ADC. Streams without a driver in
+//! this crate are plain ownership tokens.
+//!
+//! Example:
+//! let streams = dp.DMA2.split(&mut rcc.ahb1);
+//!
+//! let scan1 = adc1.scan_dma(&sequence1, unsafe { &mut BUFFER1 }, streams.s0)?;
+//! let scan3 = adc3.scan_dma(&sequence3, unsafe { &mut BUFFER3 }, streams.s1)?;
+
+use rcc::AHB1;
+use stm32f7x7::{dma2, DMA2};
+
+/// Extension trait to split a DMA peripheral in independent streams
+pub trait DmaExt {
+    /// The streams to split the DMA into
+    type Streams;
+
+    /// Enables and resets the DMA, splitting it into independent streams
+    fn split(self, ahb: &mut AHB1) -> Self::Streams;
+}
+
+/// DMA2 streams
+pub struct Streams {
+    pub s0: S0,
+    pub s1: S1,
+    pub s2: S2,
+    pub s3: S3,
+    pub s4: S4,
+    pub s5: S5,
+    pub s6: S6,
+    pub s7: S7,
+}
+
+impl DmaExt for DMA2 {
+    type Streams = Streams;
+
+    fn split(self, ahb: &mut AHB1) -> Streams {
+        ahb.enr().modify(|_, w| w.dma2en().set_bit());
+        ahb.rstr().modify(|_, w| w.dma2rst().set_bit());
+        ahb.rstr().modify(|_, w| w.dma2rst().clear_bit());
+
+        Streams {
+            s0: S0 { _0: () },
+            s1: S1 { _0: () },
+            s2: S2 { _0: () },
+            s3: S3 { _0: () },
+            s4: S4 { _0: () },
+            s5: S5 { _0: () },
+            s6: S6 { _0: () },
+            s7: S7 { _0: () },
+        }
+    }
+}
+
+macro_rules! streams {
+    ($(
+        $SX:ident: ($sxcr:ident, $SXCR:ident, $sxndtr:ident, $SXNDTR:ident, $sxpar:ident,
+            $SXPAR:ident, $sxm0ar:ident, $SXM0AR:ident, $isr:ident, $ifcr:ident, $flags:expr),
+    )+) => {
+        $(
+/// DMA2 stream
+pub struct $SX {
+    _0: (),
+}
+
+impl $SX {
+    // NOTE(unsafe) the stream registers are only accessed through this
+    // handle
+    pub(crate) fn cr(&mut self) -> &dma2::$SXCR {
+        unsafe { &(*DMA2::ptr()).$sxcr }
+    }
+
+    pub(crate) fn ndtr(&mut self) -> &dma2::$SXNDTR {
+        unsafe { &(*DMA2::ptr()).$sxndtr }
+    }
+
+    pub(crate) fn par(&mut self) -> &dma2::$SXPAR {
+        unsafe { &(*DMA2::ptr()).$sxpar }
+    }
+
+    pub(crate) fn m0ar(&mut self) -> &dma2::$SXM0AR {
+        unsafe { &(*DMA2::ptr()).$sxm0ar }
+    }
+
+    /// Returns the interrupt flags of the stream, FEIF at bit 0
+    pub(crate) fn flags(&self) -> u32 {
+        // NOTE(unsafe) atomic read with no side effects
+        let isr = unsafe { (*DMA2::ptr()).$isr.read().bits() };
+
+        (isr >> $flags) & STREAM_FLAGS
+    }
+
+    /// Clears interrupt flags of the stream, FEIF at bit 0
+    pub(crate) fn clear_flags(&mut self, flags: u32) {
+        // NOTE(unsafe) cleared by writing 1, the flags of other streams
+        // are no-ops with 0
+        unsafe {
+            (*DMA2::ptr())
+                .$ifcr
+                .write(|w| w.bits((flags & STREAM_FLAGS) << $flags))
+        }
+    }
+}
+        )+
+    }
+}
+
+macro_rules! tokens {
+    ($($SX:ident,)+) => {
+        $(
+/// DMA2 stream
+pub struct $SX {
+    _0: (),
+}
+        )+
+    }
+}
+
+/// FEIF, DMEIF, TEIF, HTIF and TCIF, relative to the stream flags
+pub(crate) const STREAM_FLAGS: u32 = 0b11_1101;
+
+// (registers, status/clear registers, stream flags base bit)
+streams! {
+    S0: (s0cr, S0CR, s0ndtr, S0NDTR, s0par, S0PAR, s0m0ar, S0M0AR, lisr, lifcr, 0),
+    S1: (s1cr, S1CR, s1ndtr, S1NDTR, s1par, S1PAR, s1m0ar, S1M0AR, lisr, lifcr, 6),
+    S2: (s2cr, S2CR, s2ndtr, S2NDTR, s2par, S2PAR, s2m0ar, S2M0AR, lisr, lifcr, 16),
+}
+
+// no driver uses these streams yet
+tokens! {
+    S3,
+    S4,
+    S5,
+    S6,
+    S7,
+}
@@ -102,8 +102,8 @@ macro_rules! gpio {
 
             use rcc::AHB1;
             use super::{
-                AF4, AF5, AF6, AF7, AF9, Analog, Floating, GpioExt, Input, OpenDrain, Output,
-                PullDown, PullUp, PushPull,
+                AF11, AF4, AF5, AF6, AF7, AF9, Analog, Floating, GpioExt, Input, OpenDrain,
+                Output, PullDown, PullUp, PushPull,
             };
 
             /// GPIO parts
@@ -355,6 +355,30 @@ macro_rules! gpio {
                         $PXi { _mode: PhantomData }
                     }
 
+                    /// Configures the pin to serve as alternate function 11 (AF11)
+                    pub fn into_af11(
+                        self,
+                        moder: &mut MODER,
+                        afr: &mut $AFR,
+                    ) -> $PXi<AF11> {
+                        let offset = 2 * $i;
+
+                        // alternate function mode
+                        let mode = 0b10;
+                        moder.moder().modify(|r, w| unsafe {
+                            w.bits((r.bits() & !(0b11 << offset)) | (mode << offset))
+                        });
+
+                        let af = 11;
+                        let offset = 4 * ($i % 8);
+
+                        afr.afr().modify(|r, w| unsafe {
+                            w.bits((r.bits() & !(0b1111 << offset)) | (af << offset))
+                        });
+
+                        $PXi { _mode: PhantomData }
+                    }
+
                     /// Configures the pin to operate as a floating input pin
                     pub fn into_floating_input(
                         self,
@@ -579,10 +603,10 @@ gpio!(GPIOB, gpiob, gpiob, gpioben, gpiobrst, PBx, [
     PB0: (pb0, 0, Input<Floating>, AFRL),
     PB1: (pb1, 1, Input<Floating>, AFRL),
     PB2: (pb2, 2, Input<Floating>, AFRL),
-    // TODO these are configured as JTAG pins
-    // PB3: (3, Input<Floating>),
-    // PB4: (4, Input<Floating>),
-    PB4: (pb4, 4, Input<Floating>, AFRL),
+    // PB3 (JTDO) and PB4 (NJTRST) reset to their JTAG alternate function,
+    // reconfiguring them gives up JTAG but leaves SWD usable
+    PB3: (pb3, 3, ::gpio::AF0, AFRL),
+    PB4: (pb4, 4, ::gpio::AF0, AFRL),
     PB5: (pb5, 5, Input<Floating>, AFRL),
     PB6: (pb6, 6, Input<Floating>, AFRL),
     PB7: (pb7, 7, Input<Floating>, AFRL),
@@ -658,8 +682,11 @@ gpio!(GPIOF, gpiof, gpiof, gpiofen, gpiofrst, PFx, [
     PF1: (pf1, 1, Input<Floating>, AFRL),
     PF2: (pf2, 2, Input<Floating>, AFRL),
     PF3: (pf3, 3, Input<Floating>, AFRL),
+    PF4: (pf4, 4, Input<Floating>, AFRL),
     PF5: (pf5, 5, Input<Floating>, AFRL),
     PF6: (pf6, 6, Input<Floating>, AFRL),
+    PF7: (pf7, 7, Input<Floating>, AFRL),
+    PF8: (pf8, 8, Input<Floating>, AFRH),
     PF9: (pf9, 9, Input<Floating>, AFRH),
     PF10: (pf10, 10, Input<Floating>, AFRH),
 ]);
@@ -0,0 +1,882 @@
+//! ISO-TP (ISO 15765-2) transport layer
+//!
+//! `IsoTp` is the peripheral independent state machine, it is fed received
+//! frames and polled for the frames to transmit. Time is given as ticks of
+//! a free running 32 bit counter running at the frequency passed to
+//! `IsoTp::new()`.
+//!
+//! `IsoTpCan` drives the state machine with a CAN interface and a
+//! `MonoTimer`.
+//!
+//! Example:
+//! static mut TX_BUFFER: [u8; 256] = [0; 256];
+//! static mut RX_BUFFER: [u8; 256] = [0; 256];
+//!
+//! let config = IsoTpConfig::new(ID::BaseID(BaseID::new(0x7E0)), ID::BaseID(BaseID::new(0x7E8)));
+//! let isotp = IsoTp::new(config, unsafe { &mut TX_BUFFER }, unsafe { &mut RX_BUFFER }, timer.frequency());
+//! let mut channel = IsoTpCan::new(can, isotp, timer);
+//!
+//! channel.send(&request)?;
+//!
+//! loop {
+//!     if let Some(response) = channel.poll()? {
+//!         ...
+//!     }
+//! }
+
+use core::cmp;
+use hal::can::nb::Can;
+use hal::can::Frame;
+use nb;
+
+use can::{from_hal_id, to_hal_id, DataFrame, ID};
+use time::{Hertz, MonoTimer};
+
+/// Largest message length that fits the 12 bit first frame length
+pub const MAX_MESSAGE_LEN: usize = 4095;
+
+// protocol control information, upper nibble of the first byte
+const PCI_SINGLE: u8 = 0x00;
+const PCI_FIRST: u8 = 0x10;
+const PCI_CONSECUTIVE: u8 = 0x20;
+const PCI_FLOW_CONTROL: u8 = 0x30;
+
+// flow status, lower nibble of the flow control PCI
+const FS_CONTINUE: u8 = 0x00;
+const FS_WAIT: u8 = 0x01;
+const FS_OVERFLOW: u8 = 0x02;
+
+const SINGLE_FRAME_MAX: usize = 7;
+const FIRST_FRAME_DATA: usize = 6;
+const CONSECUTIVE_FRAME_DATA: usize = 7;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IsoTpError {
+    /// A message is already being sent
+    Busy,
+    /// Message is empty, too large for the transmit buffer or above
+    /// `MAX_MESSAGE_LEN`
+    InvalidLength,
+    /// Received message doesn't fit the receive buffer
+    BufferOverflow,
+    /// The receiver reported an overflow
+    ReceiverOverflow,
+    /// No flow control (N_Bs) or consecutive frame (N_Cr) in time
+    Timeout,
+    /// Consecutive frame with the wrong sequence number
+    UnexpectedSequence,
+    /// More flow control wait frames than allowed
+    TooManyWaits,
+    /// Malformed frame
+    InvalidFrame,
+    /// The CAN interface reported an error
+    Bus,
+}
+
+pub struct IsoTpConfig {
+    /// ID of the transmitted frames
+    pub tx_id: ID,
+    /// ID of the received frames
+    pub rx_id: ID,
+    /// Block size sent in flow control frames, 0 for no limit
+    pub block_size: u8,
+    /// STmin sent in flow control frames, in the encoding of the frame
+    pub st_min: u8,
+    /// N_Bs and N_Cr timeout in milliseconds
+    pub timeout_ms: u32,
+    /// Maximum number of consecutive flow control wait frames
+    pub max_wait_frames: u8,
+    /// Pads transmitted frames to 8 bytes
+    pub padding: Option<u8>,
+}
+
+impl IsoTpConfig {
+    /// Configuration without block size and STmin limits, and the
+    /// default 1 second timeouts
+    pub fn new(tx_id: ID, rx_id: ID) -> Self {
+        IsoTpConfig {
+            tx_id,
+            rx_id,
+            block_size: 0,
+            st_min: 0,
+            timeout_ms: 1000,
+            max_wait_frames: 10,
+            padding: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum TxState {
+    Idle,
+    // single or first frame is next
+    Start,
+    WaitFlowControl { since: u32, waits: u8 },
+    Consecutive { last: Option<u32>, sent: u8 },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum RxState {
+    Idle,
+    Receiving { since: u32 },
+    Complete,
+}
+
+/// Transport layer state machine for one pair of IDs
+pub struct IsoTp<'a> {
+    config: IsoTpConfig,
+    frequency: Hertz,
+    tx_buffer: &'a mut [u8],
+    tx_state: TxState,
+    tx_len: usize,
+    tx_offset: usize,
+    tx_seq: u8,
+    // block size and STmin of the receiver
+    tx_block_size: u8,
+    tx_st_min: u32,
+    rx_buffer: &'a mut [u8],
+    rx_state: RxState,
+    rx_len: usize,
+    rx_offset: usize,
+    rx_seq: u8,
+    rx_block: u8,
+    // flow status of a flow control frame waiting to be sent
+    flow_control: Option<u8>,
+}
+
+impl<'a> IsoTp<'a> {
+    /// Creates the state machine, messages are limited to the length of
+    /// the buffers. `frequency` is the tick rate of the time values.
+    pub fn new(
+        config: IsoTpConfig,
+        tx_buffer: &'a mut [u8],
+        rx_buffer: &'a mut [u8],
+        frequency: Hertz,
+    ) -> Self {
+        IsoTp {
+            config,
+            frequency,
+            tx_buffer,
+            tx_state: TxState::Idle,
+            tx_len: 0,
+            tx_offset: 0,
+            tx_seq: 0,
+            tx_block_size: 0,
+            tx_st_min: 0,
+            rx_buffer,
+            rx_state: RxState::Idle,
+            rx_len: 0,
+            rx_offset: 0,
+            rx_seq: 0,
+            rx_block: 0,
+            flow_control: None,
+        }
+    }
+
+    /// Starts sending a message, the frames are handed out by `poll()`
+    pub fn send(&mut self, data: &[u8]) -> Result<(), IsoTpError> {
+        if self.tx_state != TxState::Idle {
+            return Err(IsoTpError::Busy);
+        }
+
+        if data.is_empty() || (data.len() > MAX_MESSAGE_LEN) || (data.len() > self.tx_buffer.len())
+        {
+            return Err(IsoTpError::InvalidLength);
+        }
+
+        self.tx_buffer[..data.len()].copy_from_slice(data);
+        self.tx_len = data.len();
+        self.tx_offset = 0;
+        self.tx_state = TxState::Start;
+
+        Ok(())
+    }
+
+    /// Returns true while a message is being sent
+    pub fn is_sending(&self) -> bool {
+        self.tx_state != TxState::Idle
+    }
+
+    /// Stops sending the current message
+    pub fn abort(&mut self) {
+        self.tx_state = TxState::Idle;
+    }
+
+    /// Changes the IDs, transfers in progress are dropped
+    pub fn set_ids(&mut self, tx_id: ID, rx_id: ID) {
+        self.config.tx_id = tx_id;
+        self.config.rx_id = rx_id;
+        self.tx_state = TxState::Idle;
+        self.rx_state = RxState::Idle;
+        self.flow_control = None;
+    }
+
+    /// Returns the last completely received message
+    pub fn received(&self) -> Option<&[u8]> {
+        match self.rx_state {
+            RxState::Complete => Some(&self.rx_buffer[..self.rx_len]),
+            _ => None,
+        }
+    }
+
+    /// Processes a received frame, frames of other IDs are ignored.
+    ///
+    /// Returns the length of the message once it has been received, see
+    /// `received()`.
+    pub fn on_frame(&mut self, id: ID, data: &[u8], now: u32) -> Result<Option<usize>, IsoTpError> {
+        if (id != self.config.rx_id) || data.is_empty() {
+            return Ok(None);
+        }
+
+        match data[0] & 0xF0 {
+            PCI_SINGLE => self.on_single_frame(data),
+            PCI_FIRST => self.on_first_frame(data, now),
+            PCI_CONSECUTIVE => self.on_consecutive_frame(data, now),
+            PCI_FLOW_CONTROL => self.on_flow_control(data, now).map(|_| None),
+            _ => Ok(None),
+        }
+    }
+
+    /// Checks the timeouts and returns the next frame to transmit.
+    ///
+    /// A timeout aborts the transfer it belongs to.
+    pub fn poll(&mut self, now: u32) -> Result<Option<DataFrame>, IsoTpError> {
+        let timeout = self.ticks(u64::from(self.config.timeout_ms) * 1000);
+
+        if let RxState::Receiving { since } = self.rx_state {
+            if now.wrapping_sub(since) >= timeout {
+                self.rx_state = RxState::Idle;
+                self.flow_control = None;
+                return Err(IsoTpError::Timeout);
+            }
+        }
+
+        if let Some(status) = self.flow_control.take() {
+            let (block_size, st_min) = (self.config.block_size, self.config.st_min);
+            return Ok(Some(self.frame(&[
+                PCI_FLOW_CONTROL | status,
+                block_size,
+                st_min,
+            ])));
+        }
+
+        match self.tx_state {
+            TxState::Idle => Ok(None),
+            TxState::Start => Ok(Some(self.start_frame(now))),
+            TxState::WaitFlowControl { since, .. } => {
+                if now.wrapping_sub(since) >= timeout {
+                    self.tx_state = TxState::Idle;
+                    Err(IsoTpError::Timeout)
+                } else {
+                    Ok(None)
+                }
+            }
+            TxState::Consecutive { last, sent } => {
+                if let Some(last) = last {
+                    if now.wrapping_sub(last) < self.tx_st_min {
+                        return Ok(None);
+                    }
+                }
+
+                Ok(Some(self.consecutive_frame(sent, now)))
+            }
+        }
+    }
+
+    fn start_frame(&mut self, now: u32) -> DataFrame {
+        let mut bytes = [0; 8];
+
+        if self.tx_len <= SINGLE_FRAME_MAX {
+            bytes[0] = PCI_SINGLE | (self.tx_len as u8);
+            bytes[1..self.tx_len + 1].copy_from_slice(&self.tx_buffer[..self.tx_len]);
+
+            self.tx_state = TxState::Idle;
+
+            return self.frame(&bytes[..self.tx_len + 1]);
+        }
+
+        bytes[0] = PCI_FIRST | ((self.tx_len >> 8) as u8);
+        bytes[1] = self.tx_len as u8;
+        bytes[2..].copy_from_slice(&self.tx_buffer[..FIRST_FRAME_DATA]);
+
+        self.tx_offset = FIRST_FRAME_DATA;
+        self.tx_seq = 1;
+        self.tx_state = TxState::WaitFlowControl {
+            since: now,
+            waits: 0,
+        };
+
+        self.frame(&bytes)
+    }
+
+    fn consecutive_frame(&mut self, sent: u8, now: u32) -> DataFrame {
+        let len = cmp::min(CONSECUTIVE_FRAME_DATA, self.tx_len - self.tx_offset);
+        let mut bytes = [0; 8];
+
+        bytes[0] = PCI_CONSECUTIVE | self.tx_seq;
+        bytes[1..len + 1].copy_from_slice(&self.tx_buffer[self.tx_offset..self.tx_offset + len]);
+
+        self.tx_offset += len;
+        self.tx_seq = (self.tx_seq + 1) & 0x0F;
+
+        // only counted against a block size, unlimited blocks overflow it
+        let sent = if self.tx_block_size != 0 { sent + 1 } else { 0 };
+
+        self.tx_state = if self.tx_offset == self.tx_len {
+            TxState::Idle
+        } else if (self.tx_block_size != 0) && (sent == self.tx_block_size) {
+            TxState::WaitFlowControl {
+                since: now,
+                waits: 0,
+            }
+        } else {
+            TxState::Consecutive {
+                last: Some(now),
+                sent,
+            }
+        };
+
+        self.frame(&bytes[..len + 1])
+    }
+
+    fn on_single_frame(&mut self, data: &[u8]) -> Result<Option<usize>, IsoTpError> {
+        let len = (data[0] & 0x0F) as usize;
+
+        if (len == 0) || (len > SINGLE_FRAME_MAX) || (data.len() < (len + 1)) {
+            return Ok(None);
+        }
+
+        // a new message replaces the one in progress
+        self.flow_control = None;
+
+        if len > self.rx_buffer.len() {
+            self.rx_state = RxState::Idle;
+            return Err(IsoTpError::BufferOverflow);
+        }
+
+        self.rx_buffer[..len].copy_from_slice(&data[1..len + 1]);
+        self.rx_len = len;
+        self.rx_state = RxState::Complete;
+
+        Ok(Some(len))
+    }
+
+    fn on_first_frame(&mut self, data: &[u8], now: u32) -> Result<Option<usize>, IsoTpError> {
+        if data.len() < 8 {
+            return Ok(None);
+        }
+
+        let len = (((data[0] & 0x0F) as usize) << 8) | (data[1] as usize);
+
+        if len <= SINGLE_FRAME_MAX {
+            return Ok(None);
+        }
+
+        if len > self.rx_buffer.len() {
+            self.rx_state = RxState::Idle;
+            self.flow_control = Some(FS_OVERFLOW);
+            return Err(IsoTpError::BufferOverflow);
+        }
+
+        self.rx_buffer[..FIRST_FRAME_DATA].copy_from_slice(&data[2..8]);
+        self.rx_len = len;
+        self.rx_offset = FIRST_FRAME_DATA;
+        self.rx_seq = 1;
+        self.rx_block = 0;
+        self.rx_state = RxState::Receiving { since: now };
+        self.flow_control = Some(FS_CONTINUE);
+
+        Ok(None)
+    }
+
+    fn on_consecutive_frame(&mut self, data: &[u8], now: u32) -> Result<Option<usize>, IsoTpError> {
+        match self.rx_state {
+            RxState::Receiving { .. } => (),
+            _ => return Ok(None),
+        }
+
+        if (data[0] & 0x0F) != self.rx_seq {
+            self.rx_state = RxState::Idle;
+            return Err(IsoTpError::UnexpectedSequence);
+        }
+
+        let len = cmp::min(CONSECUTIVE_FRAME_DATA, self.rx_len - self.rx_offset);
+
+        if data.len() < (len + 1) {
+            self.rx_state = RxState::Idle;
+            return Err(IsoTpError::InvalidFrame);
+        }
+
+        self.rx_buffer[self.rx_offset..self.rx_offset + len].copy_from_slice(&data[1..len + 1]);
+        self.rx_offset += len;
+        self.rx_seq = (self.rx_seq + 1) & 0x0F;
+
+        if self.rx_offset == self.rx_len {
+            self.rx_state = RxState::Complete;
+            return Ok(Some(self.rx_len));
+        }
+
+        self.rx_state = RxState::Receiving { since: now };
+
+        if self.config.block_size != 0 {
+            self.rx_block += 1;
+
+            if self.rx_block == self.config.block_size {
+                self.rx_block = 0;
+                self.flow_control = Some(FS_CONTINUE);
+            }
+        }
+
+        Ok(None)
+    }
+
+    fn on_flow_control(&mut self, data: &[u8], now: u32) -> Result<(), IsoTpError> {
+        let waits = match self.tx_state {
+            TxState::WaitFlowControl { waits, .. } => waits,
+            _ => return Ok(()),
+        };
+
+        if data.len() < 3 {
+            return Ok(());
+        }
+
+        match data[0] & 0x0F {
+            FS_CONTINUE => {
+                self.tx_block_size = data[1];
+                self.tx_st_min = self.st_min_ticks(data[2]);
+                self.tx_state = TxState::Consecutive {
+                    last: None,
+                    sent: 0,
+                };
+                Ok(())
+            }
+            FS_WAIT => {
+                if waits >= self.config.max_wait_frames {
+                    self.tx_state = TxState::Idle;
+                    return Err(IsoTpError::TooManyWaits);
+                }
+
+                self.tx_state = TxState::WaitFlowControl {
+                    since: now,
+                    waits: waits + 1,
+                };
+                Ok(())
+            }
+            FS_OVERFLOW => {
+                self.tx_state = TxState::Idle;
+                Err(IsoTpError::ReceiverOverflow)
+            }
+            _ => {
+                self.tx_state = TxState::Idle;
+                Err(IsoTpError::InvalidFrame)
+            }
+        }
+    }
+
+    fn frame(&self, bytes: &[u8]) -> DataFrame {
+        let mut frame = DataFrame::new(self.config.tx_id);
+
+        if let Some(padding) = self.config.padding {
+            frame.set_data_length(8);
+
+            for byte in frame.data_as_mut().iter_mut() {
+                *byte = padding;
+            }
+        } else {
+            frame.set_data_length(bytes.len());
+        }
+
+        frame.data_as_mut()[..bytes.len()].copy_from_slice(bytes);
+
+        frame
+    }
+
+    fn st_min_ticks(&self, st_min: u8) -> u32 {
+        let us = match st_min {
+            0x00..=0x7F => u32::from(st_min) * 1000,
+            0xF1..=0xF9 => u32::from(st_min - 0xF0) * 100,
+            // reserved values are treated as the maximum
+            _ => 127_000,
+        };
+
+        self.ticks(u64::from(us))
+    }
+
+    // Saturates at the counter range
+    fn ticks(&self, us: u64) -> u32 {
+        let ticks = u64::from(self.frequency.0).saturating_mul(us) / 1_000_000;
+
+        cmp::min(ticks, u64::from(u32::max_value())) as u32
+    }
+}
+
+/// ISO-TP channel over a CAN interface
+pub struct IsoTpCan<'a, CAN> {
+    can: CAN,
+    isotp: IsoTp<'a>,
+    timer: MonoTimer,
+    // frame the interface didn't accept yet
+    unsent: Option<DataFrame>,
+}
+
+impl<'a, CAN> IsoTpCan<'a, CAN>
+where
+    CAN: Can,
+{
+    /// The state machine must be created with the timer frequency
+    pub fn new(can: CAN, isotp: IsoTp<'a>, timer: MonoTimer) -> Self {
+        IsoTpCan {
+            can,
+            isotp,
+            timer,
+            unsent: None,
+        }
+    }
+
+    /// Starts sending a message, see `poll()`
+    pub fn send(&mut self, data: &[u8]) -> Result<(), IsoTpError> {
+        self.isotp.send(data)
+    }
+
+    /// Returns true while a message is being sent
+    pub fn is_sending(&self) -> bool {
+        self.isotp.is_sending() || self.unsent.is_some()
+    }
+
+    /// Processes the received frames and transmits the pending ones,
+    /// meant to be called periodically or from the CAN interrupts.
+    ///
+    /// Returns a message once it has been completely received.
+    pub fn poll(&mut self) -> Result<Option<&[u8]>, IsoTpError> {
+        let mut complete = false;
+
+        loop {
+            let frame = match self.can.receive() {
+                Ok(f) => f,
+                Err(nb::Error::WouldBlock) => break,
+                Err(nb::Error::Other(_)) => return Err(IsoTpError::Bus),
+            };
+
+            if frame.is_remote_frame() {
+                continue;
+            }
+
+            let now = self.timer.now().ticks();
+
+            if self
+                .isotp
+                .on_frame(from_hal_id(frame.id()), frame.data(), now)?
+                .is_some()
+            {
+                complete = true;
+                break;
+            }
+        }
+
+        self.transmit()?;
+
+        if complete {
+            Ok(self.isotp.received())
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Releases the CAN interface and the state machine
+    pub fn free(self) -> (CAN, IsoTp<'a>) {
+        (self.can, self.isotp)
+    }
+
+    fn transmit(&mut self) -> Result<(), IsoTpError> {
+        loop {
+            let frame = match self.unsent.take() {
+                Some(f) => f,
+                None => match self.isotp.poll(self.timer.now().ticks())? {
+                    Some(f) => f,
+                    None => return Ok(()),
+                },
+            };
+
+            let hal_frame = match CAN::Frame::new(to_hal_id(frame.id()), frame.data()) {
+                Some(f) => f,
+                None => return Err(IsoTpError::InvalidFrame),
+            };
+
+            match self.can.transmit(&hal_frame) {
+                Ok(_) => (),
+                Err(nb::Error::WouldBlock) => {
+                    self.unsent = Some(frame);
+                    return Ok(());
+                }
+                Err(nb::Error::Other(_)) => return Err(IsoTpError::Bus),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use can::BaseID;
+
+    // 1 tick per microsecond
+    const FREQUENCY: Hertz = Hertz(1_000_000);
+
+    fn tester_config() -> IsoTpConfig {
+        IsoTpConfig::new(
+            ID::BaseID(BaseID::new(0x7E0)),
+            ID::BaseID(BaseID::new(0x7E8)),
+        )
+    }
+
+    fn ecu_config() -> IsoTpConfig {
+        IsoTpConfig::new(
+            ID::BaseID(BaseID::new(0x7E8)),
+            ID::BaseID(BaseID::new(0x7E0)),
+        )
+    }
+
+    fn message(len: usize) -> [u8; 256] {
+        let mut data = [0; 256];
+
+        for (i, byte) in data[..len].iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+
+        data
+    }
+
+    // Loops back the frames of both ends until neither has one to send,
+    // returns the length of a message received by `rx`
+    fn exchange(tx: &mut IsoTp, rx: &mut IsoTp, now: u32) -> Option<usize> {
+        let mut received = None;
+
+        loop {
+            let mut idle = true;
+
+            if let Some(frame) = tx.poll(now).unwrap() {
+                idle = false;
+
+                if let Some(len) = rx.on_frame(frame.id(), frame.data(), now).unwrap() {
+                    received = Some(len);
+                }
+            }
+
+            if let Some(frame) = rx.poll(now).unwrap() {
+                idle = false;
+                tx.on_frame(frame.id(), frame.data(), now).unwrap();
+            }
+
+            if idle {
+                return received;
+            }
+        }
+    }
+
+    #[test]
+    fn single_frame() {
+        let (mut tx_buffer, mut rx_buffer) = ([0; 64], [0; 64]);
+        let mut tester = IsoTp::new(tester_config(), &mut tx_buffer, &mut [], FREQUENCY);
+        let mut ecu = IsoTp::new(ecu_config(), &mut [], &mut rx_buffer, FREQUENCY);
+
+        tester.send(&[0x02, 0x01, 0x0C]).unwrap();
+
+        let frame = tester.poll(0).unwrap().unwrap();
+        assert_eq!(frame.data(), &[0x03, 0x02, 0x01, 0x0C]);
+        assert!(!tester.is_sending());
+
+        assert_eq!(ecu.on_frame(frame.id(), frame.data(), 0), Ok(Some(3)));
+        assert_eq!(ecu.received(), Some(&[0x02, 0x01, 0x0C][..]));
+    }
+
+    #[test]
+    fn single_frame_padding() {
+        let mut tx_buffer = [0; 64];
+        let mut config = tester_config();
+        config.padding = Some(0xCC);
+        let mut tester = IsoTp::new(config, &mut tx_buffer, &mut [], FREQUENCY);
+
+        tester.send(&[0x01, 0x00]).unwrap();
+
+        let frame = tester.poll(0).unwrap().unwrap();
+        assert_eq!(
+            frame.data(),
+            &[0x02, 0x01, 0x00, 0xCC, 0xCC, 0xCC, 0xCC, 0xCC]
+        );
+    }
+
+    #[test]
+    fn multi_frame() {
+        let (mut tx_buffer, mut rx_buffer) = ([0; 256], [0; 256]);
+        let mut tester = IsoTp::new(tester_config(), &mut tx_buffer, &mut [], FREQUENCY);
+        let mut ecu = IsoTp::new(ecu_config(), &mut [], &mut rx_buffer, FREQUENCY);
+        let data = message(200);
+
+        tester.send(&data[..200]).unwrap();
+
+        // first frame
+        let frame = tester.poll(0).unwrap().unwrap();
+        assert_eq!(&frame.data()[..3], &[0x10, 200, 0]);
+        assert_eq!(tester.poll(0), Ok(None));
+
+        assert_eq!(ecu.on_frame(frame.id(), frame.data(), 0), Ok(None));
+
+        // 28 consecutive frames, the sequence number wraps around
+        assert_eq!(exchange(&mut tester, &mut ecu, 0), Some(200));
+        assert!(!tester.is_sending());
+        assert_eq!(ecu.received(), Some(&data[..200]));
+    }
+
+    #[test]
+    fn max_size_message() {
+        let (mut tx_buffer, mut rx_buffer) = ([0; MAX_MESSAGE_LEN], [0; MAX_MESSAGE_LEN]);
+        let mut tester = IsoTp::new(tester_config(), &mut tx_buffer, &mut [], FREQUENCY);
+        let mut ecu = IsoTp::new(ecu_config(), &mut [], &mut rx_buffer, FREQUENCY);
+
+        let mut data = [0; MAX_MESSAGE_LEN];
+        for (i, byte) in data.iter_mut().enumerate() {
+            *byte = (i % 251) as u8;
+        }
+
+        tester.send(&data).unwrap();
+
+        // more than 255 consecutive frames with an unlimited block size
+        assert_eq!(exchange(&mut tester, &mut ecu, 0), Some(MAX_MESSAGE_LEN));
+        assert!(!tester.is_sending());
+        assert_eq!(ecu.received(), Some(&data[..]));
+    }
+
+    #[test]
+    fn flow_control_block_size_and_st_min() {
+        let (mut tx_buffer, mut rx_buffer) = ([0; 256], [0; 256]);
+        let mut config = ecu_config();
+        config.block_size = 4;
+        // 10 ms
+        config.st_min = 10;
+        let mut tester = IsoTp::new(tester_config(), &mut tx_buffer, &mut [], FREQUENCY);
+        let mut ecu = IsoTp::new(config, &mut [], &mut rx_buffer, FREQUENCY);
+        let data = message(60);
+
+        tester.send(&data[..60]).unwrap();
+
+        let frame = tester.poll(0).unwrap().unwrap();
+        ecu.on_frame(frame.id(), frame.data(), 0).unwrap();
+
+        let frame = ecu.poll(0).unwrap().unwrap();
+        assert_eq!(frame.data(), &[0x30, 4, 10]);
+        tester.on_frame(frame.id(), frame.data(), 0).unwrap();
+
+        let mut now = 0;
+        let mut consecutive = 0;
+        let mut received = None;
+
+        while received.is_none() {
+            if let Some(frame) = tester.poll(now).unwrap() {
+                consecutive += 1;
+                received = ecu.on_frame(frame.id(), frame.data(), now).unwrap();
+
+                // STmin is kept between consecutive frames
+                assert_eq!(tester.poll(now + 9_999), Ok(None));
+            }
+
+            if let Some(frame) = ecu.poll(now).unwrap() {
+                // a flow control frame after each block of 4
+                assert_eq!(consecutive % 4, 0);
+                tester.on_frame(frame.id(), frame.data(), now).unwrap();
+            }
+
+            now += 10_000;
+        }
+
+        // 6 + 8 * 7 bytes
+        assert_eq!(consecutive, 8);
+        assert_eq!(received, Some(60));
+        assert_eq!(ecu.received(), Some(&data[..60]));
+    }
+
+    #[test]
+    fn flow_control_wait_and_overflow() {
+        let mut tx_buffer = [0; 256];
+        let mut tester = IsoTp::new(tester_config(), &mut tx_buffer, &mut [], FREQUENCY);
+        let ecu_id = ID::BaseID(BaseID::new(0x7E8));
+        let data = message(20);
+
+        tester.send(&data[..20]).unwrap();
+        tester.poll(0).unwrap().unwrap();
+
+        // wait frames restart the timeout, up to max_wait_frames
+        for i in 0..10 {
+            let now = (i + 1) * 500_000;
+            assert_eq!(tester.on_frame(ecu_id, &[0x31, 0, 0], now), Ok(None));
+            assert_eq!(tester.poll(now), Ok(None));
+        }
+
+        assert_eq!(
+            tester.on_frame(ecu_id, &[0x31, 0, 0], 0),
+            Err(IsoTpError::TooManyWaits)
+        );
+        assert!(!tester.is_sending());
+
+        // the receiver doesn't have room for the message
+        let mut rx_buffer = [0; 8];
+        let mut ecu = IsoTp::new(ecu_config(), &mut [], &mut rx_buffer, FREQUENCY);
+
+        tester.send(&data[..20]).unwrap();
+
+        let frame = tester.poll(0).unwrap().unwrap();
+        assert_eq!(
+            ecu.on_frame(frame.id(), frame.data(), 0),
+            Err(IsoTpError::BufferOverflow)
+        );
+
+        let frame = ecu.poll(0).unwrap().unwrap();
+        assert_eq!(frame.data(), &[0x32, 0, 0]);
+        assert_eq!(
+            tester.on_frame(frame.id(), frame.data(), 0),
+            Err(IsoTpError::ReceiverOverflow)
+        );
+        assert!(!tester.is_sending());
+    }
+
+    #[test]
+    fn timeout() {
+        let (mut tx_buffer, mut rx_buffer) = ([0; 256], [0; 256]);
+        let mut tester = IsoTp::new(tester_config(), &mut tx_buffer, &mut [], FREQUENCY);
+        let mut ecu = IsoTp::new(ecu_config(), &mut [], &mut rx_buffer, FREQUENCY);
+        let data = message(20);
+
+        tester.send(&data[..20]).unwrap();
+
+        // no flow control within N_Bs, the counter wraps around meanwhile
+        let start = u32::max_value() - 100;
+        let frame = tester.poll(start).unwrap().unwrap();
+        assert_eq!(tester.poll(start.wrapping_add(999_999)), Ok(None));
+        assert_eq!(
+            tester.poll(start.wrapping_add(1_000_000)),
+            Err(IsoTpError::Timeout)
+        );
+        assert!(!tester.is_sending());
+
+        // no consecutive frame within N_Cr
+        ecu.on_frame(frame.id(), frame.data(), 0).unwrap();
+        ecu.poll(0).unwrap().unwrap();
+        assert_eq!(ecu.poll(999_999), Ok(None));
+        assert_eq!(ecu.poll(1_000_000), Err(IsoTpError::Timeout));
+        assert_eq!(ecu.received(), None);
+    }
+
+    #[test]
+    fn long_timeout_saturates() {
+        let mut tx_buffer = [0; 256];
+        let mut config = tester_config();
+        config.timeout_ms = u32::max_value();
+        let mut tester = IsoTp::new(config, &mut tx_buffer, &mut [], Hertz(216_000_000));
+        let data = message(20);
+
+        tester.send(&data[..20]).unwrap();
+        tester.poll(0).unwrap().unwrap();
+
+        assert_eq!(tester.poll(u32::max_value() - 1), Ok(None));
+        assert!(tester.is_sending());
+    }
+}
@@ -0,0 +1,1579 @@
+//! SAE J1939
+//!
+//! 29 bit identifier layout, address claiming (J1939-81) and the TP.CM/TP.DT
+//! multi-packet transport (J1939-21), both BAM and RTS/CTS.
+//!
+//! `AddressClaim` and `Transport` are peripheral independent state
+//! machines, fed received frames and polled for the frames to transmit with
+//! time given as ticks of a free running 32 bit counter. `J1939Node` drives
+//! both with a CAN interface and a `MonoTimer`.
+//!
+//! Example:
+//! static mut TX_BUFFER: [u8; 256] = [0; 256];
+//! static mut RX_BUFFER: [u8; 256] = [0; 256];
+//!
+//! let claim = AddressClaim::new(Name(0x8000_0000_0000_1234), 0x80, timer.frequency());
+//! let transport = Transport::new(unsafe { &mut TX_BUFFER }, unsafe { &mut RX_BUFFER }, timer.frequency());
+//! let mut node = J1939Node::new(can, claim, transport, timer);
+//!
+//! loop {
+//!     node.poll(|msg, data| { ... })?;
+//! }
+
+use core::cmp;
+use hal::can::nb::Can;
+use hal::can::Frame;
+use nb;
+
+use can::{from_hal_id, to_hal_id, DataFrame, ExtendedID, ID};
+use time::{Hertz, MonoTimer};
+
+/// Request PGN
+pub const PGN_REQUEST: u32 = 0xEA00;
+/// Address claimed PGN
+pub const PGN_ADDRESS_CLAIMED: u32 = 0xEE00;
+/// Transport protocol connection management PGN
+pub const PGN_TP_CM: u32 = 0xEC00;
+/// Transport protocol data transfer PGN
+pub const PGN_TP_DT: u32 = 0xEB00;
+
+/// Destination address of broadcasts
+pub const GLOBAL_ADDRESS: u8 = 0xFF;
+/// Source address of nodes without an address
+pub const NULL_ADDRESS: u8 = 0xFE;
+
+/// Largest message carried by the transport protocol
+pub const MAX_MESSAGE_LEN: usize = 1785;
+
+/// Default priority of control messages
+pub const DEFAULT_PRIORITY: u8 = 6;
+
+// PDU formats below this are destination specific (PDU1)
+const PDU2_FORMAT: u32 = 240;
+
+// first and last address tried by arbitrary address capable nodes
+const DYNAMIC_ADDRESS_FIRST: u8 = 128;
+const DYNAMIC_ADDRESS_LAST: u8 = 247;
+
+// time to wait for contending claims
+const CLAIM_TIMEOUT_MS: u32 = 250;
+
+// TP.CM control bytes
+const CM_RTS: u8 = 16;
+const CM_CTS: u8 = 17;
+const CM_END_OF_MSG_ACK: u8 = 19;
+const CM_BAM: u8 = 32;
+const CM_ABORT: u8 = 255;
+
+// connection abort reasons
+const ABORT_BUSY: u8 = 1;
+const ABORT_RESOURCES: u8 = 2;
+const ABORT_TIMEOUT: u8 = 3;
+const ABORT_BAD_SEQUENCE: u8 = 7;
+
+// transport timing, J1939-21
+const BAM_PACKET_GAP_MS: u32 = 50;
+const T1_MS: u32 = 750;
+const T2_MS: u32 = 1250;
+const T3_MS: u32 = 1250;
+const T4_MS: u32 = 1050;
+
+const TP_PRIORITY: u8 = 7;
+const PACKET_DATA: usize = 7;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum J1939Error {
+    /// A message is already being sent
+    Busy,
+    /// Message is empty, too large for the transmit buffer or above
+    /// `MAX_MESSAGE_LEN`
+    InvalidLength,
+    /// Received message doesn't fit the receive buffer
+    BufferOverflow,
+    /// No frame from the other node in time
+    Timeout,
+    /// Connection aborted by the other node, with the abort reason
+    Aborted(u8),
+    /// Data transfer packet out of sequence
+    UnexpectedSequence,
+    /// No address has been claimed
+    NoAddress,
+    /// The CAN interface reported an error
+    Bus,
+}
+
+/// Fields of a 29 bit identifier
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct J1939Id {
+    /// 0 is the highest priority, 7 the lowest
+    pub priority: u8,
+    /// Parameter group number, 18 bits
+    pub pgn: u32,
+    pub source: u8,
+    /// `GLOBAL_ADDRESS` for PDU2 (broadcast only) PGNs
+    pub destination: u8,
+}
+
+impl J1939Id {
+    pub fn new(priority: u8, pgn: u32, source: u8, destination: u8) -> Self {
+        J1939Id {
+            priority,
+            pgn,
+            source,
+            destination,
+        }
+    }
+
+    /// Returns true if the PGN carries a destination address
+    pub fn is_pdu1(&self) -> bool {
+        is_pdu1(self.pgn)
+    }
+
+    /// Builds the identifier
+    pub fn id(&self) -> ExtendedID {
+        let pgn = if self.is_pdu1() {
+            (self.pgn & 0x3_FF00) | u32::from(self.destination)
+        } else {
+            self.pgn & 0x3_FFFF
+        };
+
+        ExtendedID::new(
+            (u32::from(self.priority & 0x7) << 26) | (pgn << 8) | u32::from(self.source),
+        )
+    }
+}
+
+impl From<ExtendedID> for J1939Id {
+    fn from(id: ExtendedID) -> Self {
+        let id = u32::from(id);
+        let pgn = (id >> 8) & 0x3_FFFF;
+
+        let (pgn, destination) = if is_pdu1(pgn) {
+            (pgn & 0x3_FF00, (pgn & 0xFF) as u8)
+        } else {
+            (pgn, GLOBAL_ADDRESS)
+        };
+
+        J1939Id {
+            priority: ((id >> 26) & 0x7) as u8,
+            pgn,
+            source: id as u8,
+            destination,
+        }
+    }
+}
+
+impl From<J1939Id> for ExtendedID {
+    fn from(id: J1939Id) -> Self {
+        id.id()
+    }
+}
+
+fn is_pdu1(pgn: u32) -> bool {
+    ((pgn >> 8) & 0xFF) < PDU2_FORMAT
+}
+
+/// Builds a data frame, `data` is at most 8 bytes
+pub fn frame(id: &J1939Id, data: &[u8]) -> DataFrame {
+    let mut frame = DataFrame::new(ID::ExtendedID(id.id()));
+
+    frame.set_data_length(data.len());
+    frame.data_as_mut().copy_from_slice(data);
+
+    frame
+}
+
+/// 64 bit NAME, the lower value wins address arbitration
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Name(pub u64);
+
+impl Name {
+    /// Returns true if the node can pick another address when it loses
+    /// arbitration
+    pub fn arbitrary_address_capable(&self) -> bool {
+        (self.0 >> 63) != 0
+    }
+
+    fn to_bytes(&self) -> [u8; 8] {
+        let mut bytes = [0; 8];
+
+        for (index, byte) in bytes.iter_mut().enumerate() {
+            *byte = (self.0 >> (8 * index)) as u8;
+        }
+
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        Name(
+            bytes
+                .iter()
+                .take(8)
+                .enumerate()
+                .fold(0, |name, (index, byte)| {
+                    name | (u64::from(*byte) << (8 * index))
+                }),
+        )
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AddressState {
+    /// Claim sent, waiting for contending claims
+    Claiming,
+    /// The address is ours
+    Claimed,
+    /// No address could be claimed
+    Failed,
+}
+
+/// Address claim procedure
+pub struct AddressClaim {
+    name: Name,
+    address: u8,
+    state: AddressState,
+    frequency: Hertz,
+    // when the claim was sent
+    since: Option<u32>,
+    // a claim (or cannot claim) message needs to be sent
+    pending: bool,
+    // addresses claimed by other nodes
+    claimed: [u32; 8],
+}
+
+impl AddressClaim {
+    /// Starts claiming `preferred`, `frequency` is the tick rate of the
+    /// time values
+    pub fn new(name: Name, preferred: u8, frequency: Hertz) -> Self {
+        AddressClaim {
+            name,
+            address: preferred,
+            state: AddressState::Claiming,
+            frequency,
+            since: None,
+            pending: true,
+            claimed: [0; 8],
+        }
+    }
+
+    pub fn name(&self) -> Name {
+        self.name
+    }
+
+    pub fn state(&self) -> AddressState {
+        self.state
+    }
+
+    /// Returns the address once claimed
+    pub fn address(&self) -> Option<u8> {
+        match self.state {
+            AddressState::Claimed => Some(self.address),
+            _ => None,
+        }
+    }
+
+    /// Processes address claims and requests for them
+    pub fn on_frame(&mut self, id: &J1939Id, data: &[u8], now: u32) {
+        match id.pgn {
+            PGN_ADDRESS_CLAIMED if data.len() >= 8 => self.on_claim(id.source, data, now),
+            PGN_REQUEST if data.len() >= 3 => {
+                let requested =
+                    u32::from(data[0]) | (u32::from(data[1]) << 8) | (u32::from(data[2]) << 16);
+
+                if (requested == PGN_ADDRESS_CLAIMED)
+                    && ((id.destination == GLOBAL_ADDRESS) || (id.destination == self.address))
+                {
+                    self.pending = true;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Returns the next claim message to transmit
+    pub fn poll(&mut self, now: u32) -> Option<DataFrame> {
+        if self.pending {
+            self.pending = false;
+
+            if self.state == AddressState::Claiming {
+                self.since = Some(now);
+            }
+
+            let source = match self.state {
+                AddressState::Failed => NULL_ADDRESS,
+                _ => self.address,
+            };
+
+            let id = J1939Id::new(
+                DEFAULT_PRIORITY,
+                PGN_ADDRESS_CLAIMED,
+                source,
+                GLOBAL_ADDRESS,
+            );
+
+            return Some(frame(&id, &self.name.to_bytes()));
+        }
+
+        if let (AddressState::Claiming, Some(since)) = (self.state, self.since) {
+            if now.wrapping_sub(since) >= ticks(self.frequency, CLAIM_TIMEOUT_MS) {
+                self.state = AddressState::Claimed;
+            }
+        }
+
+        None
+    }
+
+    fn on_claim(&mut self, source: u8, data: &[u8], now: u32) {
+        let name = Name::from_bytes(data);
+
+        if source < NULL_ADDRESS {
+            self.claimed[(source / 32) as usize] |= 1 << (source % 32);
+        }
+
+        if (source != self.address) || (name == self.name) || (self.state == AddressState::Failed) {
+            return;
+        }
+
+        if self.name < name {
+            // we win, defend the address
+            self.pending = true;
+            return;
+        }
+
+        self.state = AddressState::Failed;
+        self.pending = true;
+
+        if self.name.arbitrary_address_capable() {
+            let free = (DYNAMIC_ADDRESS_FIRST..=DYNAMIC_ADDRESS_LAST)
+                .find(|a| (self.claimed[(a / 32) as usize] & (1 << (a % 32))) == 0);
+
+            if let Some(address) = free {
+                self.address = address;
+                self.state = AddressState::Claiming;
+                self.since = Some(now);
+            }
+        }
+    }
+}
+
+/// Received message
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Message {
+    pub pgn: u32,
+    pub source: u8,
+    pub destination: u8,
+    pub len: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum TxState {
+    Idle,
+    // single frame, BAM or RTS is next
+    Start,
+    Broadcast { last: u32 },
+    WaitCts { since: u32, hold: bool },
+    Sending { remaining: u8 },
+    WaitEndOfMsgAck { since: u32 },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum RxState {
+    Idle,
+    Broadcast { since: u32 },
+    Connection { since: u32, remaining: u8 },
+    Complete,
+}
+
+/// Multi-packet transport, one transmit and one receive session
+pub struct Transport<'a> {
+    address: u8,
+    frequency: Hertz,
+    tx_buffer: &'a mut [u8],
+    tx_state: TxState,
+    tx_id: J1939Id,
+    tx_len: usize,
+    // 1 based, one past the last packet once all were sent
+    tx_next: u16,
+    tx_control: Option<DataFrame>,
+    rx_buffer: &'a mut [u8],
+    rx_state: RxState,
+    rx_message: Message,
+    rx_next: u8,
+    // packets per CTS requested by the sender
+    rx_max_packets: u8,
+    rx_control: Option<DataFrame>,
+}
+
+impl<'a> Transport<'a> {
+    /// Messages are limited to the length of the buffers, `frequency` is
+    /// the tick rate of the time values
+    pub fn new(tx_buffer: &'a mut [u8], rx_buffer: &'a mut [u8], frequency: Hertz) -> Self {
+        Transport {
+            address: NULL_ADDRESS,
+            frequency,
+            tx_buffer,
+            tx_state: TxState::Idle,
+            tx_id: J1939Id::new(0, 0, NULL_ADDRESS, GLOBAL_ADDRESS),
+            tx_len: 0,
+            tx_next: 0,
+            tx_control: None,
+            rx_buffer,
+            rx_state: RxState::Idle,
+            rx_message: Message {
+                pgn: 0,
+                source: NULL_ADDRESS,
+                destination: GLOBAL_ADDRESS,
+                len: 0,
+            },
+            rx_next: 0,
+            rx_max_packets: 0,
+            rx_control: None,
+        }
+    }
+
+    /// Sets the address of the node, sessions in progress are dropped
+    pub fn set_address(&mut self, address: u8) {
+        self.address = address;
+        self.tx_state = TxState::Idle;
+        self.rx_state = RxState::Idle;
+        self.tx_control = None;
+        self.rx_control = None;
+    }
+
+    /// Starts sending a message, up to 8 bytes go out in a single frame,
+    /// larger messages use BAM for `GLOBAL_ADDRESS` and RTS/CTS otherwise
+    pub fn send(
+        &mut self,
+        priority: u8,
+        pgn: u32,
+        destination: u8,
+        data: &[u8],
+    ) -> Result<(), J1939Error> {
+        if self.address >= NULL_ADDRESS {
+            return Err(J1939Error::NoAddress);
+        }
+
+        if self.tx_state != TxState::Idle {
+            return Err(J1939Error::Busy);
+        }
+
+        if data.is_empty() || (data.len() > MAX_MESSAGE_LEN) || (data.len() > self.tx_buffer.len())
+        {
+            return Err(J1939Error::InvalidLength);
+        }
+
+        self.tx_buffer[..data.len()].copy_from_slice(data);
+        self.tx_id = J1939Id::new(priority, pgn, self.address, destination);
+        self.tx_len = data.len();
+        self.tx_next = 1;
+        self.tx_state = TxState::Start;
+
+        Ok(())
+    }
+
+    /// Returns true while a message is being sent
+    pub fn is_sending(&self) -> bool {
+        self.tx_state != TxState::Idle
+    }
+
+    /// Returns the last completely received message and its data
+    pub fn received(&self) -> Option<(Message, &[u8])> {
+        match self.rx_state {
+            RxState::Complete => Some((self.rx_message, &self.rx_buffer[..self.rx_message.len])),
+            _ => None,
+        }
+    }
+
+    /// Processes TP.CM and TP.DT frames, other frames are ignored.
+    ///
+    /// Returns the message once it has been received, see `received()`.
+    pub fn on_frame(
+        &mut self,
+        id: &J1939Id,
+        data: &[u8],
+        now: u32,
+    ) -> Result<Option<Message>, J1939Error> {
+        if (id.destination != GLOBAL_ADDRESS) && (id.destination != self.address) {
+            return Ok(None);
+        }
+
+        if data.len() < 8 {
+            return Ok(None);
+        }
+
+        match id.pgn {
+            PGN_TP_CM => self.on_connection_management(id, data, now),
+            PGN_TP_DT => self.on_data_transfer(id, data, now),
+            _ => Ok(None),
+        }
+    }
+
+    /// Checks the timeouts and returns the next frame to transmit.
+    ///
+    /// A timeout aborts the session it belongs to.
+    pub fn poll(&mut self, now: u32) -> Result<Option<DataFrame>, J1939Error> {
+        match self.rx_state {
+            RxState::Broadcast { since } if self.elapsed(since, now, T1_MS) => {
+                self.rx_state = RxState::Idle;
+                return Err(J1939Error::Timeout);
+            }
+            RxState::Connection { since, .. } if self.elapsed(since, now, T2_MS) => {
+                let source = self.rx_message.source;
+                let pgn = self.rx_message.pgn;
+
+                self.rx_state = RxState::Idle;
+                self.rx_control = Some(self.abort_frame(source, pgn, ABORT_TIMEOUT));
+                return Err(J1939Error::Timeout);
+            }
+            _ => (),
+        }
+
+        if let Some(frame) = self.rx_control.take() {
+            return Ok(Some(frame));
+        }
+
+        if let Some(frame) = self.tx_control.take() {
+            return Ok(Some(frame));
+        }
+
+        match self.tx_state {
+            TxState::Idle => Ok(None),
+            TxState::Start => Ok(Some(self.start_frame(now))),
+            TxState::Broadcast { last } => {
+                if self.elapsed(last, now, BAM_PACKET_GAP_MS) {
+                    let frame = self.data_frame(GLOBAL_ADDRESS);
+
+                    self.tx_state = if self.tx_next > self.tx_packets() {
+                        TxState::Idle
+                    } else {
+                        TxState::Broadcast { last: now }
+                    };
+
+                    Ok(Some(frame))
+                } else {
+                    Ok(None)
+                }
+            }
+            TxState::WaitCts { since, hold } => {
+                let timeout = if hold { T4_MS } else { T3_MS };
+
+                if self.elapsed(since, now, timeout) {
+                    let (destination, pgn) = (self.tx_id.destination, self.tx_id.pgn);
+
+                    self.tx_state = TxState::Idle;
+                    self.tx_control = Some(self.abort_frame(destination, pgn, ABORT_TIMEOUT));
+                    Err(J1939Error::Timeout)
+                } else {
+                    Ok(None)
+                }
+            }
+            TxState::Sending { remaining } => {
+                let frame = self.data_frame(self.tx_id.destination);
+                let remaining = remaining - 1;
+
+                self.tx_state = if self.tx_next > self.tx_packets() {
+                    TxState::WaitEndOfMsgAck { since: now }
+                } else if remaining == 0 {
+                    TxState::WaitCts {
+                        since: now,
+                        hold: false,
+                    }
+                } else {
+                    TxState::Sending { remaining }
+                };
+
+                Ok(Some(frame))
+            }
+            TxState::WaitEndOfMsgAck { since } => {
+                if self.elapsed(since, now, T3_MS) {
+                    self.tx_state = TxState::Idle;
+                    Err(J1939Error::Timeout)
+                } else {
+                    Ok(None)
+                }
+            }
+        }
+    }
+
+    fn start_frame(&mut self, now: u32) -> DataFrame {
+        if self.tx_len <= 8 {
+            self.tx_state = TxState::Idle;
+            return frame(&self.tx_id, &self.tx_buffer[..self.tx_len]);
+        }
+
+        let (control, max_packets, destination) = if self.tx_id.destination == GLOBAL_ADDRESS {
+            self.tx_state = TxState::Broadcast { last: now };
+            (CM_BAM, 0xFF, GLOBAL_ADDRESS)
+        } else {
+            self.tx_state = TxState::WaitCts {
+                since: now,
+                hold: false,
+            };
+            (CM_RTS, 0xFF, self.tx_id.destination)
+        };
+
+        let (len, packets) = (self.tx_len, packets(self.tx_len));
+        let pgn = self.tx_id.pgn;
+
+        self.control_frame(
+            destination,
+            &[
+                control,
+                len as u8,
+                (len >> 8) as u8,
+                packets,
+                max_packets,
+                pgn as u8,
+                (pgn >> 8) as u8,
+                (pgn >> 16) as u8,
+            ],
+        )
+    }
+
+    fn on_connection_management(
+        &mut self,
+        id: &J1939Id,
+        data: &[u8],
+        now: u32,
+    ) -> Result<Option<Message>, J1939Error> {
+        let pgn = u32::from(data[5]) | (u32::from(data[6]) << 8) | (u32::from(data[7]) << 16);
+        let len = (data[1] as usize) | ((data[2] as usize) << 8);
+
+        match data[0] {
+            CM_BAM if id.destination == GLOBAL_ADDRESS => {
+                if (len > self.rx_buffer.len()) || (len > MAX_MESSAGE_LEN) {
+                    return Err(J1939Error::BufferOverflow);
+                }
+
+                self.start_receive(id, pgn, len);
+                self.rx_state = RxState::Broadcast { since: now };
+
+                Ok(None)
+            }
+            CM_RTS if id.destination == self.address => {
+                if let RxState::Connection { .. } = self.rx_state {
+                    if self.rx_message.source != id.source {
+                        self.rx_control = Some(self.abort_frame(id.source, pgn, ABORT_BUSY));
+                        return Ok(None);
+                    }
+                }
+
+                if (len > self.rx_buffer.len()) || (len > MAX_MESSAGE_LEN) {
+                    self.rx_state = RxState::Idle;
+                    self.rx_control = Some(self.abort_frame(id.source, pgn, ABORT_RESOURCES));
+                    return Err(J1939Error::BufferOverflow);
+                }
+
+                self.start_receive(id, pgn, len);
+                self.rx_max_packets = data[4];
+                self.clear_to_send(now);
+
+                Ok(None)
+            }
+            CM_CTS if self.is_tx_peer(id, pgn) => {
+                match self.tx_state {
+                    TxState::WaitCts { .. } | TxState::Sending { .. } => (),
+                    _ => return Ok(None),
+                }
+
+                let next = u16::from(cmp::max(data[2], 1));
+
+                if data[1] == 0 {
+                    self.tx_state = TxState::WaitCts {
+                        since: now,
+                        hold: true,
+                    };
+                } else if next > self.tx_packets() {
+                    // next packet out of range, ignored
+                    return Ok(None);
+                } else {
+                    self.tx_next = next;
+                    self.tx_state = TxState::Sending { remaining: data[1] };
+                }
+
+                Ok(None)
+            }
+            CM_END_OF_MSG_ACK if self.is_tx_peer(id, pgn) => {
+                if let TxState::WaitEndOfMsgAck { .. } = self.tx_state {
+                    self.tx_state = TxState::Idle;
+                }
+
+                Ok(None)
+            }
+            CM_ABORT => {
+                if self.is_tx_peer(id, pgn) && (self.tx_state != TxState::Idle) {
+                    self.tx_state = TxState::Idle;
+                    return Err(J1939Error::Aborted(data[1]));
+                }
+
+                if let RxState::Connection { .. } = self.rx_state {
+                    if (self.rx_message.source == id.source) && (self.rx_message.pgn == pgn) {
+                        self.rx_state = RxState::Idle;
+                        return Err(J1939Error::Aborted(data[1]));
+                    }
+                }
+
+                Ok(None)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn on_data_transfer(
+        &mut self,
+        id: &J1939Id,
+        data: &[u8],
+        now: u32,
+    ) -> Result<Option<Message>, J1939Error> {
+        let connection = match self.rx_state {
+            RxState::Broadcast { .. } => false,
+            RxState::Connection { .. } => true,
+            _ => return Ok(None),
+        };
+
+        if (id.source != self.rx_message.source)
+            || (connection && (id.destination != self.address))
+            || (!connection && (id.destination != GLOBAL_ADDRESS))
+        {
+            return Ok(None);
+        }
+
+        if data[0] != self.rx_next {
+            let (source, pgn) = (self.rx_message.source, self.rx_message.pgn);
+
+            if connection {
+                self.rx_control = Some(self.abort_frame(source, pgn, ABORT_BAD_SEQUENCE));
+            }
+
+            self.rx_state = RxState::Idle;
+            return Err(J1939Error::UnexpectedSequence);
+        }
+
+        let offset = (data[0] as usize - 1) * PACKET_DATA;
+        let len = cmp::min(PACKET_DATA, self.rx_message.len - offset);
+
+        self.rx_buffer[offset..offset + len].copy_from_slice(&data[1..len + 1]);
+        self.rx_next = self.rx_next.wrapping_add(1);
+
+        if (offset + len) == self.rx_message.len {
+            self.rx_state = RxState::Complete;
+
+            if connection {
+                let message = self.rx_message;
+                let packets = packets(message.len);
+
+                self.rx_control = Some(self.control_frame(
+                    message.source,
+                    &[
+                        CM_END_OF_MSG_ACK,
+                        message.len as u8,
+                        (message.len >> 8) as u8,
+                        packets,
+                        0xFF,
+                        message.pgn as u8,
+                        (message.pgn >> 8) as u8,
+                        (message.pgn >> 16) as u8,
+                    ],
+                ));
+            }
+
+            return Ok(Some(self.rx_message));
+        }
+
+        match self.rx_state {
+            RxState::Connection { remaining, .. } if remaining > 1 => {
+                self.rx_state = RxState::Connection {
+                    since: now,
+                    remaining: remaining - 1,
+                };
+            }
+            // block complete
+            RxState::Connection { .. } => self.clear_to_send(now),
+            _ => self.rx_state = RxState::Broadcast { since: now },
+        }
+
+        Ok(None)
+    }
+
+    fn start_receive(&mut self, id: &J1939Id, pgn: u32, len: usize) {
+        self.rx_message = Message {
+            pgn,
+            source: id.source,
+            destination: id.destination,
+            len,
+        };
+        self.rx_next = 1;
+    }
+
+    // Grants the next block of packets
+    fn clear_to_send(&mut self, now: u32) {
+        let message = self.rx_message;
+        let left = packets(message.len) - (self.rx_next - 1);
+        // 0xFF is no limit
+        let count = cmp::min(left, cmp::max(self.rx_max_packets, 1));
+
+        self.rx_control = Some(self.control_frame(
+            message.source,
+            &[
+                CM_CTS,
+                count,
+                self.rx_next,
+                0xFF,
+                0xFF,
+                message.pgn as u8,
+                (message.pgn >> 8) as u8,
+                (message.pgn >> 16) as u8,
+            ],
+        ));
+        self.rx_state = RxState::Connection {
+            since: now,
+            remaining: count,
+        };
+    }
+
+    fn is_tx_peer(&self, id: &J1939Id, pgn: u32) -> bool {
+        (id.source == self.tx_id.destination) && (pgn == self.tx_id.pgn)
+    }
+
+    fn tx_packets(&self) -> u16 {
+        u16::from(packets(self.tx_len))
+    }
+
+    fn data_frame(&mut self, destination: u8) -> DataFrame {
+        let offset = (self.tx_next as usize - 1) * PACKET_DATA;
+        let len = cmp::min(PACKET_DATA, self.tx_len - offset);
+        let mut bytes = [0xFF; 8];
+
+        bytes[0] = self.tx_next as u8;
+        bytes[1..len + 1].copy_from_slice(&self.tx_buffer[offset..offset + len]);
+
+        self.tx_next += 1;
+
+        let id = J1939Id::new(TP_PRIORITY, PGN_TP_DT, self.address, destination);
+
+        frame(&id, &bytes)
+    }
+
+    fn control_frame(&self, destination: u8, bytes: &[u8; 8]) -> DataFrame {
+        let id = J1939Id::new(TP_PRIORITY, PGN_TP_CM, self.address, destination);
+
+        frame(&id, bytes)
+    }
+
+    fn abort_frame(&self, destination: u8, pgn: u32, reason: u8) -> DataFrame {
+        self.control_frame(
+            destination,
+            &[
+                CM_ABORT,
+                reason,
+                0xFF,
+                0xFF,
+                0xFF,
+                pgn as u8,
+                (pgn >> 8) as u8,
+                (pgn >> 16) as u8,
+            ],
+        )
+    }
+
+    fn elapsed(&self, since: u32, now: u32, ms: u32) -> bool {
+        now.wrapping_sub(since) >= ticks(self.frequency, ms)
+    }
+}
+
+/// J1939 node over a CAN interface
+pub struct J1939Node<'a, CAN>
+where
+    CAN: Can,
+{
+    can: CAN,
+    claim: AddressClaim,
+    transport: Transport<'a>,
+    timer: MonoTimer,
+    // frame the interface didn't accept yet
+    unsent: Option<DataFrame>,
+    // pending lower priority frame the interface gave back, it may not be
+    // one of ours so it is sent again unchanged
+    replaced: Option<CAN::Frame>,
+}
+
+impl<'a, CAN> J1939Node<'a, CAN>
+where
+    CAN: Can,
+{
+    /// The state machines must be created with the timer frequency
+    pub fn new(can: CAN, claim: AddressClaim, transport: Transport<'a>, timer: MonoTimer) -> Self {
+        J1939Node {
+            can,
+            claim,
+            transport,
+            timer,
+            unsent: None,
+            replaced: None,
+        }
+    }
+
+    /// Returns the claimed address
+    pub fn address(&self) -> Option<u8> {
+        self.claim.address()
+    }
+
+    /// Starts sending a message, see `Transport::send()`
+    pub fn send(
+        &mut self,
+        priority: u8,
+        pgn: u32,
+        destination: u8,
+        data: &[u8],
+    ) -> Result<(), J1939Error> {
+        if self.claim.address().is_none() {
+            return Err(J1939Error::NoAddress);
+        }
+
+        self.transport.send(priority, pgn, destination, data)
+    }
+
+    /// Returns true while a message is being sent
+    pub fn is_sending(&self) -> bool {
+        self.transport.is_sending() || self.unsent.is_some() || self.replaced.is_some()
+    }
+
+    /// Processes the received frames and transmits the pending ones, meant
+    /// to be called periodically or from the CAN interrupts.
+    ///
+    /// Received single frame and multi-packet messages addressed to this
+    /// node, or broadcast, are passed to `on_message`.
+    pub fn poll<F>(&mut self, mut on_message: F) -> Result<(), J1939Error>
+    where
+        F: FnMut(&Message, &[u8]),
+    {
+        loop {
+            let frame = match self.can.receive() {
+                Ok(f) => f,
+                Err(nb::Error::WouldBlock) => break,
+                Err(nb::Error::Other(_)) => return Err(J1939Error::Bus),
+            };
+
+            let id = match from_hal_id(frame.id()) {
+                ID::ExtendedID(id) if !frame.is_remote_frame() => J1939Id::from(id),
+                _ => continue,
+            };
+
+            let now = self.timer.now().ticks();
+            let address = self.claim.address();
+
+            self.claim.on_frame(&id, frame.data(), now);
+
+            if self.claim.address() != address {
+                self.transport
+                    .set_address(self.claim.address().unwrap_or(NULL_ADDRESS));
+            }
+
+            match id.pgn {
+                PGN_TP_CM | PGN_TP_DT => {
+                    if self.transport.on_frame(&id, frame.data(), now)?.is_some() {
+                        if let Some((message, data)) = self.transport.received() {
+                            on_message(&message, data);
+                        }
+                    }
+                }
+                _ => {
+                    let addressed = (id.destination == GLOBAL_ADDRESS)
+                        || (Some(id.destination) == self.claim.address());
+
+                    if addressed {
+                        let message = Message {
+                            pgn: id.pgn,
+                            source: id.source,
+                            destination: id.destination,
+                            len: frame.data().len(),
+                        };
+
+                        on_message(&message, frame.data());
+                    }
+                }
+            }
+        }
+
+        self.transmit()
+    }
+
+    /// Releases the CAN interface and the state machines
+    pub fn free(self) -> (CAN, AddressClaim, Transport<'a>) {
+        (self.can, self.claim, self.transport)
+    }
+
+    fn transmit(&mut self) -> Result<(), J1939Error> {
+        loop {
+            // a replaced frame goes first, it was pending before ours
+            if let Some(replaced) = self.replaced.take() {
+                match self.can.transmit(&replaced) {
+                    Ok(r) => self.replaced = r,
+                    Err(nb::Error::WouldBlock) => {
+                        self.replaced = Some(replaced);
+                        return Ok(());
+                    }
+                    Err(nb::Error::Other(_)) => return Err(J1939Error::Bus),
+                }
+
+                continue;
+            }
+
+            let frame = match self.unsent.take() {
+                Some(f) => f,
+                None => {
+                    let now = self.timer.now().ticks();
+                    let address = self.claim.address();
+
+                    match self.claim.poll(now) {
+                        Some(f) => f,
+                        None => {
+                            if self.claim.address() != address {
+                                self.transport
+                                    .set_address(self.claim.address().unwrap_or(NULL_ADDRESS));
+                            }
+
+                            match self.transport.poll(now)? {
+                                Some(f) => f,
+                                None => return Ok(()),
+                            }
+                        }
+                    }
+                }
+            };
+
+            let hal_frame = match CAN::Frame::new(to_hal_id(frame.id()), frame.data()) {
+                Some(f) => f,
+                None => return Err(J1939Error::Bus),
+            };
+
+            match self.can.transmit(&hal_frame) {
+                // a pending lower priority frame may have been replaced
+                Ok(replaced) => self.replaced = replaced,
+                Err(nb::Error::WouldBlock) => {
+                    self.unsent = Some(frame);
+                    return Ok(());
+                }
+                Err(nb::Error::Other(_)) => return Err(J1939Error::Bus),
+            }
+        }
+    }
+}
+
+fn packets(len: usize) -> u8 {
+    ((len + PACKET_DATA - 1) / PACKET_DATA) as u8
+}
+
+// Saturates at the counter range
+fn ticks(frequency: Hertz, ms: u32) -> u32 {
+    let ticks = (u64::from(frequency.0) * u64::from(ms)) / 1000;
+
+    cmp::min(ticks, u64::from(u32::max_value())) as u32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 1 tick per millisecond
+    const FREQUENCY: Hertz = Hertz(1000);
+
+    const SENDER: u8 = 0x10;
+    const RECEIVER: u8 = 0x20;
+    const PGN: u32 = 0xFECA;
+
+    fn message(len: usize) -> [u8; MAX_MESSAGE_LEN] {
+        let mut data = [0; MAX_MESSAGE_LEN];
+
+        for (i, byte) in data[..len].iter_mut().enumerate() {
+            *byte = (i % 251) as u8;
+        }
+
+        data
+    }
+
+    fn j1939_id(frame: &DataFrame) -> J1939Id {
+        match frame.id() {
+            ID::ExtendedID(id) => J1939Id::from(id),
+            ID::BaseID(_) => panic!("standard frame"),
+        }
+    }
+
+    fn deliver(
+        to: &mut Transport,
+        frame: &DataFrame,
+        now: u32,
+    ) -> Result<Option<Message>, J1939Error> {
+        to.on_frame(&j1939_id(frame), frame.data(), now)
+    }
+
+    // Address claimed frame of another node
+    fn claim_of(source: u8, name: Name) -> DataFrame {
+        let id = J1939Id::new(
+            DEFAULT_PRIORITY,
+            PGN_ADDRESS_CLAIMED,
+            source,
+            GLOBAL_ADDRESS,
+        );
+
+        frame(&id, &name.to_bytes())
+    }
+
+    fn deliver_claim(to: &mut AddressClaim, frame: &DataFrame, now: u32) {
+        to.on_frame(&j1939_id(frame), frame.data(), now)
+    }
+
+    // Checks that `frame` claims `source` with `name`
+    fn assert_claim(frame: &DataFrame, source: u8, name: Name) {
+        let id = j1939_id(frame);
+
+        assert_eq!(id.pgn, PGN_ADDRESS_CLAIMED);
+        assert_eq!(id.source, source);
+        assert_eq!(id.destination, GLOBAL_ADDRESS);
+        assert_eq!(Name::from_bytes(frame.data()), name);
+    }
+
+    // TP.CM frame from `source` to `destination`
+    fn control(source: u8, destination: u8, bytes: &[u8; 5]) -> DataFrame {
+        let id = J1939Id::new(TP_PRIORITY, PGN_TP_CM, source, destination);
+        let pgn = [PGN as u8, (PGN >> 8) as u8, (PGN >> 16) as u8];
+
+        frame(
+            &id,
+            &[
+                bytes[0], bytes[1], bytes[2], bytes[3], bytes[4], pgn[0], pgn[1], pgn[2],
+            ],
+        )
+    }
+
+    // TP.DT frame with the given sequence number
+    fn data_packet(source: u8, destination: u8, sequence: u8) -> DataFrame {
+        let id = J1939Id::new(TP_PRIORITY, PGN_TP_DT, source, destination);
+
+        frame(&id, &[sequence, 0, 0, 0, 0, 0, 0, 0])
+    }
+
+    // Loops back the frames of both nodes until the sender is done,
+    // returns the message received by `rx`
+    fn exchange(tx: &mut Transport, rx: &mut Transport, now: &mut u32) -> Option<Message> {
+        let mut received = None;
+
+        for _ in 0..10_000 {
+            if let Some(frame) = tx.poll(*now).unwrap() {
+                if let Some(message) = deliver(rx, &frame, *now).unwrap() {
+                    received = Some(message);
+                }
+            }
+
+            if let Some(frame) = rx.poll(*now).unwrap() {
+                deliver(tx, &frame, *now).unwrap();
+            }
+
+            if !tx.is_sending() {
+                break;
+            }
+
+            *now += BAM_PACKET_GAP_MS;
+        }
+
+        received
+    }
+
+    fn transports<'a>(
+        tx_buffer: &'a mut [u8],
+        rx_buffer: &'a mut [u8],
+    ) -> (Transport<'a>, Transport<'a>) {
+        let mut tx = Transport::new(tx_buffer, &mut [], FREQUENCY);
+        let mut rx = Transport::new(&mut [], rx_buffer, FREQUENCY);
+
+        tx.set_address(SENDER);
+        rx.set_address(RECEIVER);
+
+        (tx, rx)
+    }
+
+    #[test]
+    fn broadcast() {
+        let (mut tx_buffer, mut rx_buffer) = ([0; 64], [0; 64]);
+        let (mut tx, mut rx) = transports(&mut tx_buffer, &mut rx_buffer);
+        let data = message(20);
+
+        tx.send(DEFAULT_PRIORITY, PGN, GLOBAL_ADDRESS, &data[..20])
+            .unwrap();
+
+        let frame = tx.poll(0).unwrap().unwrap();
+        assert_eq!(frame.data(), &[CM_BAM, 20, 0, 3, 0xFF, 0xCA, 0xFE, 0x00]);
+        assert_eq!(deliver(&mut rx, &frame, 0), Ok(None));
+
+        // packets are spaced by the BAM gap
+        assert_eq!(tx.poll(BAM_PACKET_GAP_MS - 1), Ok(None));
+
+        let frame = tx.poll(BAM_PACKET_GAP_MS).unwrap().unwrap();
+        assert_eq!(frame.data(), &[1, 0, 1, 2, 3, 4, 5, 6]);
+        assert_eq!(deliver(&mut rx, &frame, BAM_PACKET_GAP_MS), Ok(None));
+
+        let mut now = BAM_PACKET_GAP_MS;
+        let message = exchange(&mut tx, &mut rx, &mut now).unwrap();
+
+        assert_eq!(
+            message,
+            Message {
+                pgn: PGN,
+                source: SENDER,
+                destination: GLOBAL_ADDRESS,
+                len: 20,
+            }
+        );
+        assert_eq!(rx.received().unwrap().1, &data[..20]);
+    }
+
+    #[test]
+    fn broadcast_too_long() {
+        let mut rx_buffer = [0; 2048];
+        let mut rx = Transport::new(&mut [], &mut rx_buffer, FREQUENCY);
+        let len = MAX_MESSAGE_LEN + 1;
+
+        rx.set_address(RECEIVER);
+
+        let frame = control(
+            SENDER,
+            GLOBAL_ADDRESS,
+            &[CM_BAM, len as u8, (len >> 8) as u8, 0xFF, 0xFF],
+        );
+        assert_eq!(deliver(&mut rx, &frame, 0), Err(J1939Error::BufferOverflow));
+
+        // no session was started
+        let frame = data_packet(SENDER, GLOBAL_ADDRESS, 1);
+        assert_eq!(deliver(&mut rx, &frame, 0), Ok(None));
+    }
+
+    #[test]
+    fn connection_with_cts_hold() {
+        let (mut tx_buffer, mut rx_buffer) = ([0; 64], [0; 64]);
+        let (mut tx, mut rx) = transports(&mut tx_buffer, &mut rx_buffer);
+        let data = message(30);
+
+        tx.send(DEFAULT_PRIORITY, PGN, RECEIVER, &data[..30])
+            .unwrap();
+
+        let rts = tx.poll(0).unwrap().unwrap();
+        assert_eq!(rts.data(), &[CM_RTS, 30, 0, 5, 0xFF, 0xCA, 0xFE, 0x00]);
+
+        // the receiver holds the connection open
+        let hold = control(RECEIVER, SENDER, &[CM_CTS, 0, 0xFF, 0xFF, 0xFF]);
+        assert_eq!(deliver(&mut tx, &hold, 0), Ok(None));
+        assert_eq!(tx.poll(T4_MS - 1), Ok(None));
+
+        assert_eq!(deliver(&mut rx, &rts, 1000), Ok(None));
+
+        let cts = rx.poll(1000).unwrap().unwrap();
+        assert_eq!(cts.data(), &[CM_CTS, 5, 1, 0xFF, 0xFF, 0xCA, 0xFE, 0x00]);
+        assert_eq!(deliver(&mut tx, &cts, 1000), Ok(None));
+
+        let mut now = 1000;
+        let message = exchange(&mut tx, &mut rx, &mut now).unwrap();
+
+        assert_eq!(message.destination, RECEIVER);
+        assert_eq!(rx.received().unwrap().1, &data[..30]);
+        // end of message acknowledged
+        assert!(!tx.is_sending());
+    }
+
+    #[test]
+    fn cts_hold_timeout() {
+        let (mut tx_buffer, mut rx_buffer) = ([0; 64], [0; 64]);
+        let (mut tx, _) = transports(&mut tx_buffer, &mut rx_buffer);
+        let data = message(30);
+
+        tx.send(DEFAULT_PRIORITY, PGN, RECEIVER, &data[..30])
+            .unwrap();
+        tx.poll(0).unwrap().unwrap();
+
+        let hold = control(RECEIVER, SENDER, &[CM_CTS, 0, 0xFF, 0xFF, 0xFF]);
+        deliver(&mut tx, &hold, 0).unwrap();
+
+        assert_eq!(tx.poll(T4_MS), Err(J1939Error::Timeout));
+        assert!(!tx.is_sending());
+
+        let abort = tx.poll(T4_MS).unwrap().unwrap();
+        assert_eq!(
+            abort.data(),
+            &[CM_ABORT, ABORT_TIMEOUT, 0xFF, 0xFF, 0xFF, 0xCA, 0xFE, 0x00]
+        );
+    }
+
+    #[test]
+    fn connection_abort() {
+        let (mut tx_buffer, mut rx_buffer) = ([0; 64], [0; 64]);
+        let (mut tx, mut rx) = transports(&mut tx_buffer, &mut rx_buffer);
+        let data = message(30);
+
+        tx.send(DEFAULT_PRIORITY, PGN, RECEIVER, &data[..30])
+            .unwrap();
+
+        let rts = tx.poll(0).unwrap().unwrap();
+        deliver(&mut rx, &rts, 0).unwrap();
+
+        let cts = rx.poll(0).unwrap().unwrap();
+        deliver(&mut tx, &cts, 0).unwrap();
+
+        let packet = tx.poll(0).unwrap().unwrap();
+        deliver(&mut rx, &packet, 0).unwrap();
+
+        // the receiver runs out of resources
+        let abort = control(
+            RECEIVER,
+            SENDER,
+            &[CM_ABORT, ABORT_RESOURCES, 0xFF, 0xFF, 0xFF],
+        );
+        assert_eq!(
+            deliver(&mut tx, &abort, 0),
+            Err(J1939Error::Aborted(ABORT_RESOURCES))
+        );
+        assert!(!tx.is_sending());
+        assert_eq!(tx.poll(0), Ok(None));
+
+        // the sender gives up
+        let abort = control(
+            SENDER,
+            RECEIVER,
+            &[CM_ABORT, ABORT_TIMEOUT, 0xFF, 0xFF, 0xFF],
+        );
+        assert_eq!(
+            deliver(&mut rx, &abort, 0),
+            Err(J1939Error::Aborted(ABORT_TIMEOUT))
+        );
+        assert_eq!(rx.poll(T2_MS), Ok(None));
+        assert!(rx.received().is_none());
+    }
+
+    #[test]
+    fn receive_timeout() {
+        let (mut tx_buffer, mut rx_buffer) = ([0; 64], [0; 64]);
+        let (mut tx, mut rx) = transports(&mut tx_buffer, &mut rx_buffer);
+        let data = message(30);
+
+        tx.send(DEFAULT_PRIORITY, PGN, RECEIVER, &data[..30])
+            .unwrap();
+
+        let rts = tx.poll(0).unwrap().unwrap();
+        deliver(&mut rx, &rts, 0).unwrap();
+        rx.poll(0).unwrap().unwrap();
+
+        // no data within T2
+        assert_eq!(rx.poll(T2_MS - 1), Ok(None));
+        assert_eq!(rx.poll(T2_MS), Err(J1939Error::Timeout));
+
+        let abort = rx.poll(T2_MS).unwrap().unwrap();
+        assert_eq!(
+            abort.data(),
+            &[CM_ABORT, ABORT_TIMEOUT, 0xFF, 0xFF, 0xFF, 0xCA, 0xFE, 0x00]
+        );
+        assert_eq!(
+            deliver(&mut tx, &abort, T2_MS),
+            Err(J1939Error::Aborted(ABORT_TIMEOUT))
+        );
+    }
+
+    #[test]
+    fn max_size_connection() {
+        let (mut tx_buffer, mut rx_buffer) = ([0; MAX_MESSAGE_LEN], [0; MAX_MESSAGE_LEN]);
+        let (mut tx, mut rx) = transports(&mut tx_buffer, &mut rx_buffer);
+        let data = message(MAX_MESSAGE_LEN);
+
+        tx.send(DEFAULT_PRIORITY, PGN, RECEIVER, &data).unwrap();
+
+        let rts = tx.poll(0).unwrap().unwrap();
+        assert_eq!(&rts.data()[..4], &[CM_RTS, 0xF9, 0x06, 255]);
+
+        let mut now = 0;
+        deliver(&mut rx, &rts, now).unwrap();
+
+        let message = exchange(&mut tx, &mut rx, &mut now).unwrap();
+
+        assert_eq!(message.len, MAX_MESSAGE_LEN);
+        assert_eq!(rx.received().unwrap().1, &data[..]);
+        assert!(!tx.is_sending());
+    }
+
+    #[test]
+    fn max_size_broadcast() {
+        let (mut tx_buffer, mut rx_buffer) = ([0; MAX_MESSAGE_LEN], [0; MAX_MESSAGE_LEN]);
+        let (mut tx, mut rx) = transports(&mut tx_buffer, &mut rx_buffer);
+        let data = message(MAX_MESSAGE_LEN);
+
+        tx.send(DEFAULT_PRIORITY, PGN, GLOBAL_ADDRESS, &data)
+            .unwrap();
+
+        let mut now = 0;
+        let message = exchange(&mut tx, &mut rx, &mut now).unwrap();
+
+        assert_eq!(message.len, MAX_MESSAGE_LEN);
+        assert_eq!(rx.received().unwrap().1, &data[..]);
+        // BAM followed by 255 packets
+        assert_eq!(now, 255 * BAM_PACKET_GAP_MS);
+    }
+
+    #[test]
+    fn pdu1_id() {
+        let id = J1939Id::new(6, PGN_REQUEST, 0x80, 0x20);
+
+        assert!(id.is_pdu1());
+        assert_eq!(u32::from(id.id()), 0x18EA_2080);
+        assert_eq!(J1939Id::from(ExtendedID::new(0x18EA_2080)), id);
+
+        // data page set
+        let id = J1939Id::new(7, 0x1_0100, 0x01, 0x02);
+        assert_eq!(u32::from(id.id()), 0x1D01_0201);
+        assert_eq!(J1939Id::from(ExtendedID::new(0x1D01_0201)), id);
+    }
+
+    #[test]
+    fn pdu2_id() {
+        let id = J1939Id::new(3, 0xFECA, 0x00, GLOBAL_ADDRESS);
+
+        assert!(!id.is_pdu1());
+        assert_eq!(u32::from(id.id()), 0x0CFE_CA00);
+        assert_eq!(J1939Id::from(ExtendedID::new(0x0CFE_CA00)), id);
+
+        // the destination isn't part of a PDU2 identifier
+        let id = J1939Id::new(3, 0xFECA, 0x00, 0x20);
+        assert_eq!(u32::from(id.id()), 0x0CFE_CA00);
+    }
+
+    #[test]
+    fn address_claimed() {
+        let name = Name(0x1000);
+        let mut claim = AddressClaim::new(name, 0x80, FREQUENCY);
+
+        let frame = claim.poll(0).unwrap();
+        assert_claim(&frame, 0x80, name);
+        assert_eq!(claim.state(), AddressState::Claiming);
+        assert_eq!(claim.address(), None);
+
+        assert_eq!(claim.poll(CLAIM_TIMEOUT_MS - 1), None);
+        assert_eq!(claim.address(), None);
+
+        assert_eq!(claim.poll(CLAIM_TIMEOUT_MS), None);
+        assert_eq!(claim.state(), AddressState::Claimed);
+        assert_eq!(claim.address(), Some(0x80));
+    }
+
+    #[test]
+    fn arbitration_won() {
+        let name = Name(0x1000);
+        let mut claim = AddressClaim::new(name, 0x80, FREQUENCY);
+
+        claim.poll(0).unwrap();
+
+        // a higher NAME contends, the address is defended
+        deliver_claim(&mut claim, &claim_of(0x80, Name(0x2000)), 10);
+        assert_claim(&claim.poll(10).unwrap(), 0x80, name);
+
+        // the claim sent again restarts the wait
+        claim.poll(CLAIM_TIMEOUT_MS);
+        assert_eq!(claim.address(), None);
+
+        claim.poll(10 + CLAIM_TIMEOUT_MS);
+        assert_eq!(claim.address(), Some(0x80));
+
+        // also once claimed
+        deliver_claim(&mut claim, &claim_of(0x80, Name(0x2000)), 300);
+        assert_claim(&claim.poll(300).unwrap(), 0x80, name);
+        assert_eq!(claim.address(), Some(0x80));
+    }
+
+    #[test]
+    fn arbitration_lost() {
+        let name = Name(0x2000);
+        let mut claim = AddressClaim::new(name, 0x80, FREQUENCY);
+
+        claim.poll(0).unwrap();
+        claim.poll(CLAIM_TIMEOUT_MS);
+        assert_eq!(claim.address(), Some(0x80));
+
+        // not arbitrary address capable, cannot claim
+        deliver_claim(&mut claim, &claim_of(0x80, Name(0x1000)), 300);
+        assert_eq!(claim.state(), AddressState::Failed);
+        assert_eq!(claim.address(), None);
+
+        assert_claim(&claim.poll(300).unwrap(), NULL_ADDRESS, name);
+        assert_eq!(claim.poll(1000), None);
+        assert_eq!(claim.state(), AddressState::Failed);
+    }
+
+    #[test]
+    fn arbitrary_address_reselected() {
+        let name = Name(0x8000_0000_0000_2000);
+        let mut claim = AddressClaim::new(name, 0x20, FREQUENCY);
+
+        // the first dynamic address is taken
+        deliver_claim(
+            &mut claim,
+            &claim_of(DYNAMIC_ADDRESS_FIRST, Name(0x3000)),
+            0,
+        );
+        claim.poll(0).unwrap();
+
+        deliver_claim(&mut claim, &claim_of(0x20, Name(0x1000)), 10);
+        assert_eq!(claim.state(), AddressState::Claiming);
+
+        let next = DYNAMIC_ADDRESS_FIRST + 1;
+        assert_claim(&claim.poll(10).unwrap(), next, name);
+
+        assert_eq!(claim.poll(10 + CLAIM_TIMEOUT_MS - 1), None);
+        assert_eq!(claim.address(), None);
+
+        claim.poll(10 + CLAIM_TIMEOUT_MS);
+        assert_eq!(claim.address(), Some(next));
+    }
+
+    #[test]
+    fn arbitrary_address_cannot_claim() {
+        let name = Name(0x8000_0000_0000_2000);
+        let mut claim = AddressClaim::new(name, 0x20, FREQUENCY);
+
+        // every dynamic address is taken
+        for address in DYNAMIC_ADDRESS_FIRST..=DYNAMIC_ADDRESS_LAST {
+            deliver_claim(&mut claim, &claim_of(address, Name(0x3000)), 0);
+        }
+        claim.poll(0).unwrap();
+
+        deliver_claim(&mut claim, &claim_of(0x20, Name(0x1000)), 10);
+        assert_eq!(claim.state(), AddressState::Failed);
+        assert_claim(&claim.poll(10).unwrap(), NULL_ADDRESS, name);
+    }
+
+    #[test]
+    fn request_for_address_claimed() {
+        let name = Name(0x1000);
+        let mut claim = AddressClaim::new(name, 0x80, FREQUENCY);
+        let requested = [
+            PGN_ADDRESS_CLAIMED as u8,
+            (PGN_ADDRESS_CLAIMED >> 8) as u8,
+            (PGN_ADDRESS_CLAIMED >> 16) as u8,
+        ];
+
+        claim.poll(0).unwrap();
+        claim.poll(CLAIM_TIMEOUT_MS);
+
+        // global request
+        let id = J1939Id::new(DEFAULT_PRIORITY, PGN_REQUEST, 0x20, GLOBAL_ADDRESS);
+        claim.on_frame(&id, &requested, 300);
+        assert_claim(&claim.poll(300).unwrap(), 0x80, name);
+        assert_eq!(claim.poll(300), None);
+
+        // request to this node
+        let id = J1939Id::new(DEFAULT_PRIORITY, PGN_REQUEST, 0x20, 0x80);
+        claim.on_frame(&id, &requested, 400);
+        assert_claim(&claim.poll(400).unwrap(), 0x80, name);
+
+        // request to another node or for another PGN
+        let id = J1939Id::new(DEFAULT_PRIORITY, PGN_REQUEST, 0x20, 0x81);
+        claim.on_frame(&id, &requested, 500);
+        let id = J1939Id::new(DEFAULT_PRIORITY, PGN_REQUEST, 0x20, GLOBAL_ADDRESS);
+        claim.on_frame(&id, &[0xCA, 0xFE, 0x00], 500);
+        assert_eq!(claim.poll(500), None);
+        assert_eq!(claim.address(), Some(0x80));
+    }
+}
@@ -13,13 +13,20 @@ pub use stm32f7::stm32f7x7;
 
 pub mod adc;
 pub mod can;
+pub mod candump;
 pub mod delay;
+pub mod dma;
 pub mod flash;
 pub mod gpio;
+pub mod isotp;
 pub mod iwdg;
+pub mod j1939;
+pub mod obd2;
+pub mod oscc;
 pub mod prelude;
 pub mod rcc;
 pub mod serial;
 pub mod spi;
+pub mod spsc;
 pub mod time;
 pub mod timer;
@@ -0,0 +1,622 @@
+//! OBD-II (SAE J1979 over ISO 15765-4) client
+//!
+//! Requests are sent to the functional address, replies are collected from
+//! the ECU response IDs. Multi-frame replies are reassembled with ISO-TP,
+//! flow control frames go to the physical address of the replying ECU.
+//!
+//! Example:
+//! static mut BUFFER: [u8; 64] = [0; 64];
+//!
+//! let mut obd2 = Obd2Client::new(can, timer, unsafe { &mut BUFFER });
+//!
+//! let speed = obd2.current_data(Pid::VehicleSpeed)?;
+//! let vin = obd2.vin()?;
+
+use core::cmp;
+use hal::can::nb::Can;
+use hal::can::Frame;
+use nb;
+
+use can::{from_hal_id, to_hal_id, BaseID, DataFrame, ID};
+use isotp::{IsoTp, IsoTpConfig, IsoTpError};
+use time::MonoTimer;
+
+/// Functional (broadcast) request ID
+pub const FUNCTIONAL_REQUEST_ID: u16 = 0x7DF;
+/// Response ID of the first ECU, ECU n replies with `0x7E8 + n`
+pub const RESPONSE_ID_FIRST: u16 = 0x7E8;
+/// Response ID of the last ECU
+pub const RESPONSE_ID_LAST: u16 = 0x7EF;
+/// Physical request IDs are 8 below the response IDs
+pub const PHYSICAL_ID_OFFSET: u16 = 8;
+
+/// Show current data
+pub const MODE_CURRENT_DATA: u8 = 0x01;
+/// Request vehicle information
+pub const MODE_VEHICLE_INFO: u8 = 0x09;
+
+/// Vehicle identification number info type (mode 09)
+pub const INFO_TYPE_VIN: u8 = 0x02;
+
+/// Length of a VIN
+pub const VIN_LEN: usize = 17;
+
+// positive responses echo the mode plus 0x40
+const POSITIVE_RESPONSE: u8 = 0x40;
+const NEGATIVE_RESPONSE: u8 = 0x7F;
+
+// ISO 15765-4 frames are always 8 bytes
+const PADDING: u8 = 0x55;
+
+const PCI_SINGLE: u8 = 0x00;
+const PCI_FIRST: u8 = 0x10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Obd2Error {
+    /// No reply in time
+    Timeout,
+    /// Negative response, with the response code
+    NegativeResponse(u8),
+    /// Reply doesn't match the request or is too short
+    InvalidResponse,
+    /// Multi-frame reply failed
+    Transport(IsoTpError),
+    /// The CAN interface reported an error
+    Bus,
+}
+
+/// Mode 01 PIDs with a decoding to engineering units
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pid {
+    /// Calculated engine load, %
+    EngineLoad,
+    /// Engine coolant temperature, degrees C
+    CoolantTemperature,
+    /// Intake manifold absolute pressure, kPa
+    IntakeManifoldPressure,
+    /// Engine speed, rpm
+    EngineRpm,
+    /// Vehicle speed, km/h
+    VehicleSpeed,
+    /// Intake air temperature, degrees C
+    IntakeAirTemperature,
+    /// Mass air flow rate, g/s
+    MassAirFlow,
+    /// Throttle position, %
+    ThrottlePosition,
+    /// Run time since engine start, s
+    RunTime,
+    /// Fuel tank level, %
+    FuelLevel,
+    /// Absolute barometric pressure, kPa
+    BarometricPressure,
+    /// Control module voltage, V
+    ControlModuleVoltage,
+    /// Ambient air temperature, degrees C
+    AmbientAirTemperature,
+    /// Engine oil temperature, degrees C
+    OilTemperature,
+}
+
+impl Pid {
+    /// Returns the PID number
+    pub fn code(&self) -> u8 {
+        match self {
+            Pid::EngineLoad => 0x04,
+            Pid::CoolantTemperature => 0x05,
+            Pid::IntakeManifoldPressure => 0x0B,
+            Pid::EngineRpm => 0x0C,
+            Pid::VehicleSpeed => 0x0D,
+            Pid::IntakeAirTemperature => 0x0F,
+            Pid::MassAirFlow => 0x10,
+            Pid::ThrottlePosition => 0x11,
+            Pid::RunTime => 0x1F,
+            Pid::FuelLevel => 0x2F,
+            Pid::BarometricPressure => 0x33,
+            Pid::ControlModuleVoltage => 0x42,
+            Pid::AmbientAirTemperature => 0x46,
+            Pid::OilTemperature => 0x5C,
+        }
+    }
+
+    /// Returns the number of data bytes
+    pub fn data_len(&self) -> usize {
+        match self {
+            Pid::EngineRpm | Pid::MassAirFlow | Pid::RunTime | Pid::ControlModuleVoltage => 2,
+            _ => 1,
+        }
+    }
+
+    /// Decodes the data bytes (A, B, ...) of a reply
+    pub fn decode(&self, data: &[u8]) -> Option<f32> {
+        if data.len() < self.data_len() {
+            return None;
+        }
+
+        let a = f32::from(data[0]);
+        let ab = || f32::from((u16::from(data[0]) << 8) | u16::from(data[1]));
+
+        let value = match self {
+            Pid::EngineLoad | Pid::ThrottlePosition | Pid::FuelLevel => a * 100.0 / 255.0,
+            Pid::CoolantTemperature
+            | Pid::IntakeAirTemperature
+            | Pid::AmbientAirTemperature
+            | Pid::OilTemperature => a - 40.0,
+            Pid::IntakeManifoldPressure | Pid::VehicleSpeed | Pid::BarometricPressure => a,
+            Pid::EngineRpm => ab() / 4.0,
+            Pid::MassAirFlow => ab() / 100.0,
+            Pid::RunTime => ab(),
+            Pid::ControlModuleVoltage => ab() / 1000.0,
+        };
+
+        Some(value)
+    }
+}
+
+/// OBD-II client
+pub struct Obd2Client<'a, CAN> {
+    can: CAN,
+    timer: MonoTimer,
+    isotp: IsoTp<'a>,
+    timeout_ms: u32,
+}
+
+impl<'a, CAN> Obd2Client<'a, CAN>
+where
+    CAN: Can,
+{
+    /// Creates a client with a 100 ms reply timeout, `buffer` holds
+    /// multi-frame replies
+    pub fn new(can: CAN, timer: MonoTimer, buffer: &'a mut [u8]) -> Self {
+        // requests are single frames, sent without ISO-TP
+        let isotp = IsoTp::new(isotp_config(), &mut [], buffer, timer.frequency());
+
+        Obd2Client {
+            can,
+            timer,
+            isotp,
+            timeout_ms: 100,
+        }
+    }
+
+    /// Sets the time to wait for a (next) reply, limited to the range of
+    /// the timer
+    pub fn set_timeout(&mut self, timeout_ms: u32) {
+        self.timeout_ms = timeout_ms;
+    }
+
+    /// Requests a mode 01 PID and decodes the first reply
+    pub fn current_data(&mut self, pid: Pid) -> Result<f32, Obd2Error> {
+        let mut value = None;
+
+        self.query_with(MODE_CURRENT_DATA, pid.code(), true, |_, data| {
+            value = pid.decode(data)
+        })?;
+
+        value.ok_or(Obd2Error::InvalidResponse)
+    }
+
+    /// Returns the bitmap of supported PIDs `base + 1` to `base + 0x20`,
+    /// bit 31 is `base + 1`
+    pub fn supported_pids(&mut self, base: u8) -> Result<u32, Obd2Error> {
+        let mut pids = None;
+
+        self.query_with(MODE_CURRENT_DATA, base, true, |_, data| {
+            if data.len() >= 4 {
+                pids = Some(
+                    (u32::from(data[0]) << 24)
+                        | (u32::from(data[1]) << 16)
+                        | (u32::from(data[2]) << 8)
+                        | u32::from(data[3]),
+                );
+            }
+        })?;
+
+        pids.ok_or(Obd2Error::InvalidResponse)
+    }
+
+    /// Requests the vehicle identification number
+    pub fn vin(&mut self) -> Result<[u8; VIN_LEN], Obd2Error> {
+        let mut vin = None;
+
+        self.query_with(MODE_VEHICLE_INFO, INFO_TYPE_VIN, true, |_, data| {
+            vin = parse_vin(data)
+        })?;
+
+        vin.ok_or(Obd2Error::InvalidResponse)
+    }
+
+    /// Sends a request to all ECUs and passes each reply to `on_reply`
+    /// along with the ECU number, until no reply came in for the timeout.
+    ///
+    /// Replies start after the PID (or info type) byte. Returns the number
+    /// of replies.
+    pub fn query<F>(&mut self, mode: u8, pid: u8, on_reply: F) -> Result<usize, Obd2Error>
+    where
+        F: FnMut(u8, &[u8]),
+    {
+        self.query_with(mode, pid, false, on_reply)
+    }
+
+    /// Releases the CAN interface
+    pub fn free(self) -> CAN {
+        self.can
+    }
+
+    fn query_with<F>(
+        &mut self,
+        mode: u8,
+        pid: u8,
+        first_only: bool,
+        mut on_reply: F,
+    ) -> Result<usize, Obd2Error>
+    where
+        F: FnMut(u8, &[u8]),
+    {
+        let mut request = DataFrame::new(ID::BaseID(BaseID::new(FUNCTIONAL_REQUEST_ID)));
+        request.set_data_length(8);
+        request.data_as_mut().copy_from_slice(&[
+            PCI_SINGLE | 2,
+            mode,
+            pid,
+            PADDING,
+            PADDING,
+            PADDING,
+            PADDING,
+            PADDING,
+        ]);
+
+        self.transmit(&request)?;
+
+        let timeout = self.ticks(self.timeout_ms);
+        let mut last = self.timer.now();
+        let mut replies = 0;
+        let mut error = None;
+        // ECU sending a multi-frame reply
+        let mut receiving: Option<u8> = None;
+
+        while last.elapsed() < timeout {
+            if receiving.is_some() {
+                match self.isotp.poll(self.timer.now().ticks()) {
+                    Ok(Some(flow_control)) => self.transmit(&flow_control)?,
+                    Ok(None) => (),
+                    Err(e) => {
+                        receiving = None;
+                        error = Some(Obd2Error::Transport(e));
+                    }
+                }
+            }
+
+            let frame = match self.can.receive() {
+                Ok(f) => f,
+                Err(nb::Error::WouldBlock) => continue,
+                Err(nb::Error::Other(_)) => return Err(Obd2Error::Bus),
+            };
+
+            let ecu = match ecu_number(&frame) {
+                Some(ecu) => ecu,
+                None => continue,
+            };
+
+            let data = frame.data();
+
+            if data.is_empty() {
+                continue;
+            }
+
+            last = self.timer.now();
+
+            let reply = match data[0] & 0xF0 {
+                PCI_SINGLE => {
+                    let len = (data[0] & 0x0F) as usize;
+
+                    if (len == 0) || (data.len() < (len + 1)) {
+                        error = Some(Obd2Error::InvalidResponse);
+                        continue;
+                    }
+
+                    &data[1..len + 1]
+                }
+                _ => {
+                    if (receiving.is_none()) && ((data[0] & 0xF0) == PCI_FIRST) {
+                        self.isotp.set_ids(physical_id(ecu), response_id(ecu));
+                        receiving = Some(ecu);
+                    }
+
+                    if receiving != Some(ecu) {
+                        continue;
+                    }
+
+                    match self
+                        .isotp
+                        .on_frame(from_hal_id(frame.id()), data, last.ticks())
+                    {
+                        Ok(Some(_)) => {
+                            receiving = None;
+
+                            match self.isotp.received() {
+                                Some(r) => r,
+                                None => continue,
+                            }
+                        }
+                        Ok(None) => continue,
+                        Err(e) => {
+                            receiving = None;
+                            error = Some(Obd2Error::Transport(e));
+                            continue;
+                        }
+                    }
+                }
+            };
+
+            match check_reply(mode, pid, reply) {
+                Ok(payload) => {
+                    on_reply(ecu, payload);
+                    replies += 1;
+
+                    if first_only {
+                        break;
+                    }
+                }
+                Err(e) => error = Some(e),
+            }
+        }
+
+        if replies == 0 {
+            Err(error.unwrap_or(Obd2Error::Timeout))
+        } else {
+            Ok(replies)
+        }
+    }
+
+    fn transmit(&mut self, frame: &DataFrame) -> Result<(), Obd2Error> {
+        let frame = match CAN::Frame::new(to_hal_id(frame.id()), frame.data()) {
+            Some(f) => f,
+            None => return Err(Obd2Error::Bus),
+        };
+
+        let timeout = self.ticks(self.timeout_ms);
+        let start = self.timer.now();
+
+        loop {
+            match self.can.transmit(&frame) {
+                Ok(_) => return Ok(()),
+                Err(nb::Error::WouldBlock) => {
+                    if start.elapsed() >= timeout {
+                        return Err(Obd2Error::Timeout);
+                    }
+                }
+                Err(nb::Error::Other(_)) => return Err(Obd2Error::Bus),
+            }
+        }
+    }
+
+    // Saturates at the counter range
+    fn ticks(&self, ms: u32) -> u32 {
+        let ticks = (u64::from(self.timer.frequency().0) * u64::from(ms)) / 1000;
+
+        cmp::min(ticks, u64::from(u32::max_value())) as u32
+    }
+}
+
+// Reassembles multi-frame replies, ECU 0 until a first frame is received
+fn isotp_config() -> IsoTpConfig {
+    let mut config = IsoTpConfig::new(physical_id(0), response_id(0));
+    config.padding = Some(PADDING);
+
+    config
+}
+
+// Returns the VIN of a reply, preceded by the number of data items on CAN
+fn parse_vin(data: &[u8]) -> Option<[u8; VIN_LEN]> {
+    if data.len() < VIN_LEN {
+        return None;
+    }
+
+    let mut vin = [0; VIN_LEN];
+    vin.copy_from_slice(&data[data.len() - VIN_LEN..]);
+
+    Some(vin)
+}
+
+// Returns the ECU number of a response frame
+fn ecu_number<F>(frame: &F) -> Option<u8>
+where
+    F: Frame,
+{
+    if frame.is_remote_frame() {
+        return None;
+    }
+
+    match from_hal_id(frame.id()) {
+        ID::BaseID(id) => {
+            let id = u16::from(id);
+
+            if (id >= RESPONSE_ID_FIRST) && (id <= RESPONSE_ID_LAST) {
+                Some((id - RESPONSE_ID_FIRST) as u8)
+            } else {
+                None
+            }
+        }
+        ID::ExtendedID(_) => None,
+    }
+}
+
+// Checks the mode and PID of a reply, returns the data after them
+fn check_reply(mode: u8, pid: u8, reply: &[u8]) -> Result<&[u8], Obd2Error> {
+    if (reply.len() >= 3) && (reply[0] == NEGATIVE_RESPONSE) && (reply[1] == mode) {
+        return Err(Obd2Error::NegativeResponse(reply[2]));
+    }
+
+    if (reply.len() < 2) || (reply[0] != (mode | POSITIVE_RESPONSE)) || (reply[1] != pid) {
+        return Err(Obd2Error::InvalidResponse);
+    }
+
+    Ok(&reply[2..])
+}
+
+fn physical_id(ecu: u8) -> ID {
+    ID::BaseID(BaseID::new(
+        RESPONSE_ID_FIRST - PHYSICAL_ID_OFFSET + u16::from(ecu),
+    ))
+}
+
+fn response_id(ecu: u8) -> ID {
+    ID::BaseID(BaseID::new(RESPONSE_ID_FIRST + u16::from(ecu)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use can::HalFrame;
+    use hal::can::{Id, StandardId};
+    use time::Hertz;
+
+    // 1 tick per millisecond
+    const FREQUENCY: Hertz = Hertz(1000);
+
+    const VIN: &[u8; VIN_LEN] = b"1G1JC5444R7252367";
+
+    fn assert_close(value: Option<f32>, expected: f32) {
+        let value = value.unwrap();
+
+        assert!((value - expected).abs() < 0.01, "{} != {}", value, expected);
+    }
+
+    fn frame(id: u16, data: &[u8]) -> HalFrame {
+        HalFrame::new(StandardId::new(id).unwrap(), data).unwrap()
+    }
+
+    #[test]
+    fn decode() {
+        assert_close(Pid::EngineLoad.decode(&[0xFF]), 100.0);
+        assert_close(Pid::CoolantTemperature.decode(&[0x7B]), 83.0);
+        assert_close(Pid::IntakeManifoldPressure.decode(&[0x21]), 33.0);
+        assert_close(Pid::EngineRpm.decode(&[0x1A, 0xF8]), 1726.0);
+        assert_close(Pid::VehicleSpeed.decode(&[0x3C]), 60.0);
+        assert_close(Pid::IntakeAirTemperature.decode(&[0x00]), -40.0);
+        assert_close(Pid::MassAirFlow.decode(&[0x01, 0x2C]), 3.0);
+        assert_close(Pid::ThrottlePosition.decode(&[0x33]), 20.0);
+        assert_close(Pid::RunTime.decode(&[0x01, 0x00]), 256.0);
+        assert_close(Pid::FuelLevel.decode(&[0x80]), 50.2);
+        assert_close(Pid::BarometricPressure.decode(&[0x65]), 101.0);
+        assert_close(Pid::ControlModuleVoltage.decode(&[0x36, 0xB0]), 14.0);
+        assert_close(Pid::AmbientAirTemperature.decode(&[0x3C]), 20.0);
+        assert_close(Pid::OilTemperature.decode(&[0xFF]), 215.0);
+
+        // trailing bytes are ignored
+        assert_close(Pid::VehicleSpeed.decode(&[0x3C, 0x55, 0x55]), 60.0);
+    }
+
+    #[test]
+    fn decode_too_short() {
+        assert_eq!(Pid::VehicleSpeed.decode(&[]), None);
+        assert_eq!(Pid::EngineRpm.decode(&[0x1A]), None);
+        assert_eq!(Pid::ControlModuleVoltage.decode(&[0x36]), None);
+    }
+
+    #[test]
+    fn reply() {
+        assert_eq!(
+            check_reply(0x01, 0x0D, &[0x41, 0x0D, 0x3C]),
+            Ok(&[0x3C][..])
+        );
+        assert_eq!(
+            check_reply(0x01, 0x0D, &[0x7F, 0x01, 0x12]),
+            Err(Obd2Error::NegativeResponse(0x12))
+        );
+        // wrong mode, wrong PID, too short
+        assert_eq!(
+            check_reply(0x01, 0x0D, &[0x49, 0x0D, 0x3C]),
+            Err(Obd2Error::InvalidResponse)
+        );
+        assert_eq!(
+            check_reply(0x01, 0x0D, &[0x41, 0x0C, 0x3C]),
+            Err(Obd2Error::InvalidResponse)
+        );
+        assert_eq!(
+            check_reply(0x01, 0x0D, &[0x41]),
+            Err(Obd2Error::InvalidResponse)
+        );
+        // negative response to another mode
+        assert_eq!(
+            check_reply(0x01, 0x0D, &[0x7F, 0x09, 0x12]),
+            Err(Obd2Error::InvalidResponse)
+        );
+    }
+
+    #[test]
+    fn response_ecu_number() {
+        assert_eq!(ecu_number(&frame(0x7E8, &[])), Some(0));
+        assert_eq!(ecu_number(&frame(0x7EF, &[])), Some(7));
+        assert_eq!(ecu_number(&frame(0x7E0, &[])), None);
+        assert_eq!(ecu_number(&frame(0x7DF, &[])), None);
+
+        let remote = HalFrame::new_remote(Id::Standard(StandardId::new(0x7E8).unwrap()), 8);
+        assert_eq!(ecu_number(&remote.unwrap()), None);
+    }
+
+    #[test]
+    fn vin_multi_frame() {
+        // 49 02, number of data items, VIN
+        let mut reply = [0; 20];
+        reply[..3].copy_from_slice(&[0x49, INFO_TYPE_VIN, 0x01]);
+        reply[3..].copy_from_slice(VIN);
+
+        // ECU 1 replying to the functional request
+        let (mut tx_buffer, mut rx_buffer) = ([0; 64], [0; 64]);
+        let mut ecu = IsoTp::new(
+            IsoTpConfig::new(response_id(1), physical_id(1)),
+            &mut tx_buffer,
+            &mut [],
+            FREQUENCY,
+        );
+        let mut client = IsoTp::new(isotp_config(), &mut [], &mut rx_buffer, FREQUENCY);
+
+        ecu.send(&reply).unwrap();
+
+        let first = ecu.poll(0).unwrap().unwrap();
+        assert_eq!(first.id(), ID::BaseID(BaseID::new(0x7E9)));
+        assert_eq!(&first.data()[..2], &[0x10, 20]);
+
+        let number = ecu_number(&frame(0x7E9, first.data())).unwrap();
+        assert_eq!(number, 1);
+
+        // as done by the client on the first frame
+        client.set_ids(physical_id(number), response_id(number));
+        assert_eq!(client.on_frame(first.id(), first.data(), 0), Ok(None));
+
+        // flow control goes to the physical ID of the ECU
+        let flow_control = client.poll(0).unwrap().unwrap();
+        assert_eq!(flow_control.id(), ID::BaseID(BaseID::new(0x7E1)));
+        assert_eq!(
+            flow_control.data(),
+            &[0x30, 0, 0, PADDING, PADDING, PADDING, PADDING, PADDING]
+        );
+        ecu.on_frame(flow_control.id(), flow_control.data(), 0)
+            .unwrap();
+
+        let mut received = None;
+        while let Some(consecutive) = ecu.poll(0).unwrap() {
+            received = client
+                .on_frame(consecutive.id(), consecutive.data(), 0)
+                .unwrap();
+        }
+        assert_eq!(received, Some(20));
+
+        let payload = check_reply(MODE_VEHICLE_INFO, INFO_TYPE_VIN, client.received().unwrap());
+        assert_eq!(parse_vin(payload.unwrap()), Some(*VIN));
+    }
+
+    #[test]
+    fn vin_last_bytes() {
+        // without the number of data items
+        assert_eq!(parse_vin(VIN), Some(*VIN));
+
+        let mut data = [0xFF; 20];
+        data[3..].copy_from_slice(VIN);
+        assert_eq!(parse_vin(&data), Some(*VIN));
+
+        assert_eq!(parse_vin(&VIN[1..]), None);
+    }
+}
@@ -0,0 +1,461 @@
+//! OSCC CAN messages
+//!
+//! Brake, steering and throttle enable/disable/command/report messages and
+//! the fault report, as defined by the OSCC CAN protocol. All messages are
+//! standard data frames of 8 bytes starting with the two magic bytes,
+//! multi-byte fields are little endian.
+//!
+//! Example:
+//! can.transmit(&BrakeCommand { pedal_command: 0.25 }.into())?;
+//!
+//! match OsccMessage::try_from(&frame) {
+//!     Ok(OsccMessage::SteeringReport(report)) => ...,
+//!     ...
+//! }
+
+use core::convert::TryFrom;
+
+use can::{BaseID, CanFrame, DataFrame, ID};
+
+pub const MAGIC_BYTE_0: u8 = 0x05;
+pub const MAGIC_BYTE_1: u8 = 0xCC;
+
+pub const BRAKE_ENABLE_CAN_ID: u16 = 0x70;
+pub const BRAKE_DISABLE_CAN_ID: u16 = 0x71;
+pub const BRAKE_COMMAND_CAN_ID: u16 = 0x72;
+pub const BRAKE_REPORT_CAN_ID: u16 = 0x73;
+
+pub const STEERING_ENABLE_CAN_ID: u16 = 0x80;
+pub const STEERING_DISABLE_CAN_ID: u16 = 0x81;
+pub const STEERING_COMMAND_CAN_ID: u16 = 0x82;
+pub const STEERING_REPORT_CAN_ID: u16 = 0x83;
+
+pub const THROTTLE_ENABLE_CAN_ID: u16 = 0x90;
+pub const THROTTLE_DISABLE_CAN_ID: u16 = 0x91;
+pub const THROTTLE_COMMAND_CAN_ID: u16 = 0x92;
+pub const THROTTLE_REPORT_CAN_ID: u16 = 0x93;
+
+pub const FAULT_REPORT_CAN_ID: u16 = 0xAF;
+
+// `fault_origin_id` values of the fault report
+pub const FAULT_ORIGIN_BRAKE: u32 = 0;
+pub const FAULT_ORIGIN_STEERING: u32 = 1;
+pub const FAULT_ORIGIN_THROTTLE: u32 = 2;
+
+/// Length of every OSCC message
+pub const MESSAGE_LEN: usize = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OsccError {
+    /// Frame ID doesn't belong to the message
+    UnexpectedId,
+    /// Remote frame, or data frame of the wrong length
+    InvalidFrame,
+    /// Magic bytes don't match
+    InvalidMagic,
+}
+
+// Returns the data of an OSCC message with the given ID
+fn message_data(frame: &CanFrame, id: u16) -> Result<&[u8], OsccError> {
+    if frame.id() != ID::BaseID(BaseID::new(id)) {
+        return Err(OsccError::UnexpectedId);
+    }
+
+    let data = match *frame {
+        CanFrame::DataFrame(ref f) => f.data(),
+        CanFrame::RemoteFrame(_) => return Err(OsccError::InvalidFrame),
+    };
+
+    if data.len() != MESSAGE_LEN {
+        return Err(OsccError::InvalidFrame);
+    }
+
+    if (data[0] != MAGIC_BYTE_0) || (data[1] != MAGIC_BYTE_1) {
+        return Err(OsccError::InvalidMagic);
+    }
+
+    Ok(data)
+}
+
+// Creates an OSCC message with the magic bytes set, the rest is zeroed
+fn message_frame(id: u16) -> DataFrame {
+    let mut frame = DataFrame::new(ID::BaseID(BaseID::new(id)));
+
+    frame.set_data_length(MESSAGE_LEN);
+    frame.data_as_mut()[0] = MAGIC_BYTE_0;
+    frame.data_as_mut()[1] = MAGIC_BYTE_1;
+
+    frame
+}
+
+macro_rules! enable_messages {
+    ($($(#[$meta:meta])* $Msg:ident: $ID:ident,)+) => {
+        $(
+$(#[$meta])*
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct $Msg;
+
+impl<'a> TryFrom<&'a CanFrame> for $Msg {
+    type Error = OsccError;
+
+    fn try_from(frame: &'a CanFrame) -> Result<Self, OsccError> {
+        message_data(frame, $ID).map(|_| $Msg)
+    }
+}
+
+impl From<$Msg> for CanFrame {
+    fn from(_msg: $Msg) -> CanFrame {
+        CanFrame::from(message_frame($ID))
+    }
+}
+        )+
+    }
+}
+
+macro_rules! command_messages {
+    ($($(#[$meta:meta])* $Msg:ident: ($ID:ident, $field:ident),)+) => {
+        $(
+$(#[$meta])*
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct $Msg {
+    pub $field: f32,
+}
+
+impl<'a> TryFrom<&'a CanFrame> for $Msg {
+    type Error = OsccError;
+
+    fn try_from(frame: &'a CanFrame) -> Result<Self, OsccError> {
+        let data = message_data(frame, $ID)?;
+        let bits = u32::from(data[2])
+            | (u32::from(data[3]) << 8)
+            | (u32::from(data[4]) << 16)
+            | (u32::from(data[5]) << 24);
+
+        Ok($Msg {
+            $field: f32::from_bits(bits),
+        })
+    }
+}
+
+impl From<$Msg> for CanFrame {
+    fn from(msg: $Msg) -> CanFrame {
+        let mut frame = message_frame($ID);
+        let bits = msg.$field.to_bits();
+
+        frame.data_as_mut()[2] = bits as u8;
+        frame.data_as_mut()[3] = (bits >> 8) as u8;
+        frame.data_as_mut()[4] = (bits >> 16) as u8;
+        frame.data_as_mut()[5] = (bits >> 24) as u8;
+
+        CanFrame::from(frame)
+    }
+}
+        )+
+    }
+}
+
+macro_rules! report_messages {
+    ($($(#[$meta:meta])* $Msg:ident: $ID:ident,)+) => {
+        $(
+$(#[$meta])*
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct $Msg {
+    pub enabled: bool,
+    pub operator_override: bool,
+    /// Diagnostic trouble code bits
+    pub dtcs: u8,
+}
+
+impl<'a> TryFrom<&'a CanFrame> for $Msg {
+    type Error = OsccError;
+
+    fn try_from(frame: &'a CanFrame) -> Result<Self, OsccError> {
+        let data = message_data(frame, $ID)?;
+
+        Ok($Msg {
+            enabled: data[2] != 0,
+            operator_override: data[3] != 0,
+            dtcs: data[4],
+        })
+    }
+}
+
+impl From<$Msg> for CanFrame {
+    fn from(msg: $Msg) -> CanFrame {
+        let mut frame = message_frame($ID);
+
+        frame.data_as_mut()[2] = msg.enabled as u8;
+        frame.data_as_mut()[3] = msg.operator_override as u8;
+        frame.data_as_mut()[4] = msg.dtcs;
+
+        CanFrame::from(frame)
+    }
+}
+        )+
+    }
+}
+
+enable_messages! {
+    BrakeEnable: BRAKE_ENABLE_CAN_ID,
+    BrakeDisable: BRAKE_DISABLE_CAN_ID,
+    SteeringEnable: STEERING_ENABLE_CAN_ID,
+    SteeringDisable: STEERING_DISABLE_CAN_ID,
+    ThrottleEnable: THROTTLE_ENABLE_CAN_ID,
+    ThrottleDisable: THROTTLE_DISABLE_CAN_ID,
+}
+
+command_messages! {
+    /// Brake pedal position, [0.0, 1.0]
+    BrakeCommand: (BRAKE_COMMAND_CAN_ID, pedal_command),
+    /// Steering wheel torque, [-1.0, 1.0]
+    SteeringCommand: (STEERING_COMMAND_CAN_ID, torque_command),
+    /// Throttle pedal position, [0.0, 1.0]
+    ThrottleCommand: (THROTTLE_COMMAND_CAN_ID, torque_request),
+}
+
+report_messages! {
+    BrakeReport: BRAKE_REPORT_CAN_ID,
+    SteeringReport: STEERING_REPORT_CAN_ID,
+    ThrottleReport: THROTTLE_REPORT_CAN_ID,
+}
+
+/// Fault reported by one of the modules
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FaultReport {
+    /// One of the `FAULT_ORIGIN_*` values
+    pub fault_origin_id: u32,
+    /// Diagnostic trouble code bits
+    pub dtcs: u8,
+}
+
+impl<'a> TryFrom<&'a CanFrame> for FaultReport {
+    type Error = OsccError;
+
+    fn try_from(frame: &'a CanFrame) -> Result<Self, OsccError> {
+        let data = message_data(frame, FAULT_REPORT_CAN_ID)?;
+
+        Ok(FaultReport {
+            fault_origin_id: u32::from(data[2])
+                | (u32::from(data[3]) << 8)
+                | (u32::from(data[4]) << 16)
+                | (u32::from(data[5]) << 24),
+            dtcs: data[6],
+        })
+    }
+}
+
+impl From<FaultReport> for CanFrame {
+    fn from(msg: FaultReport) -> CanFrame {
+        let mut frame = message_frame(FAULT_REPORT_CAN_ID);
+
+        frame.data_as_mut()[2] = msg.fault_origin_id as u8;
+        frame.data_as_mut()[3] = (msg.fault_origin_id >> 8) as u8;
+        frame.data_as_mut()[4] = (msg.fault_origin_id >> 16) as u8;
+        frame.data_as_mut()[5] = (msg.fault_origin_id >> 24) as u8;
+        frame.data_as_mut()[6] = msg.dtcs;
+
+        CanFrame::from(frame)
+    }
+}
+
+/// Any OSCC message, decoded by ID
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OsccMessage {
+    BrakeEnable(BrakeEnable),
+    BrakeDisable(BrakeDisable),
+    BrakeCommand(BrakeCommand),
+    BrakeReport(BrakeReport),
+    SteeringEnable(SteeringEnable),
+    SteeringDisable(SteeringDisable),
+    SteeringCommand(SteeringCommand),
+    SteeringReport(SteeringReport),
+    ThrottleEnable(ThrottleEnable),
+    ThrottleDisable(ThrottleDisable),
+    ThrottleCommand(ThrottleCommand),
+    ThrottleReport(ThrottleReport),
+    FaultReport(FaultReport),
+}
+
+impl<'a> TryFrom<&'a CanFrame> for OsccMessage {
+    type Error = OsccError;
+
+    fn try_from(frame: &'a CanFrame) -> Result<Self, OsccError> {
+        let id = match frame.id() {
+            ID::BaseID(id) => u16::from(id),
+            ID::ExtendedID(_) => return Err(OsccError::UnexpectedId),
+        };
+
+        match id {
+            BRAKE_ENABLE_CAN_ID => BrakeEnable::try_from(frame).map(OsccMessage::BrakeEnable),
+            BRAKE_DISABLE_CAN_ID => BrakeDisable::try_from(frame).map(OsccMessage::BrakeDisable),
+            BRAKE_COMMAND_CAN_ID => BrakeCommand::try_from(frame).map(OsccMessage::BrakeCommand),
+            BRAKE_REPORT_CAN_ID => BrakeReport::try_from(frame).map(OsccMessage::BrakeReport),
+            STEERING_ENABLE_CAN_ID => {
+                SteeringEnable::try_from(frame).map(OsccMessage::SteeringEnable)
+            }
+            STEERING_DISABLE_CAN_ID => {
+                SteeringDisable::try_from(frame).map(OsccMessage::SteeringDisable)
+            }
+            STEERING_COMMAND_CAN_ID => {
+                SteeringCommand::try_from(frame).map(OsccMessage::SteeringCommand)
+            }
+            STEERING_REPORT_CAN_ID => {
+                SteeringReport::try_from(frame).map(OsccMessage::SteeringReport)
+            }
+            THROTTLE_ENABLE_CAN_ID => {
+                ThrottleEnable::try_from(frame).map(OsccMessage::ThrottleEnable)
+            }
+            THROTTLE_DISABLE_CAN_ID => {
+                ThrottleDisable::try_from(frame).map(OsccMessage::ThrottleDisable)
+            }
+            THROTTLE_COMMAND_CAN_ID => {
+                ThrottleCommand::try_from(frame).map(OsccMessage::ThrottleCommand)
+            }
+            THROTTLE_REPORT_CAN_ID => {
+                ThrottleReport::try_from(frame).map(OsccMessage::ThrottleReport)
+            }
+            FAULT_REPORT_CAN_ID => FaultReport::try_from(frame).map(OsccMessage::FaultReport),
+            _ => Err(OsccError::UnexpectedId),
+        }
+    }
+}
+
+impl From<OsccMessage> for CanFrame {
+    fn from(msg: OsccMessage) -> CanFrame {
+        match msg {
+            OsccMessage::BrakeEnable(m) => m.into(),
+            OsccMessage::BrakeDisable(m) => m.into(),
+            OsccMessage::BrakeCommand(m) => m.into(),
+            OsccMessage::BrakeReport(m) => m.into(),
+            OsccMessage::SteeringEnable(m) => m.into(),
+            OsccMessage::SteeringDisable(m) => m.into(),
+            OsccMessage::SteeringCommand(m) => m.into(),
+            OsccMessage::SteeringReport(m) => m.into(),
+            OsccMessage::ThrottleEnable(m) => m.into(),
+            OsccMessage::ThrottleDisable(m) => m.into(),
+            OsccMessage::ThrottleCommand(m) => m.into(),
+            OsccMessage::ThrottleReport(m) => m.into(),
+            OsccMessage::FaultReport(m) => m.into(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use can::RemoteFrame;
+
+    fn round_trip(msg: OsccMessage) {
+        let frame = CanFrame::from(msg);
+
+        assert_eq!(OsccMessage::try_from(&frame), Ok(msg));
+    }
+
+    fn data(frame: &CanFrame) -> &[u8] {
+        match *frame {
+            CanFrame::DataFrame(ref f) => f.data(),
+            CanFrame::RemoteFrame(_) => &[],
+        }
+    }
+
+    #[test]
+    fn enable_messages() {
+        round_trip(OsccMessage::BrakeEnable(BrakeEnable));
+        round_trip(OsccMessage::BrakeDisable(BrakeDisable));
+        round_trip(OsccMessage::SteeringEnable(SteeringEnable));
+        round_trip(OsccMessage::SteeringDisable(SteeringDisable));
+        round_trip(OsccMessage::ThrottleEnable(ThrottleEnable));
+        round_trip(OsccMessage::ThrottleDisable(ThrottleDisable));
+
+        let frame = CanFrame::from(BrakeEnable);
+        assert_eq!(frame.id(), ID::BaseID(BaseID::new(BRAKE_ENABLE_CAN_ID)));
+        assert_eq!(data(&frame), &[0x05, 0xCC, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn command_messages() {
+        round_trip(OsccMessage::BrakeCommand(BrakeCommand {
+            pedal_command: 0.25,
+        }));
+        round_trip(OsccMessage::SteeringCommand(SteeringCommand {
+            torque_command: -0.5,
+        }));
+        round_trip(OsccMessage::ThrottleCommand(ThrottleCommand {
+            torque_request: 1.0,
+        }));
+
+        // 1.0, little endian
+        let frame = CanFrame::from(ThrottleCommand {
+            torque_request: 1.0,
+        });
+        assert_eq!(data(&frame), &[0x05, 0xCC, 0x00, 0x00, 0x80, 0x3F, 0, 0]);
+    }
+
+    #[test]
+    fn report_messages() {
+        round_trip(OsccMessage::BrakeReport(BrakeReport {
+            enabled: true,
+            operator_override: false,
+            dtcs: 0x01,
+        }));
+        round_trip(OsccMessage::SteeringReport(SteeringReport {
+            enabled: false,
+            operator_override: true,
+            dtcs: 0x80,
+        }));
+        round_trip(OsccMessage::ThrottleReport(ThrottleReport {
+            enabled: true,
+            operator_override: true,
+            dtcs: 0,
+        }));
+    }
+
+    #[test]
+    fn fault_report() {
+        let report = FaultReport {
+            fault_origin_id: FAULT_ORIGIN_THROTTLE,
+            dtcs: 0x03,
+        };
+
+        round_trip(OsccMessage::FaultReport(report));
+
+        let frame = CanFrame::from(report);
+        assert_eq!(data(&frame), &[0x05, 0xCC, 2, 0, 0, 0, 0x03, 0]);
+    }
+
+    #[test]
+    fn bad_magic() {
+        let mut frame = message_frame(BRAKE_REPORT_CAN_ID);
+        frame.data_as_mut()[1] = 0xCD;
+
+        assert_eq!(
+            OsccMessage::try_from(&CanFrame::from(frame)),
+            Err(OsccError::InvalidMagic)
+        );
+    }
+
+    #[test]
+    fn bad_length() {
+        let mut frame = message_frame(BRAKE_REPORT_CAN_ID);
+        frame.set_data_length(7);
+
+        assert_eq!(
+            OsccMessage::try_from(&CanFrame::from(frame)),
+            Err(OsccError::InvalidFrame)
+        );
+
+        let frame = CanFrame::from(RemoteFrame::new(ID::BaseID(BaseID::new(
+            BRAKE_REPORT_CAN_ID,
+        ))));
+        assert_eq!(OsccMessage::try_from(&frame), Err(OsccError::InvalidFrame));
+    }
+
+    #[test]
+    fn unexpected_id() {
+        let frame = CanFrame::from(message_frame(0x123));
+        assert_eq!(OsccMessage::try_from(&frame), Err(OsccError::UnexpectedId));
+
+        let frame = CanFrame::from(BrakeEnable);
+        assert_eq!(BrakeDisable::try_from(&frame), Err(OsccError::UnexpectedId));
+    }
+}
@@ -1,5 +1,6 @@
 //! Prelude
 
+pub use dma::DmaExt as _stm32f7x7_hal_dma_DmaExt;
 pub use flash::FlashExt as _stm32f7x7_hal_flash_FlashExt;
 pub use gpio::GpioExt as _stm32f7x7_hal_gpio_GpioExt;
 pub use hal::prelude::*;
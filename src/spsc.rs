@@ -0,0 +1,265 @@
+//! Single producer single consumer queue
+//!
+//! Lock-free queue, backed by caller provided storage, meant to move items
+//! from an interrupt handler (the producer) to the main loop (the consumer).
+//!
+//! Example:
+//! static mut STORAGE: [Option<CanFrame>; 4] = [None, None, None, None];
+//! static mut QUEUE: Option<Queue<'static, CanFrame>> = None;
+//!
+//! let queue = unsafe {
+//!     QUEUE = Some(Queue::new(&mut STORAGE));
+//!     QUEUE.as_mut().unwrap()
+//! };
+//! let (producer, consumer) = queue.split();
+
+use core::marker::PhantomData;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// Fixed capacity queue
+pub struct Queue<'a, T: 'a> {
+    buffer: *mut Option<T>,
+    capacity: usize,
+    // indices run over [0, 2 * capacity) to tell full from empty
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    overruns: AtomicUsize,
+    _storage: PhantomData<&'a mut [Option<T>]>,
+}
+
+/// Producer endpoint of a `Queue`
+pub struct Producer<'q, T: 'q> {
+    queue: &'q Queue<'q, T>,
+}
+
+/// Consumer endpoint of a `Queue`
+pub struct Consumer<'q, T: 'q> {
+    queue: &'q Queue<'q, T>,
+}
+
+// NOTE(unsafe) each endpoint only touches its own index and the slots it
+// owns, ownership of a slot is handed over through the atomic indices
+unsafe impl<'q, T: Send> Send for Producer<'q, T> {}
+unsafe impl<'q, T: Send> Send for Consumer<'q, T> {}
+
+impl<'a, T> Queue<'a, T> {
+    /// Creates an empty queue using `storage`, the capacity of the queue
+    /// is the length of `storage`
+    pub fn new(storage: &'a mut [Option<T>]) -> Self {
+        assert!(storage.len() > 0);
+        assert!(storage.len() <= (usize::max_value() / 2));
+
+        for slot in storage.iter_mut() {
+            *slot = None;
+        }
+
+        Queue {
+            buffer: storage.as_mut_ptr(),
+            capacity: storage.len(),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            overruns: AtomicUsize::new(0),
+            _storage: PhantomData,
+        }
+    }
+
+    /// Returns the maximum number of items the queue can hold
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Returns the number of items in the queue
+    pub fn len(&self) -> usize {
+        self.count(
+            self.head.load(Ordering::Acquire),
+            self.tail.load(Ordering::Acquire),
+        )
+    }
+
+    /// Returns true if the queue is empty
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the number of items dropped because the queue was full
+    /// or lost before reaching the queue
+    pub fn overruns(&self) -> usize {
+        self.overruns.load(Ordering::Relaxed)
+    }
+
+    /// Splits the queue into producer and consumer endpoints
+    pub fn split<'q>(&'q mut self) -> (Producer<'q, T>, Consumer<'q, T>) {
+        let queue: &'q Queue<'q, T> = self;
+
+        (Producer { queue }, Consumer { queue })
+    }
+
+    fn count(&self, head: usize, tail: usize) -> usize {
+        if tail >= head {
+            tail - head
+        } else {
+            tail + (2 * self.capacity) - head
+        }
+    }
+
+    fn next(&self, index: usize) -> usize {
+        if (index + 1) == (2 * self.capacity) {
+            0
+        } else {
+            index + 1
+        }
+    }
+
+    fn slot(&self, index: usize) -> *mut Option<T> {
+        let offset = if index >= self.capacity {
+            index - self.capacity
+        } else {
+            index
+        };
+
+        // NOTE(unsafe) offset is always within the storage
+        unsafe { self.buffer.add(offset) }
+    }
+}
+
+impl<'q, T> Producer<'q, T> {
+    /// Adds an item to the back of the queue.
+    ///
+    /// Returns the item back and counts an overrun if the queue is full.
+    pub fn enqueue(&mut self, item: T) -> Result<(), T> {
+        let tail = self.queue.tail.load(Ordering::Relaxed);
+        let head = self.queue.head.load(Ordering::Acquire);
+
+        if self.queue.count(head, tail) == self.queue.capacity {
+            self.record_overrun();
+            return Err(item);
+        }
+
+        // NOTE(unsafe) the consumer won't touch this slot until the tail
+        // index is published
+        unsafe { *self.queue.slot(tail) = Some(item) };
+
+        self.queue
+            .tail
+            .store(self.queue.next(tail), Ordering::Release);
+
+        Ok(())
+    }
+
+    /// Returns true if the queue is full
+    pub fn is_full(&self) -> bool {
+        self.queue.len() == self.queue.capacity
+    }
+
+    /// Counts an item lost before it could be enqueued
+    pub fn record_overrun(&mut self) {
+        // only the producer writes the counter
+        let overruns = self.queue.overruns.load(Ordering::Relaxed);
+        self.queue
+            .overruns
+            .store(overruns.wrapping_add(1), Ordering::Relaxed);
+    }
+}
+
+impl<'q, T> Consumer<'q, T> {
+    /// Removes the item at the front of the queue
+    pub fn dequeue(&mut self) -> Option<T> {
+        let head = self.queue.head.load(Ordering::Relaxed);
+        let tail = self.queue.tail.load(Ordering::Acquire);
+
+        if head == tail {
+            return None;
+        }
+
+        // NOTE(unsafe) the producer won't touch this slot until the head
+        // index is published
+        let item = unsafe { (*self.queue.slot(head)).take() };
+
+        self.queue
+            .head
+            .store(self.queue.next(head), Ordering::Release);
+
+        item
+    }
+
+    /// Returns the number of items in the queue
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// Returns true if the queue is empty
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// Returns the number of items dropped by the producer
+    pub fn overruns(&self) -> usize {
+        self.queue.overruns()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_and_full() {
+        let mut storage = [None, None, None];
+        let mut queue = Queue::new(&mut storage);
+        let (mut producer, mut consumer) = queue.split();
+
+        assert!(consumer.is_empty());
+        assert_eq!(consumer.dequeue(), None);
+
+        for i in 0..3 {
+            assert_eq!(producer.enqueue(i), Ok(()));
+        }
+
+        assert!(producer.is_full());
+        assert_eq!(consumer.len(), 3);
+        assert_eq!(producer.enqueue(3), Err(3));
+        assert_eq!(consumer.overruns(), 1);
+
+        for i in 0..3 {
+            assert_eq!(consumer.dequeue(), Some(i));
+        }
+
+        assert!(consumer.is_empty());
+        assert!(!producer.is_full());
+        assert_eq!(consumer.dequeue(), None);
+    }
+
+    #[test]
+    fn wraparound() {
+        let mut storage = [None, None, None];
+        let mut queue = Queue::new(&mut storage);
+        let (mut producer, mut consumer) = queue.split();
+
+        // indices run over twice the capacity, go around a few times with
+        // the queue partially filled
+        for i in 0..20 {
+            assert_eq!(producer.enqueue(2 * i), Ok(()));
+            assert_eq!(producer.enqueue((2 * i) + 1), Ok(()));
+            assert_eq!(consumer.len(), 2);
+
+            assert_eq!(consumer.dequeue(), Some(2 * i));
+            assert_eq!(consumer.dequeue(), Some((2 * i) + 1));
+            assert!(consumer.is_empty());
+        }
+
+        // full with the tail index behind the head index
+        for i in 0..3 {
+            assert_eq!(producer.enqueue(i), Ok(()));
+        }
+
+        assert!(producer.is_full());
+        assert_eq!(producer.enqueue(3), Err(3));
+
+        for i in 0..3 {
+            assert_eq!(consumer.dequeue(), Some(i));
+        }
+
+        assert_eq!(consumer.dequeue(), None);
+        assert_eq!(consumer.overruns(), 1);
+    }
+}
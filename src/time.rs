@@ -114,4 +114,9 @@ impl Instant {
     pub fn elapsed(&self) -> u32 {
         DWT::get_cycle_count().wrapping_sub(self.now)
     }
+
+    /// Cycle count at which the `Instant` was created
+    pub fn ticks(&self) -> u32 {
+        self.now
+    }
 }
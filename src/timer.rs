@@ -25,6 +25,27 @@ pub enum Event {
     TimeOut,
 }
 
+/// Trigger output (TRGO) source, e.g. to start ADC conversions
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MasterMode {
+    /// Counter reset by software (EGR.UG)
+    Reset,
+    /// Counter enable
+    Enable,
+    /// Update event, the timer period
+    Update,
+}
+
+impl From<MasterMode> for u8 {
+    fn from(m: MasterMode) -> u8 {
+        match m {
+            MasterMode::Reset => 0b000,
+            MasterMode::Enable => 0b001,
+            MasterMode::Update => 0b010,
+        }
+    }
+}
+
 pub trait OnePulse: CountDown {
     fn reconfigure_one_pulse_mode(&mut self);
 
@@ -155,6 +176,11 @@ macro_rules! hal {
                     }
                 }
 
+                /// Selects the event sent on the trigger output (TRGO)
+                pub fn set_master_mode(&mut self, mode: MasterMode) {
+                    self.tim.cr2.modify(|_, w| unsafe { w.mms().bits(u8::from(mode)) });
+                }
+
                 /// Releases the TIM peripheral
                 pub fn free(self) -> $TIM {
                     // pause counter